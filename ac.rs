@@ -0,0 +1,87 @@
+use crate::battery::POWER_SUPPLY_DIR;
+use crate::sysfs;
+use crate::{read_battery_string, write_str};
+use log::error;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// An AC adapter and whether it's providing power, if it says.
+pub struct Adapter {
+    pub name: String,
+    pub online: Option<bool>,
+}
+
+/// All power_supply devices that can power the system, of type Mains
+/// (barrel jacks, ACPI adapters) or USB (USB-C ports), sorted by name.
+pub fn adapter_paths() -> Vec<PathBuf> {
+    // devices can come and go while looking, so skip the ones that
+    // can't be read rather than stopping
+    let mut paths: Vec<PathBuf> = fs::read_dir(sysfs(POWER_SUPPLY_DIR))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let ty = fs::read_to_string(path.join("type")).unwrap_or_default();
+            let scope = fs::read_to_string(path.join("scope")).unwrap_or_default();
+            matches!(ty.trim(), "Mains" | "USB") && scope.trim() != "Device"
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+pub fn read(paths: &[PathBuf]) -> Vec<Adapter> {
+    paths
+        .iter()
+        .map(|path| Adapter {
+            name: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            online: read_battery_string(path, "online").map(|online| online != "0"),
+        })
+        .collect()
+}
+
+/// Connected if any adapter is online, disconnected if none is and at
+/// least one says so, and None if none can tell.
+pub fn online(adapters: &[Adapter]) -> Option<&'static str> {
+    let states: Vec<bool> = adapters
+        .iter()
+        .filter_map(|adapter| adapter.online)
+        .collect();
+    if states.contains(&true) {
+        Some("1")
+    } else if !states.is_empty() {
+        Some("0")
+    } else {
+        None
+    }
+}
+
+/// Write whether each adapter is online to <dir_path>/ac/<name>,
+/// removing those that went away. published keeps track of what was
+/// written before.
+pub(crate) fn publish(dir_path: &str, adapters: &[Adapter], published: &mut HashSet<String>) {
+    let ac_path = format!("{dir_path}/ac");
+    let mut present = HashSet::new();
+    for adapter in adapters {
+        let val = adapter.online.map(|x| if x { "1" } else { "0" });
+        write_str(&ac_path, &adapter.name, val);
+        present.insert(adapter.name.clone());
+    }
+
+    for name in published.difference(&present) {
+        let path = format!("{ac_path}/{name}");
+        if let Err(err) = fs::remove_file(&path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                error!("remove {path}: {err}");
+            }
+        }
+    }
+    *published = present;
+}
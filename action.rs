@@ -0,0 +1,81 @@
+use crate::sysfs;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+
+// What to do once the battery reaches request_shutdown_battery_percent.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CriticalAction {
+    #[default]
+    Poweroff,
+    Hibernate,
+    HybridSleep,
+    Suspend,
+}
+
+impl fmt::Display for CriticalAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CriticalAction::Poweroff => "poweroff",
+            CriticalAction::Hibernate => "hibernate",
+            CriticalAction::HybridSleep => "hybrid-sleep",
+            CriticalAction::Suspend => "suspend",
+        })
+    }
+}
+
+fn sleep_states() -> String {
+    fs::read_to_string(sysfs("/sys/power/state")).unwrap_or_default()
+}
+
+// Hibernating without swap to write the image to just fails.
+fn can_hibernate() -> bool {
+    let swaps = fs::read_to_string("/proc/swaps").unwrap_or_default();
+    sleep_states()
+        .split_whitespace()
+        .any(|state| state == "disk")
+        && swaps.lines().count() > 1
+}
+
+fn can_suspend() -> bool {
+    sleep_states()
+        .split_whitespace()
+        .any(|state| state == "mem")
+}
+
+impl CriticalAction {
+    fn is_available(self) -> bool {
+        match self {
+            CriticalAction::Poweroff => true,
+            CriticalAction::Hibernate => can_hibernate(),
+            CriticalAction::HybridSleep => can_hibernate() && can_suspend(),
+            CriticalAction::Suspend => can_suspend(),
+        }
+    }
+
+    // What to try instead when unavailable, in order. Powering off
+    // always works.
+    fn fallbacks(self) -> &'static [CriticalAction] {
+        match self {
+            CriticalAction::Poweroff => &[],
+            CriticalAction::Hibernate => &[CriticalAction::Poweroff],
+            CriticalAction::HybridSleep => &[CriticalAction::Hibernate, CriticalAction::Poweroff],
+            CriticalAction::Suspend => &[CriticalAction::Poweroff],
+        }
+    }
+
+    // The configured action if possible on this system, or the first
+    // available fallback.
+    pub fn resolve(self) -> CriticalAction {
+        std::iter::once(self)
+            .chain(self.fallbacks().iter().copied())
+            .find(|action| action.is_available())
+            .unwrap_or(CriticalAction::Poweroff)
+    }
+
+    // The system comes back from anything but poweroff.
+    pub fn resumes(self) -> bool {
+        self != CriticalAction::Poweroff
+    }
+}
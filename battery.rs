@@ -0,0 +1,562 @@
+use crate::config::Config;
+use crate::sensors::matches;
+use crate::units::{self, Quantity, Validator};
+use crate::{read_battery_f64, read_battery_string, sysfs};
+use log::{info, warn};
+use std::cell::Cell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A battery under /sys/class/power_supply, and how to read it.
+pub struct Battery {
+    pub path: PathBuf,
+    pub name: String,
+    files_named_charge: bool,
+    files_named_current: bool,
+    // File with the temperature, and the divisor to get to °C.
+    temp_file: Option<(PathBuf, f64)>,
+    // Whether current_now/power_now are positive while discharging,
+    // from the quirks, or else learned while the status says so.
+    positive_discharge_quirk: Option<bool>,
+    positive_discharge_learned: Cell<Option<bool>>,
+    // Scales the raw values by the quirks and rejects absurd ones.
+    validator: Validator,
+}
+
+/// The values read from a battery at once, in the units of its driver
+/// unless the name says otherwise.
+#[derive(Clone, Default)]
+pub struct BatteryReading {
+    pub charge_full: Option<f64>,
+    pub charge_full_design: Option<f64>,
+    pub charge_now: Option<f64>,
+    pub cycle_count: Option<f64>,
+    pub power_now: Option<f64>,
+    pub power_watts: Option<f64>,
+    /// Positive when charging, negative when draining.
+    pub net_power_watts: Option<f64>,
+    /// In A, with the same sign as net_power_watts.
+    pub net_current_amps: Option<f64>,
+    pub direction: Option<Direction>,
+    pub status: Option<String>,
+    pub temp_celsius: Option<f64>,
+    pub voltage_min_design: Option<f64>,
+    pub voltage_now: Option<f64>,
+    /// From the present file, for removable batteries: false when the
+    /// slot is empty.
+    pub present: Option<bool>,
+    pub units: Units,
+}
+
+/// What the charge_* values of a reading are measured in. power_now is
+/// in pW (µV times µA) either way.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Units {
+    /// charge_* files, in µAh.
+    #[default]
+    Charge,
+    // energy_* files, in µWh.
+    Energy,
+}
+
+/// Which way the energy flows, whatever sign the driver uses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Charging,
+    Discharging,
+    Idle,
+}
+
+impl Direction {
+    pub fn name(self) -> &'static str {
+	match self {
+	    Direction::Charging => "charging",
+	    Direction::Discharging => "discharging",
+	    Direction::Idle => "idle",
+	}
+    }
+}
+
+pub const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// All power_supply devices of type Battery, sorted by name, and
+/// whether they are peripherals (controllers, mice, headsets, ...).
+pub fn all_battery_paths() -> Vec<(PathBuf, bool)> {
+    let entries = match fs::read_dir(sysfs(POWER_SUPPLY_DIR)) {
+	Ok(entries) => entries,
+	Err(_) => return Vec::new(),
+    };
+    let mut paths: Vec<(PathBuf, bool)> = entries
+	.flatten()
+	.map(|entry| entry.path())
+	.filter_map(|path| {
+	    // the battery might also go away while checking
+	    let ty = fs::read_to_string(path.join("type")).unwrap_or_default();
+	    if ty.trim() != "Battery" {
+		return None;
+	    }
+	    let scope = fs::read_to_string(path.join("scope")).unwrap_or_default();
+	    Some((path, scope.trim() == "Device"))
+	})
+	.collect();
+    paths.sort();
+    paths
+}
+
+/// Paths of the system batteries that drive vpower, sorted by name:
+/// only the one named battery_device (under /sys/class/power_supply,
+/// unless absolute) if configured, otherwise all non-peripheral ones
+/// allowed by battery_allow and battery_deny.
+pub fn battery_paths(config: &Config) -> Vec<PathBuf> {
+    if let Some(battery_device) = &config.battery_device {
+	let path = sysfs(POWER_SUPPLY_DIR).join(battery_device);
+	return if path.exists() { vec![path] } else { Vec::new() };
+    }
+
+    all_battery_paths()
+	.into_iter()
+	.filter(|(_, peripheral)| !peripheral)
+	.map(|(path, _)| path)
+	.filter(|path| {
+	    let name = path.file_name().unwrap_or_default().to_string_lossy();
+	    let allowed = config.battery_allow.is_empty()
+		|| config.battery_allow.iter().any(|pattern| matches(pattern.as_bytes(), name.as_bytes()));
+	    let denied = config.battery_deny.iter().any(|pattern| matches(pattern.as_bytes(), name.as_bytes()));
+	    allowed && !denied
+	})
+	.collect()
+}
+
+/// Find all batteries, like battery_paths().
+pub fn find_batteries(config: &Config) -> Vec<Battery> {
+    battery_paths(config)
+	.into_iter()
+	.map(|path_bat| {
+	    info!("Found battery: {}", path_bat.display());
+	    let mut battery = Battery::new(path_bat);
+	    battery.positive_discharge_quirk = config.quirks.positive_discharge_current();
+	    battery.validator = Validator::new(config.quirks.scales());
+	    battery
+	})
+	.collect()
+}
+
+impl Battery {
+    pub fn new(path: PathBuf) -> Battery {
+	let name = path
+	    .file_name()
+	    .map(|name| name.to_string_lossy().to_lowercase())
+	    .unwrap_or_default();
+
+	// Some files that the code further below will attempt to read
+	// every second (not all devices might provide them, probably
+	// better to keep running for partial functionality than stopping
+	// completely)
+	let bat_values_filenames = vec!["status", "voltage_min_design", "voltage_now"];
+	for expected_file in bat_values_filenames.into_iter() {
+	    let path_expected_file = PathBuf::from(format!("{}/{expected_file}", path.display()));
+	    if ! path_expected_file.exists() {
+		warn!("missing expected file: {}", path_expected_file.display());
+	    }
+	}
+	// for the following files, names vary between charge_full/now
+	// (SteamDeck for example) and energy_full/now
+	let mut files_named_charge = true;
+	let bat_values_filenames_charge = vec!["charge_full", "charge_now"];
+	for expected_file in bat_values_filenames_charge.into_iter() {
+	    let path_expected_file = PathBuf::from(format!("{}/{expected_file}", path.display()));
+	    if ! path_expected_file.exists() {
+		// assume files are named energy_*
+		files_named_charge = false;
+		let expected_file_subst = expected_file.replace("charge_", "energy_");
+		let path_expected_file_subst = PathBuf::from(format!("{}/{expected_file_subst}", path.display()));
+		if ! path_expected_file_subst.exists() {
+		    warn!("missing expected files: {} or {}", path_expected_file.display(), path_expected_file_subst.display());
+		}
+		else {
+		    info!("using {} (instead of '{}')", path_expected_file_subst.display(), expected_file);
+		}
+	    }
+	}
+	// the following name varies between current_now and power_now
+	let mut files_named_current = true;
+	let bat_values_filenames_current = vec!["current_now"];
+	for expected_file in bat_values_filenames_current.into_iter() {
+	    let path_expected_file = PathBuf::from(format!("{}/{expected_file}", path.display()));
+	    if ! path_expected_file.exists() {
+		// assume files are named power_*
+		files_named_current = false;
+		let expected_file_subst = expected_file.replace("current_", "power_");
+		let path_expected_file_subst = PathBuf::from(format!("{}/{expected_file_subst}", path.display()));
+		if ! path_expected_file_subst.exists() {
+		    warn!("missing expected files: {} or {}", path_expected_file.display(), path_expected_file_subst.display());
+		}
+		else {
+		    info!("using {} (instead of '{}')", path_expected_file_subst.display(), expected_file);
+		}
+	    }
+	}
+
+	// temp is in tenths of °C, but not all drivers provide it, some
+	// register a hwmon device instead (in m°C)
+	let temp_file = find_temp_file(&path);
+	match &temp_file {
+	    Some((temp_path, _)) => info!("using {} for battery temperature", temp_path.display()),
+	    None => warn!("could not find battery temperature for {}", path.display()),
+	}
+
+	Battery {
+	    path,
+	    name,
+	    files_named_charge,
+	    files_named_current,
+	    temp_file,
+	    positive_discharge_quirk: None,
+	    positive_discharge_learned: Cell::new(None),
+	    validator: Validator::default(),
+	}
+    }
+
+    // An attribute, unless it makes no sense.
+    fn read_checked(&self, name: &str, quantity: Quantity) -> Option<f64> {
+	let val = read_battery_f64(&self.path, name);
+	self.validator.check(&format!("{}/{name}", self.path.display()), quantity, val)
+    }
+
+    pub fn read(&self) -> BatteryReading {
+	let path_bat = &self.path;
+	let (charge_full, charge_full_design, charge_now) = if self.files_named_charge {
+	    // SteamDeck (and others)
+	    (
+		self.read_checked("charge_full", Quantity::Charge),
+		self.read_checked("charge_full_design", Quantity::Charge),
+		self.read_checked("charge_now", Quantity::Charge),
+	    )
+	} else {
+	    // In µWh rather than µAh: the reading's units tell them
+	    // apart, and to_wh converts either to Wh.
+	    (
+		self.read_checked("energy_full", Quantity::Energy),
+		self.read_checked("energy_full_design", Quantity::Energy),
+		self.read_checked("energy_now", Quantity::Energy),
+	    )
+	};
+	// Drivers that report a sign use negative values when draining
+	let current_now_signed = if self.files_named_current { self.read_checked("current_now", Quantity::Current) } else { None };
+	let (current_now, power_now_from_file) = if self.files_named_current {
+	    // SteamDeck (and others)
+	    ( current_now_signed.map(f64::abs), None )
+	}
+	else {
+	    ( None, self.read_checked("power_now", Quantity::Power) )
+	};
+	let cycle_count = read_battery_f64(path_bat, "cycle_count");
+	let status = read_battery_string(path_bat, "status");
+	let temp_celsius = self.temp_file.as_ref().and_then(|(temp_path, divisor)| {
+	    let val = f64::from_str(fs::read_to_string(temp_path).ok()?.trim()).ok()?;
+	    self.validator.check(&temp_path.display().to_string(), Quantity::Temperature, Some(val / divisor))
+	});
+	let voltage_min_design = self.read_checked("voltage_min_design", Quantity::Voltage);
+	let voltage_now = self.read_checked("voltage_now", Quantity::Voltage);
+	// Only removable batteries have it, so don't complain without
+	let present = fs::read_to_string(path_bat.join("present")).ok().map(|present| present.trim() != "0");
+
+	let power_now = match (voltage_now, current_now, power_now_from_file) {
+	    (Some(voltage_now), Some(current_now), _) => Some(voltage_now * current_now),
+	    // µW to pW, no voltage involved
+	    (_, None, Some(power_now_from_file)) => Some(power_now_from_file.abs() * 1e6),
+	    _ => None,
+	};
+	// current_now is in µA and voltage_now in µV, power_now in µW
+	let power_watts = match (voltage_now, current_now, power_now_from_file) {
+	    (Some(voltage_now), Some(current_now), _) => Some(units::watts(voltage_now, current_now)),
+	    (_, None, Some(power_now_from_file)) => Some(units::si(power_now_from_file.abs())),
+	    _ => None,
+	};
+
+	// Most drivers use negative values when draining, but some use
+	// positive ones: take the status' word until it's known which.
+	let signed = current_now_signed.or(power_now_from_file).filter(|val| *val != 0.0);
+	if let (Some("Discharging"), Some(val)) = (status.as_deref(), signed) {
+	    self.positive_discharge_learned.set(Some(val > 0.0));
+	}
+	let positive_discharge = self.positive_discharge_quirk.or(self.positive_discharge_learned.get());
+	let direction = match (status.as_deref(), signed, positive_discharge) {
+	    (_, Some(val), Some(positive_discharge)) if (val > 0.0) == positive_discharge => Direction::Discharging,
+	    (_, Some(_), Some(_)) => Direction::Charging,
+	    (Some("Discharging"), _, _) => Direction::Discharging,
+	    (Some("Charging"), _, _) => Direction::Charging,
+	    (_, Some(val), None) if val < 0.0 => Direction::Discharging,
+	    (_, Some(_), None) => Direction::Charging,
+	    (_, None, _) => Direction::Idle,
+	};
+	let draining = direction == Direction::Discharging;
+	let net_power_watts = power_watts.map(|power_watts| if draining { -power_watts.abs() } else { power_watts.abs() });
+	// µA to A
+	let net_current_amps = current_now.map(units::si).map(|amps| if draining { -amps } else { amps });
+
+	BatteryReading {
+	    charge_full,
+	    charge_full_design,
+	    charge_now,
+	    cycle_count,
+	    power_now,
+	    power_watts,
+	    net_power_watts,
+	    net_current_amps,
+	    direction: Some(direction),
+	    status,
+	    temp_celsius,
+	    voltage_min_design,
+	    voltage_now,
+	    present,
+	    units: if self.files_named_charge { Units::Charge } else { Units::Energy },
+	}
+    }
+}
+
+fn find_temp_file(path: &Path) -> Option<(PathBuf, f64)> {
+    let temp_path = path.join("temp");
+    if temp_path.exists() {
+	return Some((temp_path, 10.0));
+    }
+
+    for entry in fs::read_dir(path).ok()?.flatten() {
+	if entry.file_name().to_string_lossy().starts_with("hwmon") {
+	    let temp_path = entry.path().join("temp1_input");
+	    if temp_path.exists() {
+		return Some((temp_path, 1000.0));
+	    }
+	}
+    }
+    None
+}
+
+impl BatteryReading {
+    pub fn battery_percent(&self) -> Option<f64> {
+	match (self.charge_now, self.charge_full) {
+	    (Some(charge_now), Some(charge_full)) => Some(charge_now / charge_full * 100.0),
+	    _ => None,
+	}
+    }
+
+    /// Convert a charge_* value to Wh: energy_* files are in µWh,
+    /// charge_* ones in µAh, at voltage_min_design in µV.
+    pub fn to_wh(&self, val: f64) -> Option<f64> {
+	match self.units {
+	    Units::Energy => Some(units::si(val)),
+	    Units::Charge => self.voltage_min_design.map(|voltage_min_design| units::si(val) * units::si(voltage_min_design)),
+	}
+    }
+
+    /// How many hours a charge_* value lasts at power_now, whichever
+    /// the units.
+    pub fn hours_at(&self, val: f64, power_now: f64) -> Option<f64> {
+	self.to_wh(val).map(|wh| wh / (power_now / 1e12))
+    }
+
+    /// How much of the design capacity is left.
+    pub fn battery_health_percent(&self) -> Option<f64> {
+	match (self.charge_full, self.charge_full_design) {
+	    (Some(charge_full), Some(charge_full_design)) if charge_full_design > 0.0 => {
+		Some(charge_full / charge_full_design * 100.0)
+	    }
+	    _ => None,
+	}
+    }
+}
+
+/// Combine the readings of several batteries into one, as if they
+/// were a single pack: charges and power add up, voltages are taken
+/// from the first battery that reports them.
+pub fn aggregate(readings: &[BatteryReading]) -> BatteryReading {
+    if readings.len() == 1 {
+	return readings[0].clone();
+    }
+
+    // µAh and µWh don't add up: with both kinds of batteries, add up
+    // energy, converting charges at their voltage_min_design.
+    let units = readings.first().map_or(Units::Charge, |reading| reading.units);
+    let mixed = readings.iter().any(|reading| reading.units != units);
+    let mut total = BatteryReading {
+	units: if mixed { Units::Energy } else { units },
+	..Default::default()
+    };
+    let mut design_complete = true;
+    for reading in readings {
+	// Wh to µWh
+	let to_total = |val: Option<f64>| if mixed { reading.to_wh(val?).map(|wh| wh * 1e6) } else { val };
+	if let (Some(charge_full), Some(charge_now)) = (to_total(reading.charge_full), to_total(reading.charge_now)) {
+	    total.charge_full = Some(total.charge_full.unwrap_or(0.0) + charge_full);
+	    total.charge_now = Some(total.charge_now.unwrap_or(0.0) + charge_now);
+	}
+	if let Some(charge_full_design) = to_total(reading.charge_full_design) {
+	    total.charge_full_design = Some(total.charge_full_design.unwrap_or(0.0) + charge_full_design);
+	} else {
+	    design_complete = false;
+	}
+	if let Some(cycle_count) = reading.cycle_count {
+	    total.cycle_count = Some(total.cycle_count.unwrap_or(0.0).max(cycle_count));
+	}
+	if let Some(power_now) = reading.power_now {
+	    total.power_now = Some(total.power_now.unwrap_or(0.0) + power_now);
+	}
+	if let Some(power_watts) = reading.power_watts {
+	    total.power_watts = Some(total.power_watts.unwrap_or(0.0) + power_watts);
+	}
+	if let Some(net_power_watts) = reading.net_power_watts {
+	    total.net_power_watts = Some(total.net_power_watts.unwrap_or(0.0) + net_power_watts);
+	}
+	if let Some(net_current_amps) = reading.net_current_amps {
+	    total.net_current_amps = Some(total.net_current_amps.unwrap_or(0.0) + net_current_amps);
+	}
+	if let Some(temp_celsius) = reading.temp_celsius {
+	    total.temp_celsius = Some(total.temp_celsius.map_or(temp_celsius, |temp| temp.max(temp_celsius)));
+	}
+	total.voltage_min_design = total.voltage_min_design.or(reading.voltage_min_design);
+	total.voltage_now = total.voltage_now.or(reading.voltage_now);
+	// Present if any of them is.
+	total.present = match (total.present, reading.present) {
+	    (Some(true), _) | (_, Some(true)) => Some(true),
+	    (present, other) => present.or(other),
+	};
+    }
+
+    // A partial sum would make the pack look healthier than it is.
+    if !design_complete {
+	total.charge_full_design = None;
+    }
+
+    // One battery can charge while another drains, like when one
+    // charges the other: the pack's power is what flows in or out of
+    // it overall, and that's the way it goes.
+    if let Some(net_power_watts) = total.net_power_watts {
+	total.power_watts = Some(net_power_watts.abs());
+	// W to pW
+	total.power_now = total.power_now.map(|_| net_power_watts.abs() * 1e12);
+    }
+    let directions: Vec<Direction> = readings.iter().filter_map(|reading| reading.direction).collect();
+    total.direction = match total.net_power_watts {
+	Some(net_power_watts) if net_power_watts > 0.0 => Some(Direction::Charging),
+	Some(net_power_watts) if net_power_watts < 0.0 => Some(Direction::Discharging),
+	_ if directions.contains(&Direction::Discharging) => Some(Direction::Discharging),
+	_ if directions.contains(&Direction::Charging) => Some(Direction::Charging),
+	_ => directions.first().copied(),
+    };
+
+    // Any battery (dis)charging means the pack is (dis)charging, the
+    // way the power goes when both are, and it's only full when all
+    // of them are.
+    let statuses: Vec<&str> = readings.iter().filter_map(|reading| reading.status.as_deref()).collect();
+    total.status = if statuses.contains(&"Discharging") && statuses.contains(&"Charging") {
+	let charging = total.direction == Some(Direction::Charging);
+	Some(if charging { "Charging" } else { "Discharging" }.to_string())
+    } else if statuses.contains(&"Discharging") {
+	Some("Discharging".to_string())
+    } else if statuses.contains(&"Charging") {
+	Some("Charging".to_string())
+    } else if !statuses.is_empty() && statuses.iter().all(|&status| status == "Full") {
+	Some("Full".to_string())
+    } else {
+	statuses.first().map(|status| status.to_string())
+    };
+
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{fixture_dir, FixtureCopy};
+
+    fn assert_close(val: Option<f64>, expected: f64) {
+	let val = val.expect("no value");
+	assert!((val - expected).abs() < 0.1, "{val} != {expected}");
+    }
+
+    #[test]
+    fn positive_discharge_current_learned() {
+	// A driver reporting a positive current while discharging,
+	// whose status then stops saying so.
+	let copy = FixtureCopy::new("steamdeck-discharging", "power_supply/BAT1");
+	fs::write(copy.path.join("current_now"), "1500000\n").unwrap();
+	let battery = Battery::new(copy.path.clone());
+
+	let reading = battery.read();
+	assert_eq!(reading.direction, Some(Direction::Discharging));
+	assert_close(reading.net_power_watts, -11.7);
+
+	fs::write(copy.path.join("status"), "Unknown\n").unwrap();
+	let reading = battery.read();
+	assert_eq!(reading.direction, Some(Direction::Discharging));
+	assert_close(reading.net_current_amps, -1.5);
+    }
+
+    #[test]
+    fn empty_battery_slot_not_present() {
+	// A ThinkPad with its second, removable battery taken out.
+	let copy = FixtureCopy::new("thinkpad", "power_supply/BAT0");
+	fs::write(copy.path.join("present"), "0\n").unwrap();
+	let absent = Battery::new(copy.path.clone()).read();
+	let present = Battery::new(fixture_dir("thinkpad").join("power_supply/BAT0")).read();
+
+	assert_eq!(absent.present, Some(false));
+	let readings = [absent, present];
+	assert_eq!(aggregate(&readings[..1]).present, Some(false));
+	assert_eq!(aggregate(&readings).present, Some(true));
+    }
+
+    #[test]
+    fn aggregate_mixed_units() {
+	// 2 Ah at 15 V and 20 Wh, both half full.
+	let charge = BatteryReading {
+	    charge_full: Some(2e6),
+	    charge_full_design: Some(2e6),
+	    charge_now: Some(1e6),
+	    voltage_min_design: Some(15e6),
+	    units: Units::Charge,
+	    ..Default::default()
+	};
+	let energy = BatteryReading {
+	    charge_full: Some(20e6),
+	    charge_full_design: Some(20e6),
+	    charge_now: Some(10e6),
+	    units: Units::Energy,
+	    ..Default::default()
+	};
+	let total = aggregate(&[charge.clone(), energy]);
+	assert_eq!(total.units, Units::Energy);
+	assert_close(total.charge_full.map(units::si), 50.0);
+	assert_close(total.charge_full_design.map(units::si), 50.0);
+	assert_close(total.battery_percent(), 50.0);
+
+	// Without a voltage to convert at, the charge is unknown.
+	let charge = BatteryReading { voltage_min_design: None, ..charge };
+	let energy = BatteryReading { units: Units::Energy, ..charge.clone() };
+	let total = aggregate(&[charge, energy]);
+	assert_eq!(total.charge_full_design, None);
+    }
+
+    #[test]
+    fn aggregate_charging_and_discharging() {
+	let battery = |net_power_watts: f64, status: &str| BatteryReading {
+	    power_now: Some(net_power_watts.abs() * 1e12),
+	    power_watts: Some(net_power_watts.abs()),
+	    net_power_watts: Some(net_power_watts),
+	    direction: Some(if net_power_watts > 0.0 { Direction::Charging } else { Direction::Discharging }),
+	    status: Some(status.to_string()),
+	    ..Default::default()
+	};
+	let total = aggregate(&[battery(10.0, "Charging"), battery(-15.0, "Discharging")]);
+	assert_close(total.power_watts, 5.0);
+	assert_close(total.power_now.map(|power_now| power_now / 1e12), 5.0);
+	assert_eq!(total.direction, Some(Direction::Discharging));
+	assert_eq!(total.status.as_deref(), Some("Discharging"));
+
+	let total = aggregate(&[battery(20.0, "Charging"), battery(-15.0, "Discharging")]);
+	assert_close(total.power_watts, 5.0);
+	assert_eq!(total.direction, Some(Direction::Charging));
+	assert_eq!(total.status.as_deref(), Some("Charging"));
+    }
+}
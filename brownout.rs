@@ -0,0 +1,83 @@
+use std::time::{Duration, Instant};
+
+// Samples further apart than this don't tell how fast the voltage
+// drops, like across a suspend.
+const MAX_SAMPLE_GAP: Duration = Duration::from_secs(5);
+
+// Notices the voltage dropping fast under heavy load, which on a worn
+// battery comes right before the EC cuts the power.
+#[derive(Default)]
+pub struct SagDetector {
+    prev: Option<(Instant, f64)>,
+    sagging: bool,
+    events: u64,
+}
+
+impl SagDetector {
+    // Whether a new sag started: the voltage falling by at least
+    // volts_per_sec while drawing at least min_watts from the battery.
+    pub fn update(
+        &mut self,
+        volts: Option<f64>,
+        watts: Option<f64>,
+        now: Instant,
+        volts_per_sec: f64,
+        min_watts: f64,
+    ) -> bool {
+        let Some(volts) = volts else {
+            self.prev = None;
+            return false;
+        };
+        let rate = self.prev.and_then(|(prev_at, prev_volts)| {
+            let secs = now.checked_duration_since(prev_at)?;
+            (!secs.is_zero() && secs <= MAX_SAMPLE_GAP)
+                .then(|| (prev_volts - volts) / secs.as_secs_f64())
+        });
+        self.prev = Some((now, volts));
+
+        let sagging = rate.is_some_and(|rate| rate >= volts_per_sec)
+            && watts.is_some_and(|watts| watts >= min_watts);
+        let started = sagging && !self.sagging;
+        self.sagging = sagging;
+        if started {
+            self.events += 1;
+        }
+        started
+    }
+
+    // How many sags were seen since vpower started.
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+
+    pub fn reset(&mut self) {
+        self.prev = None;
+        self.sagging = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sag_under_load_counts_once() {
+        let start = Instant::now();
+        let at = |secs: f64| start + Duration::from_secs_f64(secs);
+        let mut detector = SagDetector::default();
+        assert!(!detector.update(Some(7.8), Some(25.0), at(0.0), 0.3, 15.0));
+        // Dropping 0.5 V/s under 25 W, for two samples.
+        assert!(detector.update(Some(7.3), Some(25.0), at(1.0), 0.3, 15.0));
+        assert!(!detector.update(Some(6.8), Some(25.0), at(2.0), 0.3, 15.0));
+        assert_eq!(detector.events(), 1);
+        // The same drop under a light load is just the battery
+        // emptying.
+        assert!(!detector.update(Some(7.0), Some(5.0), at(3.0), 0.3, 15.0));
+        assert!(!detector.update(Some(6.5), Some(5.0), at(4.0), 0.3, 15.0));
+        // Nor across a gap.
+        assert!(!detector.update(Some(6.0), Some(25.0), at(20.0), 0.3, 15.0));
+        assert_eq!(detector.events(), 1);
+        assert!(detector.update(Some(5.5), Some(25.0), at(21.0), 0.3, 15.0));
+        assert_eq!(detector.events(), 2);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::battery::{aggregate, find_batteries, Battery, BatteryReading};
+use crate::config::Config;
+use crate::history::HISTORY_DIR;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const CALIBRATION_FILE: &str = "calibration.toml";
+
+const INTERVAL: Duration = Duration::from_secs(5);
+
+// Where the discharge stops, before the critical action kicks in.
+const STOP_PERCENT: f64 = 5.0;
+
+// Anything further off is more likely a botched run than a battery.
+const MIN_FACTOR: f64 = 0.5;
+const MAX_FACTOR: f64 = 1.5;
+
+// The result of --calibrate: what a full discharge delivered, against
+// what charge_full promised.
+#[derive(Deserialize, Serialize)]
+struct Calibration {
+    capacity_factor: f64,
+    measured_wh: f64,
+    reported_wh: f64,
+    timestamp: u64,
+}
+
+enum Step {
+    Charge,
+    Discharge { delivered_wh: f64 },
+    Recharge,
+}
+
+fn path() -> PathBuf {
+    PathBuf::from(HISTORY_DIR).join(CALIBRATION_FILE)
+}
+
+// What to multiply charge_full by, 1 unless calibrated.
+pub fn capacity_factor() -> f64 {
+    let path = path();
+    let string = match fs::read_to_string(&path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return 1.0,
+        Err(err) => {
+            error!("read {}: {err}", path.display());
+            return 1.0;
+        }
+        Ok(string) => string,
+    };
+    match toml::from_str::<Calibration>(&string) {
+        Err(err) => {
+            error!("read {}: {err}", path.display());
+            1.0
+        }
+        Ok(calibration) => {
+            info!(
+                "Using capacity_factor {} from {}",
+                calibration.capacity_factor,
+                path.display()
+            );
+            calibration.capacity_factor
+        }
+    }
+}
+
+fn read(batteries: &[Battery]) -> BatteryReading {
+    let readings: Vec<BatteryReading> = batteries.iter().map(Battery::read).collect();
+    aggregate(&readings)
+}
+
+fn is_full(reading: &BatteryReading) -> bool {
+    reading.status.as_deref() == Some("Full") || reading.battery_percent() >= Some(99.5)
+}
+
+fn save(reading: &BatteryReading, delivered_wh: f64) -> Result<f64, String> {
+    let remaining_wh = reading
+        .charge_now
+        .and_then(|charge_now| reading.to_wh(charge_now));
+    let reported_wh = reading
+        .charge_full
+        .and_then(|charge_full| reading.to_wh(charge_full));
+    let (remaining_wh, reported_wh) = match (remaining_wh, reported_wh) {
+        (Some(remaining_wh), Some(reported_wh)) if reported_wh > 0.0 => (remaining_wh, reported_wh),
+        _ => return Err("cannot read the battery's charge".to_string()),
+    };
+
+    let measured_wh = delivered_wh + remaining_wh;
+    let capacity_factor = measured_wh / reported_wh;
+    if !(MIN_FACTOR..=MAX_FACTOR).contains(&capacity_factor) {
+        return Err(format!(
+            "measured {measured_wh:.2} Wh against {reported_wh:.2} Wh reported, not saving"
+        ));
+    }
+
+    let calibration = Calibration {
+        capacity_factor,
+        measured_wh,
+        reported_wh,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs()),
+    };
+    let path = path();
+    let string = toml::to_string(&calibration).map_err(|err| err.to_string())?;
+    fs::create_dir_all(HISTORY_DIR)
+        .and_then(|_| fs::write(&path, string))
+        .map_err(|err| format!("write {}: {err}", path.display()))?;
+    Ok(capacity_factor)
+}
+
+// Guide through a full charge, discharge and charge cycle, measuring
+// the energy actually delivered. Whether it succeeded.
+pub fn run(config: &Config) -> bool {
+    let batteries = find_batteries(config);
+    if batteries.is_empty() {
+        eprintln!("No battery found.");
+        return false;
+    }
+    if config.charge_limit_percent.is_some() {
+        println!("Note: charge_limit_percent is set, remove it to charge to full.");
+    }
+
+    println!("Step 1/3: charge the battery to full, keeping the charger connected.");
+    let mut step = Step::Charge;
+    let mut last_sample = Instant::now();
+    loop {
+        let reading = read(&batteries);
+        let percent = reading.battery_percent().unwrap_or(0.0);
+        let hours = last_sample.elapsed().as_secs_f64() / 3600.0;
+        last_sample = Instant::now();
+
+        match &mut step {
+            Step::Charge => {
+                if is_full(&reading) {
+                    println!(
+                        "Step 2/3: unplug the charger and keep using the device until the \
+                         battery is down to {STOP_PERCENT}%, without letting it suspend."
+                    );
+                    step = Step::Discharge { delivered_wh: 0.0 };
+                }
+            }
+            Step::Discharge { delivered_wh } => {
+                match reading.status.as_deref() {
+                    Some("Discharging") => {
+                        *delivered_wh += reading.power_watts.unwrap_or(0.0) * hours
+                    }
+                    // Still Full until unplugged.
+                    Some("Charging") => {
+                        println!("\nThe charger was connected, starting over.");
+                        println!(
+                            "Step 1/3: charge the battery to full, keeping the charger connected."
+                        );
+                        step = Step::Charge;
+                        thread::sleep(INTERVAL);
+                        continue;
+                    }
+                    _ => {}
+                }
+                print!("\r{percent:.1}%, {delivered_wh:.2} Wh delivered ");
+                let _ = io::stdout().flush();
+
+                if percent <= STOP_PERCENT {
+                    println!();
+                    match save(&reading, *delivered_wh) {
+                        Err(err) => {
+                            eprintln!("Calibration failed: {err}");
+                            return false;
+                        }
+                        Ok(capacity_factor) => println!(
+                            "Saved capacity_factor {capacity_factor:.3} to {}, \
+                             applied after systemctl reload vpower.",
+                            path().display()
+                        ),
+                    }
+                    println!("Step 3/3: connect the charger and charge back to full.");
+                    step = Step::Recharge;
+                }
+            }
+            Step::Recharge => {
+                if is_full(&reading) {
+                    println!("Done.");
+                    return true;
+                }
+            }
+        }
+        thread::sleep(INTERVAL);
+    }
+}
@@ -0,0 +1,17 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+// Write the charge limit to the knob at path, unless it's already set.
+// Returns whether it had to be written, as firmware might reset it
+// (like on resume).
+pub fn apply(path: &Path, percent: f64) -> io::Result<bool> {
+    let percent = percent.round().clamp(1.0, 100.0) as u32;
+    let current = fs::read_to_string(path)?;
+    if u32::from_str(current.trim()).ok() == Some(percent) {
+        return Ok(false);
+    }
+    fs::write(path, percent.to_string())?;
+    Ok(true)
+}
@@ -0,0 +1,73 @@
+use std::time::{Duration, Instant};
+
+// Charging at less than this counts as not charging.
+const STALL_WATTS: f64 = 1.0;
+
+// Notices the battery not charging although it could, like with a bad
+// cable, a hub sharing its power, or charging throttled because of the
+// heat.
+#[derive(Default)]
+pub struct ChargeStall {
+    since: Option<Instant>,
+    stalled: bool,
+}
+
+impl ChargeStall {
+    // Whether a stall started: charging at under STALL_WATTS for at
+    // least after, while can_charge, meaning a charger that should do
+    // better is plugged in and the battery has room left.
+    pub fn update(
+        &mut self,
+        can_charge: bool,
+        charge_watts: Option<f64>,
+        now: Instant,
+        after: Duration,
+    ) -> bool {
+        let stalling = can_charge && charge_watts.is_some_and(|watts| watts < STALL_WATTS);
+        if !stalling {
+            self.since = None;
+            self.stalled = false;
+            return false;
+        }
+        let since = *self.since.get_or_insert(now);
+        let stalled = now.saturating_duration_since(since) >= after;
+        let started = stalled && !self.stalled;
+        self.stalled = stalled;
+        started
+    }
+
+    pub fn stalled(&self) -> bool {
+        self.stalled
+    }
+
+    pub fn reset(&mut self) {
+        self.since = None;
+        self.stalled = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stall_after_a_while() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let after = Duration::from_secs(600);
+        let mut stall = ChargeStall::default();
+        assert!(!stall.update(true, Some(0.2), at(0), after));
+        assert!(!stall.update(true, Some(0.2), at(599), after));
+        assert!(stall.update(true, Some(0.2), at(600), after));
+        assert!(!stall.update(true, Some(0.2), at(601), after));
+        assert!(stall.stalled());
+        // Charging again ends it, and the wait starts over.
+        assert!(!stall.update(true, Some(20.0), at(602), after));
+        assert!(!stall.stalled());
+        assert!(!stall.update(true, Some(0.0), at(603), after));
+        // Not when it can't charge anyway, like when full.
+        assert!(!stall.update(false, Some(0.0), at(2000), after));
+        assert!(!stall.update(true, Some(0.0), at(2001), after));
+        assert!(stall.update(true, Some(0.0), at(2601), after));
+    }
+}
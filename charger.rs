@@ -0,0 +1,111 @@
+use crate::battery::POWER_SUPPLY_DIR;
+use crate::sysfs;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const TYPEC_DIR: &str = "/sys/class/typec";
+
+// USB vendor ID of Valve, which the official dock identifies with.
+const VALVE_VID: u32 = 0x28de;
+
+/// What is providing power, as far as the kernel can tell.
+pub struct Charger {
+    /// Like "Valve Steam Deck Docking Station (PD)".
+    pub model: Option<String>,
+    /// Whether the official dock is attached, powered or not.
+    pub dock_connected: bool,
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    let string = fs::read_to_string(path).ok()?;
+    let string = string.trim();
+    (!string.is_empty()).then(|| string.to_string())
+}
+
+// usb_type lists the supported types with the active one in brackets,
+// like "C [PD] PD_PPS".
+fn active_usb_type(usb_type: &str) -> Option<&str> {
+    usb_type
+        .split_whitespace()
+        .find_map(|ty| ty.strip_prefix('[')?.strip_suffix(']'))
+}
+
+fn describe_supply(path: &Path) -> Option<String> {
+    let manufacturer = read_trimmed(&path.join("manufacturer"));
+    let model_name = read_trimmed(&path.join("model_name"));
+    let mut model = match (manufacturer, model_name) {
+        (Some(manufacturer), Some(model_name)) => format!("{manufacturer} {model_name}"),
+        (Some(name), None) | (None, Some(name)) => name,
+        (None, None) => return None,
+    };
+    if let Some(usb_type) = read_trimmed(&path.join("usb_type")) {
+        if let Some(active) = active_usb_type(&usb_type) {
+            model.push_str(&format!(" ({active})"));
+        }
+    }
+    Some(model)
+}
+
+// The online Mains and USB power_supply devices, sorted by name.
+fn online_supplies() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(sysfs(POWER_SUPPLY_DIR))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let ty = read_trimmed(&path.join("type")).unwrap_or_default();
+            (ty == "Mains" || ty == "USB")
+                && read_trimmed(&path.join("online")).as_deref() == Some("1")
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn read_hex(path: &Path) -> Option<u32> {
+    let string = read_trimmed(path)?;
+    u32::from_str_radix(string.trim_start_matches("0x"), 16).ok()
+}
+
+// Vendor and product IDs of what's attached to the USB-C ports, from
+// the identity they sent over PD, when they did.
+fn typec_partners() -> Vec<(u32, u32)> {
+    let mut partners: Vec<(u32, u32)> = fs::read_dir(sysfs(TYPEC_DIR))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with("-partner"))
+        .filter_map(|entry| {
+            let identity = entry.path().join("identity");
+            let vid = read_hex(&identity.join("id_header"))? & 0xffff;
+            let pid = read_hex(&identity.join("product")).unwrap_or(0) >> 16;
+            (vid != 0).then_some((vid, pid))
+        })
+        .collect();
+    partners.sort();
+    partners
+}
+
+/// Describe the charger, when connected, and whether the dock is.
+pub fn identify(connected: bool) -> Charger {
+    let partners = typec_partners();
+    let dock_connected = partners.iter().any(|(vid, _)| *vid == VALVE_VID);
+    let model = if connected {
+        online_supplies()
+            .iter()
+            .find_map(|path| describe_supply(path))
+            .or_else(|| dock_connected.then(|| "Valve Steam Deck Docking Station".to_string()))
+            .or_else(|| {
+                partners
+                    .first()
+                    .map(|(vid, pid)| format!("USB-C {vid:04x}:{pid:04x}"))
+            })
+    } else {
+        None
+    };
+    Charger {
+        model,
+        dock_connected,
+    }
+}
@@ -0,0 +1,153 @@
+use crate::ac::adapter_paths;
+use crate::battery::battery_paths;
+use crate::config::Config;
+use crate::sensors::Sensors;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+// Battery attributes, with alternatives: the first one is what vpower
+// can't do without.
+const BATTERY_ATTRIBUTES: [&[&str]; 8] = [
+    &["status"],
+    &["charge_now", "energy_now"],
+    &["charge_full", "energy_full"],
+    &["current_now", "power_now"],
+    &["voltage_now"],
+    &["voltage_min_design"],
+    &["charge_full_design", "energy_full_design"],
+    &["cycle_count"],
+];
+
+// How many of BATTERY_ATTRIBUTES are required.
+const REQUIRED_BATTERY_ATTRIBUTES: usize = 3;
+
+#[derive(Serialize)]
+struct Report {
+    ok: bool,
+    config: ConfigReport,
+    batteries: Vec<BatteryReport>,
+    ac_adapters: Vec<String>,
+    sensors: SensorsReport,
+    output_dir: OutputDirReport,
+}
+
+#[derive(Serialize)]
+struct ConfigReport {
+    path: String,
+    exists: bool,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BatteryReport {
+    path: String,
+    // The attribute found for each of BATTERY_ATTRIBUTES.
+    attributes: Vec<String>,
+    missing: Vec<String>,
+    required_missing: bool,
+}
+
+#[derive(Serialize)]
+struct SensorsReport {
+    backend: &'static str,
+    chip: Option<String>,
+    charger_supply: Option<String>,
+    error: Option<String>,
+    pdcs: bool,
+    pdvl: bool,
+    pdam: bool,
+}
+
+#[derive(Serialize)]
+struct OutputDirReport {
+    path: String,
+    writable: bool,
+    error: Option<String>,
+}
+
+fn check_battery(path: &Path) -> BatteryReport {
+    let mut report = BatteryReport {
+        path: path.display().to_string(),
+        attributes: Vec::new(),
+        missing: Vec::new(),
+        required_missing: false,
+    };
+    for (i, names) in BATTERY_ATTRIBUTES.iter().enumerate() {
+        match names.iter().find(|name| path.join(name).exists()) {
+            Some(name) => report.attributes.push(name.to_string()),
+            None => {
+                report.missing.push(names.join("|"));
+                report.required_missing |= i < REQUIRED_BATTERY_ATTRIBUTES;
+            }
+        }
+    }
+    report
+}
+
+fn check_output_dir(dir_path: &str) -> OutputDirReport {
+    let probe = Path::new(dir_path).join(".check");
+    let result = fs::create_dir_all(dir_path)
+        .and_then(|_| fs::write(&probe, ""))
+        .and_then(|_| fs::remove_file(&probe));
+    OutputDirReport {
+        path: dir_path.to_string(),
+        writable: result.is_ok(),
+        error: result.err().map(|err| err.to_string()),
+    }
+}
+
+// Print what vpower would work with as JSON, and whether it can work
+// at all: a readable configuration, a battery with the required
+// attributes, and a writable output_dir.
+pub fn run(config: &Config, config_path: &str) -> bool {
+    let validation = Config::validate(config_path);
+    let config_report = ConfigReport {
+        path: config_path.to_string(),
+        exists: validation.is_some(),
+        error: validation.and_then(Result::err),
+    };
+
+    let batteries: Vec<BatteryReport> = battery_paths(config)
+        .iter()
+        .map(|path| check_battery(path))
+        .collect();
+
+    let sensors = Sensors::new(
+        config.sensors_chip.as_deref(),
+        config.charger_supply.as_deref(),
+    );
+    let (backend, chip) = sensors.describe();
+    let sensors_report = SensorsReport {
+        backend,
+        chip: chip.as_ref().ok().cloned(),
+        charger_supply: config.charger_supply.clone(),
+        error: chip.err().map(|err| err.to_string()),
+        pdcs: sensors.pdcs().is_ok(),
+        pdvl: sensors.pdvl().is_ok(),
+        pdam: sensors.pdam().is_ok(),
+    };
+
+    let output_dir = check_output_dir(&config.output_dir);
+
+    let ok = config_report.error.is_none()
+        && !batteries.is_empty()
+        && batteries.iter().all(|battery| !battery.required_missing)
+        && output_dir.writable;
+    let report = Report {
+        ok,
+        config: config_report,
+        batteries,
+        ac_adapters: adapter_paths()
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect(),
+        sensors: sensors_report,
+        output_dir,
+    };
+    match serde_json::to_string_pretty(&report) {
+        Ok(json) => println!("{json}"),
+        Err(err) => eprintln!("{err}"),
+    }
+    ok
+}
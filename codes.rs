@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+
+// Stable numbers for the published strings, for UIs to switch on
+// rather than matching English text. Never renumber these, only add.
+
+pub fn ac_status_code(ac_status: &str) -> Option<u8> {
+    match ac_status {
+        "Disconnected" => Some(0),
+        "Connected" => Some(1),
+        "Connected slow" => Some(2),
+        _ => None,
+    }
+}
+
+pub fn battery_status_code(battery_status: &str) -> Option<u8> {
+    match battery_status {
+        "Discharging" => Some(0),
+        "Charging" => Some(1),
+        "Not charging" => Some(2),
+        "Full" => Some(3),
+        _ => None,
+    }
+}
+
+pub fn charger_type_code(charger_type: &str) -> Option<u8> {
+    match charger_type {
+        "none" => Some(0),
+        "unknown" => Some(1),
+        "slow" => Some(2),
+        "PD" => Some(3),
+        _ => None,
+    }
+}
+
+// The human readable variant of a published string, from the locale
+// table if it has one.
+pub fn localize<'a>(locale: &'a HashMap<String, String>, string: &'a str) -> &'a str {
+    locale.get(string).map_or(string, String::as_str)
+}
@@ -0,0 +1,724 @@
+use crate::action::CriticalAction;
+use crate::derived::{Expr, Name};
+use crate::hooks::Hooks;
+use crate::mqtt::MqttConfig;
+use crate::notifications::Bus as NotificationBus;
+use crate::power_saving::{BacklightCap, PowerLimit};
+use crate::power_state::{default_charger_classes, ChargerClass};
+use crate::pre_shutdown::PreShutdownCommand;
+use crate::quirks::Quirks;
+use crate::telemetry::TelemetryConfig;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_PATH: &str = "/etc/vpower.toml";
+
+// Where the values are written, along with the socket.
+pub const OUTPUT_DIR: &str = "/run/vpower";
+
+const MIN_POLL_INTERVAL_SECS: f64 = 0.25;
+const MAX_POLL_INTERVAL_SECS: f64 = 3600.0;
+
+// The longest timeout or delay, a week, and history, a century.
+const MAX_DELAY_SECS: f64 = 7.0 * 86400.0;
+const MAX_RETENTION_DAYS: f64 = 36500.0;
+
+// Keep a value within what Duration and Instant can take: TOML also
+// allows nan, inf or 1e20, which would make them panic.
+fn clamp(name: &str, val: &mut f64, min: f64, max: f64) {
+    if val.is_nan() || *val < min {
+        warn!("{name}: {val} is too low, using {min}");
+        *val = min;
+    } else if *val > max {
+        warn!("{name}: {val} is too high, using {max}");
+        *val = max;
+    }
+}
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    request_shutdown_battery_percent: Option<f64>,
+    force_shutdown_timeout_secs: Option<f64>,
+    dbus: Option<bool>,
+    poll_interval_secs: Option<f64>,
+    power_smoothing_samples: Option<f64>,
+    sensors_chip: Option<String>,
+    shutdown_command: Option<String>,
+    shutdown_args: Option<Vec<String>>,
+    warning_battery_percent: Option<f64>,
+    warning_command: Option<String>,
+    max_battery_temp_celsius: Option<f64>,
+    log_level: Option<String>,
+    shutdown_inhibit_pidfiles: Option<Vec<String>>,
+    shutdown_inhibit_processes: Option<Vec<String>>,
+    max_shutdown_inhibit_secs: Option<f64>,
+    critical_action: Option<CriticalAction>,
+    notifications: Option<bool>,
+    notifications_bus: Option<NotificationBus>,
+    metrics_listen: Option<String>,
+    history: Option<bool>,
+    history_interval_secs: Option<f64>,
+    history_retention_days: Option<f64>,
+    charge_limit_percent: Option<f64>,
+    charge_limit_path: Option<String>,
+    slow_charger_watts: Option<f64>,
+    battery_device: Option<String>,
+    battery_allow: Option<Vec<String>>,
+    battery_deny: Option<Vec<String>>,
+    publish_peripherals: Option<bool>,
+    output_dir: Option<String>,
+    status_hysteresis_percent: Option<f64>,
+    status_hysteresis_secs: Option<f64>,
+    monotonic_battery_percent: Option<bool>,
+    voltage_curve: Option<Vec<(f64, f64)>>,
+    charger_supply: Option<String>,
+    pre_shutdown: Option<Vec<PreShutdownCommand>>,
+    wake_alarm: Option<bool>,
+    wake_alarm_margin_secs: Option<f64>,
+    hibernate_battery_percent: Option<f64>,
+    locale: Option<HashMap<String, String>>,
+    low_power_profile: Option<String>,
+    low_power_profile_battery_percent: Option<f64>,
+    backlight_cap: Option<Vec<BacklightCap>>,
+    power_limit: Option<Vec<PowerLimit>>,
+    full_dwell_secs: Option<f64>,
+    idle_poll_interval_secs: Option<f64>,
+    fast_poll_interval_secs: Option<f64>,
+    fast_poll_battery_percent: Option<f64>,
+    enable_forced_shutdown: Option<bool>,
+    emergency_voltage_volts: Option<f64>,
+    emergency_voltage_samples: Option<u32>,
+    sag_volts_per_sec: Option<f64>,
+    sag_min_watts: Option<f64>,
+    mqtt: Option<MqttConfig>,
+    charger_class: Option<Vec<ChargerClass>>,
+    user: Option<String>,
+    sandbox: Option<bool>,
+    remove_output_on_stop: Option<bool>,
+    shutdown_confirmation_secs: Option<f64>,
+    max_shutdown_postpones: Option<u32>,
+    charge_stall_secs: Option<f64>,
+    charge_stall_charger_watts: Option<f64>,
+    telemetry: Option<TelemetryConfig>,
+    derived: Option<BTreeMap<Name, Expr>>,
+    policy_script: Option<String>,
+    hooks: Option<Hooks>,
+}
+
+// Effective configuration, with defaults applied.
+pub struct Config {
+    pub request_shutdown_battery_percent: f64,
+    pub force_shutdown_timeout_secs: f64,
+    pub dbus: bool,
+    pub poll_interval_secs: f64,
+    pub power_smoothing_samples: f64,
+    pub sensors_chip: Option<String>,
+    pub shutdown_command: Option<String>,
+    pub shutdown_args: Vec<String>,
+    pub warning_battery_percent: Option<f64>,
+    pub warning_command: Option<String>,
+    pub max_battery_temp_celsius: Option<f64>,
+    pub log_level: String,
+    pub shutdown_inhibit_pidfiles: Vec<String>,
+    pub shutdown_inhibit_processes: Vec<String>,
+    pub max_shutdown_inhibit_secs: f64,
+    pub critical_action: CriticalAction,
+    pub notifications: bool,
+    pub notifications_bus: NotificationBus,
+    pub metrics_listen: Option<String>,
+    pub history: bool,
+    pub history_interval_secs: f64,
+    pub history_retention_days: f64,
+    pub charge_limit_percent: Option<f64>,
+    pub charge_limit_path: Option<String>,
+    pub slow_charger_watts: f64,
+    pub battery_device: Option<String>,
+    pub battery_allow: Vec<String>,
+    pub battery_deny: Vec<String>,
+    pub publish_peripherals: bool,
+    pub output_dir: String,
+    pub status_hysteresis_percent: f64,
+    pub status_hysteresis_secs: f64,
+    pub monotonic_battery_percent: bool,
+    pub voltage_curve: Vec<(f64, f64)>,
+    pub charger_supply: Option<String>,
+    pub pre_shutdown: Vec<PreShutdownCommand>,
+    pub wake_alarm: bool,
+    pub wake_alarm_margin_secs: f64,
+    pub hibernate_battery_percent: Option<f64>,
+    pub locale: HashMap<String, String>,
+    pub low_power_profile: Option<String>,
+    pub low_power_profile_battery_percent: f64,
+    pub backlight_cap: Vec<BacklightCap>,
+    pub power_limit: Vec<PowerLimit>,
+    pub full_dwell_secs: Option<f64>,
+    pub idle_poll_interval_secs: f64,
+    pub fast_poll_interval_secs: f64,
+    pub fast_poll_battery_percent: f64,
+    pub enable_forced_shutdown: bool,
+    pub emergency_voltage_volts: Option<f64>,
+    pub emergency_voltage_samples: u32,
+    pub sag_volts_per_sec: Option<f64>,
+    pub sag_min_watts: f64,
+    pub mqtt: Option<MqttConfig>,
+    pub charger_class: Vec<ChargerClass>,
+    pub user: Option<String>,
+    pub sandbox: bool,
+    pub remove_output_on_stop: bool,
+    pub shutdown_confirmation_secs: Option<f64>,
+    pub max_shutdown_postpones: u32,
+    pub charge_stall_secs: Option<f64>,
+    pub charge_stall_charger_watts: f64,
+    pub telemetry: Option<TelemetryConfig>,
+    pub derived: BTreeMap<Name, Expr>,
+    pub policy_script: Option<String>,
+    pub hooks: Hooks,
+    // Not from the file.
+    pub quirks: Quirks,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            request_shutdown_battery_percent: 0.49999998,
+            force_shutdown_timeout_secs: 10.0,
+            dbus: false,
+            poll_interval_secs: 1.0,
+            power_smoothing_samples: 10.0,
+            sensors_chip: None,
+            shutdown_command: None,
+            shutdown_args: Vec::new(),
+            warning_battery_percent: None,
+            warning_command: None,
+            max_battery_temp_celsius: None,
+            log_level: "info".to_string(),
+            shutdown_inhibit_pidfiles: Vec::new(),
+            shutdown_inhibit_processes: Vec::new(),
+            max_shutdown_inhibit_secs: 300.0,
+            critical_action: CriticalAction::default(),
+            notifications: false,
+            notifications_bus: NotificationBus::default(),
+            metrics_listen: None,
+            history: false,
+            history_interval_secs: 60.0,
+            history_retention_days: 90.0,
+            charge_limit_percent: None,
+            charge_limit_path: None,
+            slow_charger_watts: 30.0,
+            battery_device: None,
+            battery_allow: Vec::new(),
+            battery_deny: Vec::new(),
+            publish_peripherals: false,
+            output_dir: OUTPUT_DIR.to_string(),
+            status_hysteresis_percent: 0.5,
+            status_hysteresis_secs: 30.0,
+            monotonic_battery_percent: false,
+            voltage_curve: Vec::new(),
+            charger_supply: None,
+            pre_shutdown: Vec::new(),
+            wake_alarm: false,
+            wake_alarm_margin_secs: 300.0,
+            hibernate_battery_percent: None,
+            locale: HashMap::new(),
+            low_power_profile: None,
+            low_power_profile_battery_percent: 20.0,
+            backlight_cap: Vec::new(),
+            power_limit: Vec::new(),
+            full_dwell_secs: None,
+            idle_poll_interval_secs: 5.0,
+            fast_poll_interval_secs: 0.25,
+            fast_poll_battery_percent: 2.0,
+            enable_forced_shutdown: true,
+            emergency_voltage_volts: None,
+            emergency_voltage_samples: 3,
+            sag_volts_per_sec: None,
+            sag_min_watts: 15.0,
+            mqtt: None,
+            charger_class: default_charger_classes(),
+            user: None,
+            sandbox: false,
+            remove_output_on_stop: false,
+            shutdown_confirmation_secs: None,
+            max_shutdown_postpones: 3,
+            charge_stall_secs: None,
+            charge_stall_charger_watts: 20.0,
+            telemetry: None,
+            derived: BTreeMap::new(),
+            policy_script: None,
+            hooks: Hooks::default(),
+            quirks: Quirks::default(),
+        }
+    }
+}
+
+impl Config {
+    // Whether config_path parses, None if there is no such file.
+    pub fn validate(config_path: &str) -> Option<Result<(), String>> {
+        match fs::read(config_path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => Some(Err(err.to_string())),
+            Ok(bytes) => Some(
+                toml::from_slice::<ConfigFile>(&bytes)
+                    .map(|_| ())
+                    .map_err(|err| err.to_string()),
+            ),
+        }
+    }
+
+    pub fn load(config_path: &str) -> Config {
+        let mut config = Config::default();
+
+        // Device quirks provide defaults.
+        let quirks = Quirks::load();
+        quirks.apply_defaults(&mut config);
+        config.quirks = quirks;
+
+        match fs::read(config_path) {
+            Err(err) => error!("read {config_path}: {err}"),
+
+            Ok(bytes) => match toml::from_slice::<ConfigFile>(&bytes) {
+                Err(err) => error!("read {config_path}: {err}"),
+
+                Ok(file) => {
+                    if let Some(value) = file.request_shutdown_battery_percent {
+                        config.request_shutdown_battery_percent = value;
+                    }
+                    if let Some(value) = file.force_shutdown_timeout_secs {
+                        config.force_shutdown_timeout_secs = value;
+                    }
+                    if let Some(value) = file.dbus {
+                        config.dbus = value;
+                    }
+                    if let Some(value) = file.poll_interval_secs {
+                        config.poll_interval_secs = value;
+                    }
+                    if let Some(value) = file.power_smoothing_samples {
+                        config.power_smoothing_samples = value;
+                    }
+                    if let Some(value) = file.sensors_chip {
+                        config.sensors_chip = Some(value);
+                    }
+                    if let Some(value) = file.shutdown_command {
+                        config.shutdown_command = Some(value);
+                    }
+                    if let Some(value) = file.shutdown_args {
+                        config.shutdown_args = value;
+                    }
+                    if let Some(value) = file.warning_battery_percent {
+                        config.warning_battery_percent = Some(value);
+                    }
+                    if let Some(value) = file.warning_command {
+                        config.warning_command = Some(value);
+                    }
+                    if let Some(value) = file.max_battery_temp_celsius {
+                        config.max_battery_temp_celsius = Some(value);
+                    }
+                    if let Some(value) = file.log_level {
+                        config.log_level = value;
+                    }
+                    if let Some(value) = file.shutdown_inhibit_pidfiles {
+                        config.shutdown_inhibit_pidfiles = value;
+                    }
+                    if let Some(value) = file.shutdown_inhibit_processes {
+                        config.shutdown_inhibit_processes = value;
+                    }
+                    if let Some(value) = file.max_shutdown_inhibit_secs {
+                        config.max_shutdown_inhibit_secs = value;
+                    }
+                    if let Some(value) = file.critical_action {
+                        config.critical_action = value;
+                    }
+                    if let Some(value) = file.notifications {
+                        config.notifications = value;
+                    }
+                    if let Some(value) = file.notifications_bus {
+                        config.notifications_bus = value;
+                    }
+                    if let Some(value) = file.metrics_listen {
+                        config.metrics_listen = Some(value);
+                    }
+                    if let Some(value) = file.history {
+                        config.history = value;
+                    }
+                    if let Some(value) = file.history_interval_secs {
+                        config.history_interval_secs = value;
+                    }
+                    if let Some(value) = file.history_retention_days {
+                        config.history_retention_days = value;
+                    }
+                    if let Some(value) = file.charge_limit_percent {
+                        config.charge_limit_percent = Some(value);
+                    }
+                    if let Some(value) = file.charge_limit_path {
+                        config.charge_limit_path = Some(value);
+                    }
+                    if let Some(value) = file.slow_charger_watts {
+                        config.slow_charger_watts = value;
+                    }
+                    if let Some(value) = file.battery_device {
+                        config.battery_device = Some(value);
+                    }
+                    if let Some(value) = file.battery_allow {
+                        config.battery_allow = value;
+                    }
+                    if let Some(value) = file.battery_deny {
+                        config.battery_deny = value;
+                    }
+                    if let Some(value) = file.publish_peripherals {
+                        config.publish_peripherals = value;
+                    }
+                    if let Some(value) = file.output_dir {
+                        config.output_dir = value;
+                    }
+                    if let Some(value) = file.status_hysteresis_percent {
+                        config.status_hysteresis_percent = value;
+                    }
+                    if let Some(value) = file.status_hysteresis_secs {
+                        config.status_hysteresis_secs = value;
+                    }
+                    if let Some(value) = file.monotonic_battery_percent {
+                        config.monotonic_battery_percent = value;
+                    }
+                    if let Some(value) = file.voltage_curve {
+                        config.voltage_curve = value;
+                    }
+                    if let Some(value) = file.charger_supply {
+                        config.charger_supply = Some(value);
+                    }
+                    if let Some(value) = file.pre_shutdown {
+                        config.pre_shutdown = value;
+                    }
+                    if let Some(value) = file.wake_alarm {
+                        config.wake_alarm = value;
+                    }
+                    if let Some(value) = file.wake_alarm_margin_secs {
+                        config.wake_alarm_margin_secs = value;
+                    }
+                    if let Some(value) = file.hibernate_battery_percent {
+                        config.hibernate_battery_percent = Some(value);
+                    }
+                    if let Some(value) = file.locale {
+                        config.locale = value;
+                    }
+                    if let Some(value) = file.low_power_profile {
+                        config.low_power_profile = Some(value);
+                    }
+                    if let Some(value) = file.low_power_profile_battery_percent {
+                        config.low_power_profile_battery_percent = value;
+                    }
+                    if let Some(value) = file.backlight_cap {
+                        config.backlight_cap = value;
+                    }
+                    if let Some(value) = file.power_limit {
+                        config.power_limit = value;
+                    }
+                    if let Some(value) = file.full_dwell_secs {
+                        config.full_dwell_secs = Some(value);
+                    }
+                    if let Some(value) = file.idle_poll_interval_secs {
+                        config.idle_poll_interval_secs = value;
+                    }
+                    if let Some(value) = file.fast_poll_interval_secs {
+                        config.fast_poll_interval_secs = value;
+                    }
+                    if let Some(value) = file.fast_poll_battery_percent {
+                        config.fast_poll_battery_percent = value;
+                    }
+                    if let Some(value) = file.enable_forced_shutdown {
+                        config.enable_forced_shutdown = value;
+                    }
+                    if let Some(value) = file.emergency_voltage_volts {
+                        config.emergency_voltage_volts = Some(value);
+                    }
+                    if let Some(value) = file.emergency_voltage_samples {
+                        config.emergency_voltage_samples = value;
+                    }
+                    if let Some(value) = file.sag_volts_per_sec {
+                        config.sag_volts_per_sec = Some(value);
+                    }
+                    if let Some(value) = file.sag_min_watts {
+                        config.sag_min_watts = value;
+                    }
+                    if let Some(value) = file.mqtt {
+                        config.mqtt = Some(value);
+                    }
+                    if let Some(value) = file.charger_class {
+                        config.charger_class = value;
+                    }
+                    if let Some(value) = file.user {
+                        config.user = Some(value);
+                    }
+                    if let Some(value) = file.sandbox {
+                        config.sandbox = value;
+                    }
+                    if let Some(value) = file.remove_output_on_stop {
+                        config.remove_output_on_stop = value;
+                    }
+                    if let Some(value) = file.shutdown_confirmation_secs {
+                        config.shutdown_confirmation_secs = Some(value);
+                    }
+                    if let Some(value) = file.max_shutdown_postpones {
+                        config.max_shutdown_postpones = value;
+                    }
+                    if let Some(value) = file.charge_stall_secs {
+                        config.charge_stall_secs = Some(value);
+                    }
+                    if let Some(value) = file.charge_stall_charger_watts {
+                        config.charge_stall_charger_watts = value;
+                    }
+                    if let Some(value) = file.telemetry {
+                        config.telemetry = Some(value);
+                    }
+                    if let Some(value) = file.derived {
+                        config.derived = value;
+                    }
+                    if let Some(value) = file.policy_script {
+                        config.policy_script = Some(value);
+                    }
+                    if let Some(value) = file.hooks {
+                        config.hooks = value;
+                    }
+                }
+            },
+        }
+
+        // Reading sysfs much faster than this is pointless and wasteful.
+        for (name, poll_interval_secs) in [
+            ("poll_interval_secs", &mut config.poll_interval_secs),
+            (
+                "idle_poll_interval_secs",
+                &mut config.idle_poll_interval_secs,
+            ),
+            (
+                "fast_poll_interval_secs",
+                &mut config.fast_poll_interval_secs,
+            ),
+        ] {
+            clamp(
+                name,
+                poll_interval_secs,
+                MIN_POLL_INTERVAL_SECS,
+                MAX_POLL_INTERVAL_SECS,
+            );
+        }
+        for (name, secs) in [
+            (
+                "force_shutdown_timeout_secs",
+                Some(&mut config.force_shutdown_timeout_secs),
+            ),
+            (
+                "max_shutdown_inhibit_secs",
+                Some(&mut config.max_shutdown_inhibit_secs),
+            ),
+            (
+                "history_interval_secs",
+                Some(&mut config.history_interval_secs),
+            ),
+            (
+                "shutdown_confirmation_secs",
+                config.shutdown_confirmation_secs.as_mut(),
+            ),
+            ("charge_stall_secs", config.charge_stall_secs.as_mut()),
+        ] {
+            if let Some(secs) = secs {
+                clamp(name, secs, 0.0, MAX_DELAY_SECS);
+            }
+        }
+        for pre_shutdown in &mut config.pre_shutdown {
+            let name = format!("pre_shutdown {}: timeout_secs", pre_shutdown.command);
+            clamp(&name, &mut pre_shutdown.timeout_secs, 0.0, MAX_DELAY_SECS);
+        }
+        clamp(
+            "history_retention_days",
+            &mut config.history_retention_days,
+            0.0,
+            MAX_RETENTION_DAYS,
+        );
+
+        // Interpolating needs the points in order.
+        config.voltage_curve.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        // Better to fall back to the default than to find out about a
+        // typo when the battery is already empty.
+        if let Some(command) = &config.shutdown_command {
+            if find_executable(command).is_none() {
+                warn!("shutdown_command: {command} not found, using logind");
+                config.shutdown_command = None;
+                config.shutdown_args = Vec::new();
+            }
+        }
+
+        config
+    }
+
+    pub fn print(&self) {
+        info!(
+            "request_shutdown_battery_percent: {}",
+            self.request_shutdown_battery_percent
+        );
+        info!(
+            "force_shutdown_timeout_secs: {}",
+            self.force_shutdown_timeout_secs
+        );
+        info!("dbus: {}", self.dbus);
+        info!("poll_interval_secs: {}", self.poll_interval_secs);
+        info!("power_smoothing_samples: {}", self.power_smoothing_samples);
+        info!("sensors_chip: {:?}", self.sensors_chip);
+        info!("shutdown_command: {:?}", self.shutdown_command);
+        info!("shutdown_args: {:?}", self.shutdown_args);
+        info!(
+            "warning_battery_percent: {:?}",
+            self.warning_battery_percent
+        );
+        info!("warning_command: {:?}", self.warning_command);
+        info!(
+            "max_battery_temp_celsius: {:?}",
+            self.max_battery_temp_celsius
+        );
+        info!("log_level: {}", self.log_level);
+        info!(
+            "shutdown_inhibit_pidfiles: {:?}",
+            self.shutdown_inhibit_pidfiles
+        );
+        info!(
+            "shutdown_inhibit_processes: {:?}",
+            self.shutdown_inhibit_processes
+        );
+        info!(
+            "max_shutdown_inhibit_secs: {}",
+            self.max_shutdown_inhibit_secs
+        );
+        info!("critical_action: {}", self.critical_action);
+        info!("notifications: {}", self.notifications);
+        info!("notifications_bus: {:?}", self.notifications_bus);
+        info!("metrics_listen: {:?}", self.metrics_listen);
+        info!("history: {}", self.history);
+        info!("history_interval_secs: {}", self.history_interval_secs);
+        info!("history_retention_days: {}", self.history_retention_days);
+        info!("charge_limit_percent: {:?}", self.charge_limit_percent);
+        info!("charge_limit_path: {:?}", self.charge_limit_path);
+        info!("slow_charger_watts: {}", self.slow_charger_watts);
+        info!("battery_device: {:?}", self.battery_device);
+        info!("battery_allow: {:?}", self.battery_allow);
+        info!("battery_deny: {:?}", self.battery_deny);
+        info!("publish_peripherals: {}", self.publish_peripherals);
+        info!("output_dir: {}", self.output_dir);
+        info!(
+            "status_hysteresis_percent: {}",
+            self.status_hysteresis_percent
+        );
+        info!("status_hysteresis_secs: {}", self.status_hysteresis_secs);
+        info!(
+            "monotonic_battery_percent: {}",
+            self.monotonic_battery_percent
+        );
+        info!("voltage_curve: {:?}", self.voltage_curve);
+        self.quirks.print();
+        info!("charger_supply: {:?}", self.charger_supply);
+        for pre_shutdown in &self.pre_shutdown {
+            info!(
+                "pre_shutdown: {} {:?} (timeout {}s)",
+                pre_shutdown.command, pre_shutdown.args, pre_shutdown.timeout_secs
+            );
+        }
+        info!("wake_alarm: {}", self.wake_alarm);
+        info!("wake_alarm_margin_secs: {}", self.wake_alarm_margin_secs);
+        info!(
+            "hibernate_battery_percent: {:?}",
+            self.hibernate_battery_percent
+        );
+        info!("locale: {:?}", self.locale);
+        info!("low_power_profile: {:?}", self.low_power_profile);
+        info!(
+            "low_power_profile_battery_percent: {}",
+            self.low_power_profile_battery_percent
+        );
+        for cap in &self.backlight_cap {
+            info!(
+                "backlight_cap: {}% brightness at {}% battery",
+                cap.brightness_percent, cap.battery_percent
+            );
+        }
+        for limit in &self.power_limit {
+            info!(
+                "power_limit: {} = {} at {}% battery",
+                limit.path, limit.value, limit.battery_percent
+            );
+        }
+        info!("full_dwell_secs: {:?}", self.full_dwell_secs);
+        info!("idle_poll_interval_secs: {}", self.idle_poll_interval_secs);
+        info!("fast_poll_interval_secs: {}", self.fast_poll_interval_secs);
+        info!(
+            "fast_poll_battery_percent: {}",
+            self.fast_poll_battery_percent
+        );
+        info!("enable_forced_shutdown: {}", self.enable_forced_shutdown);
+        info!(
+            "emergency_voltage_volts: {:?}",
+            self.emergency_voltage_volts
+        );
+        info!(
+            "emergency_voltage_samples: {}",
+            self.emergency_voltage_samples
+        );
+        info!("sag_volts_per_sec: {:?}", self.sag_volts_per_sec);
+        info!("sag_min_watts: {}", self.sag_min_watts);
+        if let Some(mqtt) = &self.mqtt {
+            info!("mqtt: {} under {}/", mqtt.broker, mqtt.topic_prefix);
+        }
+        for class in &self.charger_class {
+            match class.below_watts {
+                Some(below_watts) => info!("charger_class: {} below {below_watts} W", class.name),
+                None => info!("charger_class: {} otherwise", class.name),
+            }
+        }
+        info!("user: {:?}", self.user);
+        info!("sandbox: {}", self.sandbox);
+        info!("remove_output_on_stop: {}", self.remove_output_on_stop);
+        info!(
+            "shutdown_confirmation_secs: {:?}",
+            self.shutdown_confirmation_secs
+        );
+        info!("max_shutdown_postpones: {}", self.max_shutdown_postpones);
+        info!("charge_stall_secs: {:?}", self.charge_stall_secs);
+        info!(
+            "charge_stall_charger_watts: {}",
+            self.charge_stall_charger_watts
+        );
+        if let Some(telemetry) = &self.telemetry {
+            info!("telemetry: {}", telemetry.path);
+        }
+        for (name, expr) in &self.derived {
+            info!("derived.{}: {}", name.as_str(), expr.source);
+        }
+        info!("policy_script: {:?}", self.policy_script);
+        self.hooks.print();
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+// Resolve a command the same way Command does, searching PATH unless
+// it contains a slash.
+pub fn find_executable(command: &str) -> Option<PathBuf> {
+    if command.contains('/') {
+        let path = PathBuf::from(command);
+        return is_executable(&path).then_some(path);
+    }
+
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|dir| dir.join(command))
+        .find(|path| is_executable(path))
+}
@@ -0,0 +1,128 @@
+use crate::signals;
+use libc::*;
+use log::{error, info};
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::thread;
+use std::time::Duration;
+
+// Editors save in several steps: wait for them to be done before
+// reloading, and reload once.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+const MASK: u32 = IN_CLOSE_WRITE | IN_MOVED_TO | IN_MOVED_FROM | IN_DELETE;
+
+fn add_watch(fd: c_int, dir: &Path) -> Option<c_int> {
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let wd = unsafe { inotify_add_watch(fd, path.as_ptr(), MASK) };
+    if wd < 0 {
+        error!("watch {}: {}", dir.display(), io::Error::last_os_error());
+        return None;
+    }
+    Some(wd)
+}
+
+// The names of the files changed in the events in buf, by watch.
+fn changed_files(buf: &[u8]) -> Vec<(c_int, &OsStr)> {
+    let mut changed = Vec::new();
+    let mut offset = 0;
+    while offset + mem::size_of::<inotify_event>() <= buf.len() {
+        let event: inotify_event =
+            unsafe { ptr::read_unaligned(buf[offset..].as_ptr() as *const inotify_event) };
+        let name_start = offset + mem::size_of::<inotify_event>();
+        let name_end = (name_start + event.len as usize).min(buf.len());
+        let name = &buf[name_start..name_end];
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        changed.push((event.wd, OsStr::from_bytes(name)));
+        offset = name_end;
+    }
+    changed
+}
+
+// Whether more events are waiting, without blocking.
+fn pending(fd: c_int) -> bool {
+    let mut pollfd = pollfd {
+        fd,
+        events: POLLIN,
+        revents: 0,
+    };
+    unsafe { poll(&mut pollfd, 1, 0) > 0 }
+}
+
+fn read_events(fd: c_int, buf: &mut [u8]) -> io::Result<usize> {
+    let len = unsafe { read(fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(len as usize)
+}
+
+// Request a reload whenever config_path or a .toml file in quirks_dir
+// changes. The directories are watched rather than the files, since
+// editors usually replace files instead of writing to them; a
+// quirks_dir created later is only noticed after a restart.
+pub fn start(config_path: &str, quirks_dir: &str) {
+    let config_path = Path::new(config_path);
+    let (Some(config_dir), Some(config_name)) = (config_path.parent(), config_path.file_name())
+    else {
+        return;
+    };
+    let config_name = config_name.to_owned();
+
+    let fd = unsafe { inotify_init1(IN_CLOEXEC) };
+    if fd < 0 {
+        error!("inotify_init1: {}", io::Error::last_os_error());
+        return;
+    }
+    let config_wd = add_watch(fd, config_dir);
+    let quirks_wd = if Path::new(quirks_dir).is_dir() {
+        add_watch(fd, Path::new(quirks_dir))
+    } else {
+        None
+    };
+    if config_wd.is_none() && quirks_wd.is_none() {
+        unsafe { close(fd) };
+        return;
+    }
+
+    let spawned = thread::Builder::new()
+        .name("config-watch".to_string())
+        .spawn(move || {
+            let mut buf = [0u8; 4096];
+            loop {
+                let len = match read_events(fd, &mut buf) {
+                    Ok(len) => len,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        error!("inotify read: {err}");
+                        return;
+                    }
+                };
+                let relevant = changed_files(&buf[..len]).iter().any(|&(wd, name)| {
+                    (Some(wd) == config_wd && name == config_name)
+                        || (Some(wd) == quirks_wd
+                            && Path::new(name).extension() == Some(OsStr::new("toml")))
+                });
+                if !relevant {
+                    continue;
+                }
+
+                thread::sleep(SETTLE_TIME);
+                while pending(fd) {
+                    if read_events(fd, &mut buf).is_err() {
+                        break;
+                    }
+                }
+                info!("Configuration changed.");
+                signals::request_reload();
+            }
+        });
+    if let Err(err) = spawned {
+        error!("config watch thread: {err}");
+        unsafe { close(fd) };
+    }
+}
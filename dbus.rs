@@ -0,0 +1,174 @@
+use crate::snapshot::Snapshot;
+use crate::stats::Stats;
+use zbus::{blocking::connection, blocking::Connection, interface, Result};
+
+const NAME: &str = "org.vpower.Power1";
+const PATH: &str = "/org/vpower/Power1";
+
+// Mirrors the values written to /run/vpower. Like the files, a value
+// keeps its last known state when it can't be calculated.
+#[derive(Default)]
+struct Power {
+    ac_status: String,
+    battery_percent: f64,
+    battery_status: String,
+    power_draw_watts: f64,
+    secs_until_battery_full: f64,
+    secs_until_shutdown_request: f64,
+    last_session_secs: f64,
+    last_session_average_watts: f64,
+    average_watts: f64,
+    battery_life_secs: f64,
+}
+
+#[interface(name = "org.vpower.Power1")]
+impl Power {
+    #[zbus(property)]
+    fn ac_status(&self) -> &str {
+        &self.ac_status
+    }
+
+    #[zbus(property)]
+    fn battery_percent(&self) -> f64 {
+        self.battery_percent
+    }
+
+    #[zbus(property)]
+    fn battery_status(&self) -> &str {
+        &self.battery_status
+    }
+
+    #[zbus(property)]
+    fn power_draw_watts(&self) -> f64 {
+        self.power_draw_watts
+    }
+
+    #[zbus(property)]
+    fn secs_until_battery_full(&self) -> f64 {
+        self.secs_until_battery_full
+    }
+
+    #[zbus(property)]
+    fn secs_until_shutdown_request(&self) -> f64 {
+        self.secs_until_shutdown_request
+    }
+
+    #[zbus(property)]
+    fn last_session_secs(&self) -> f64 {
+        self.last_session_secs
+    }
+
+    #[zbus(property)]
+    fn last_session_average_watts(&self) -> f64 {
+        self.last_session_average_watts
+    }
+
+    #[zbus(property)]
+    fn average_watts(&self) -> f64 {
+        self.average_watts
+    }
+
+    #[zbus(property)]
+    fn battery_life_secs(&self) -> f64 {
+        self.battery_life_secs
+    }
+}
+
+pub struct DbusService {
+    connection: Connection,
+}
+
+impl DbusService {
+    pub fn new() -> Result<DbusService> {
+        let connection = connection::Builder::system()?
+            .name(NAME)?
+            .serve_at(PATH, Power::default())?
+            .build()?;
+        Ok(DbusService { connection })
+    }
+
+    // Update properties, emitting PropertiesChanged for those that
+    // actually changed.
+    pub fn update(&self, snapshot: &Snapshot) -> Result<()> {
+        let iface_ref = self
+            .connection
+            .object_server()
+            .interface::<_, Power>(PATH)?;
+        let mut iface = iface_ref.get_mut();
+        let emitter = iface_ref.signal_emitter();
+
+        if let Some(val) = &snapshot.ac_status {
+            if iface.ac_status != *val {
+                iface.ac_status = val.clone();
+                zbus::block_on(iface.ac_status_changed(emitter))?;
+            }
+        }
+        if let Some(val) = snapshot.battery_percent {
+            if iface.battery_percent != val {
+                iface.battery_percent = val;
+                zbus::block_on(iface.battery_percent_changed(emitter))?;
+            }
+        }
+        if let Some(val) = &snapshot.battery_status {
+            if iface.battery_status != *val {
+                iface.battery_status = val.clone();
+                zbus::block_on(iface.battery_status_changed(emitter))?;
+            }
+        }
+        if let Some(val) = snapshot.power_draw_watts {
+            if iface.power_draw_watts != val {
+                iface.power_draw_watts = val;
+                zbus::block_on(iface.power_draw_watts_changed(emitter))?;
+            }
+        }
+        if let Some(val) = snapshot.secs_until_battery_full {
+            if iface.secs_until_battery_full != val {
+                iface.secs_until_battery_full = val;
+                zbus::block_on(iface.secs_until_battery_full_changed(emitter))?;
+            }
+        }
+        if let Some(val) = snapshot.secs_until_shutdown_request {
+            if iface.secs_until_shutdown_request != val {
+                iface.secs_until_shutdown_request = val;
+                zbus::block_on(iface.secs_until_shutdown_request_changed(emitter))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The statistics from the sessions on battery so far, 0 while
+    // unknown.
+    pub fn update_stats(&self, stats: &Stats) -> Result<()> {
+        let iface_ref = self
+            .connection
+            .object_server()
+            .interface::<_, Power>(PATH)?;
+        let mut iface = iface_ref.get_mut();
+        let emitter = iface_ref.signal_emitter();
+
+        let last_session = stats.last_session.as_ref();
+        let val = last_session.map_or(0.0, |session| session.duration_secs);
+        if iface.last_session_secs != val {
+            iface.last_session_secs = val;
+            zbus::block_on(iface.last_session_secs_changed(emitter))?;
+        }
+        let val = last_session.map_or(0.0, |session| session.average_watts);
+        if iface.last_session_average_watts != val {
+            iface.last_session_average_watts = val;
+            zbus::block_on(iface.last_session_average_watts_changed(emitter))?;
+        }
+        let val = stats.lifetime.average_watts().unwrap_or(0.0);
+        if iface.average_watts != val {
+            iface.average_watts = val;
+            zbus::block_on(iface.average_watts_changed(emitter))?;
+        }
+        let val = stats.lifetime.battery_life_secs().unwrap_or(0.0);
+        if iface.battery_life_secs != val {
+            iface.battery_life_secs = val;
+            zbus::block_on(iface.battery_life_secs_changed(emitter))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+use crate::battery::{BatteryReading, Units};
+use crate::snapshot::Snapshot;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// sysfs attributes of the battery, as read, usable along with the
+// values of status.json.
+const RAW: [&str; 4] = [
+    "charge_now",
+    "charge_full",
+    "charge_full_design",
+    "voltage_now",
+];
+
+// The same for batteries with energy_* files.
+const RAW_ENERGY: [&str; 3] = ["energy_now", "energy_full", "energy_full_design"];
+
+const FUNCTIONS: [&str; 3] = ["abs", "min", "max"];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Number(f64),
+    Variable(String),
+    Neg(Box<Node>),
+    Binary(char, Box<Node>, Box<Node>),
+    Call(String, Vec<Node>),
+}
+
+// An arithmetic expression over the sampled values, like
+// "energy_now / 1e6": numbers, variables, + - * /, parentheses, and
+// abs(x), min(x, y, ...) and max(x, y, ...).
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Expr {
+    pub source: String,
+    node: Node,
+}
+
+// The name of a derived value, and of its file: letters, digits, '_'
+// and '-'.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String")]
+pub struct Name(String);
+
+impl TryFrom<String> for Name {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Name, String> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if name.is_empty() || !name.chars().all(valid) {
+            return Err(format!("invalid name \"{name}\""));
+        }
+        Ok(Name(name))
+    }
+}
+
+impl Name {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    source: &'a str,
+}
+
+impl Parser<'_> {
+    fn skip_space(&mut self) {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_space();
+        self.chars.peek().map(|&(_, c)| c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.chars.next();
+                Ok(())
+            }
+            Some(c) => Err(format!("expected '{expected}', found '{c}'")),
+            None => Err(format!("expected '{expected}'")),
+        }
+    }
+
+    // Characters as long as they match.
+    fn take_while(&mut self, mut pred: impl FnMut(char) -> bool) -> &str {
+        let start = self.chars.peek().map_or(self.source.len(), |&(i, _)| i);
+        let mut end = start;
+        while let Some((i, c)) = self.chars.next_if(|&(_, c)| pred(c)) {
+            end = i + c.len_utf8();
+        }
+        &self.source[start..end]
+    }
+
+    fn expr(&mut self) -> Result<Node, String> {
+        let mut node = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.chars.next();
+            node = Node::Binary(op, Box::new(node), Box::new(self.term()?));
+        }
+        Ok(node)
+    }
+
+    fn term(&mut self) -> Result<Node, String> {
+        let mut node = self.factor()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.chars.next();
+            node = Node::Binary(op, Box::new(node), Box::new(self.factor()?));
+        }
+        Ok(node)
+    }
+
+    fn factor(&mut self) -> Result<Node, String> {
+        match self.peek() {
+            None => Err("unexpected end".to_string()),
+            Some('-') => {
+                self.chars.next();
+                Ok(Node::Neg(Box::new(self.factor()?)))
+            }
+            Some('(') => {
+                self.chars.next();
+                let node = self.expr()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let mut prev = ' ';
+                let number = self.take_while(|c| {
+                    // The sign of an exponent.
+                    let ok = c.is_ascii_alphanumeric()
+                        || c == '.'
+                        || ((c == '-' || c == '+') && prev == 'e');
+                    prev = c;
+                    ok
+                });
+                number
+                    .parse()
+                    .map(Node::Number)
+                    .map_err(|_| format!("invalid number '{number}'"))
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let name = self
+                    .take_while(|c| c.is_ascii_alphanumeric() || c == '_')
+                    .to_string();
+                if self.peek() != Some('(') {
+                    return Ok(Node::Variable(name));
+                }
+                self.chars.next();
+                let mut args = vec![self.expr()?];
+                while self.peek() == Some(',') {
+                    self.chars.next();
+                    args.push(self.expr()?);
+                }
+                self.expect(')')?;
+                Ok(Node::Call(name, args))
+            }
+            Some(c) => Err(format!("unexpected '{c}'")),
+        }
+    }
+}
+
+// Whether the variables and functions exist.
+fn check(node: &Node, known: &[String]) -> Result<(), String> {
+    match node {
+        Node::Number(_) => Ok(()),
+        Node::Variable(name) if known.contains(name) => Ok(()),
+        Node::Variable(name) => Err(format!("unknown variable '{name}'")),
+        Node::Neg(node) => check(node, known),
+        Node::Binary(_, left, right) => check(left, known).and(check(right, known)),
+        Node::Call(name, args) => {
+            if !FUNCTIONS.contains(&name.as_str()) {
+                return Err(format!("unknown function '{name}'"));
+            }
+            if name == "abs" && args.len() != 1 {
+                return Err("abs takes one argument".to_string());
+            }
+            args.iter().try_for_each(|arg| check(arg, known))
+        }
+    }
+}
+
+fn eval(node: &Node, vars: &HashMap<String, f64>) -> Option<f64> {
+    Some(match node {
+        Node::Number(val) => *val,
+        Node::Variable(name) => *vars.get(name)?,
+        Node::Neg(node) => -eval(node, vars)?,
+        Node::Binary(op, left, right) => {
+            let (left, right) = (eval(left, vars)?, eval(right, vars)?);
+            match op {
+                '+' => left + right,
+                '-' => left - right,
+                '*' => left * right,
+                _ => left / right,
+            }
+        }
+        Node::Call(name, args) => {
+            let args = args
+                .iter()
+                .map(|arg| eval(arg, vars))
+                .collect::<Option<Vec<_>>>()?;
+            match name.as_str() {
+                "abs" => args[0].abs(),
+                "min" => args.into_iter().fold(f64::INFINITY, f64::min),
+                _ => args.into_iter().fold(f64::NEG_INFINITY, f64::max),
+            }
+        }
+    })
+}
+
+// The names expressions can use.
+fn known_variables() -> Vec<String> {
+    let snapshot = serde_json::to_value(Snapshot::default()).unwrap_or_default();
+    let status = snapshot
+        .as_object()
+        .into_iter()
+        .flat_map(|map| map.keys().cloned());
+    status
+        .chain(RAW.into_iter().chain(RAW_ENERGY).map(String::from))
+        .collect()
+}
+
+impl TryFrom<String> for Expr {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Expr, String> {
+        let mut parser = Parser {
+            chars: source.char_indices().peekable(),
+            source: &source,
+        };
+        let node = parser
+            .expr()
+            .and_then(|node| match parser.peek() {
+                Some(c) => Err(format!("unexpected '{c}'")),
+                None => Ok(node),
+            })
+            .and_then(|node| check(&node, &known_variables()).map(|()| node))
+            .map_err(|err| format!("{err} in \"{source}\""))?;
+        Ok(Expr { source, node })
+    }
+}
+
+impl Expr {
+    // None when a variable it uses has no value, or the result isn't
+    // a number, like after dividing by zero.
+    pub fn eval(&self, vars: &HashMap<String, f64>) -> Option<f64> {
+        eval(&self.node, vars).filter(|val| val.is_finite())
+    }
+}
+
+// The values of status.json, booleans as 0 or 1, and the battery's
+// sysfs attributes.
+pub fn variables(snapshot: &Snapshot, reading: &BatteryReading) -> HashMap<String, f64> {
+    let mut vars = HashMap::new();
+    if let Ok(serde_json::Value::Object(status)) = serde_json::to_value(snapshot) {
+        for (name, val) in status {
+            let val = match val {
+                serde_json::Value::Bool(val) => Some(f64::from(u8::from(val))),
+                val => val.as_f64(),
+            };
+            if let Some(val) = val {
+                vars.insert(name, val);
+            }
+        }
+    }
+    let [now, full, full_design] = match reading.units {
+        Units::Charge => ["charge_now", "charge_full", "charge_full_design"],
+        Units::Energy => RAW_ENERGY,
+    };
+    let raw = [
+        (now, reading.charge_now),
+        (full, reading.charge_full),
+        (full_design, reading.charge_full_design),
+        ("voltage_now", reading.voltage_now),
+    ];
+    for (name, val) in raw {
+        if let Some(val) = val {
+            vars.insert(name.to_string(), val);
+        }
+    }
+    vars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expr(source: &str) -> Result<Expr, String> {
+        Expr::try_from(source.to_string())
+    }
+
+    #[test]
+    fn evaluates() {
+        let vars = HashMap::from([
+            ("energy_now".to_string(), 25_000_000.0),
+            ("net_power_watts".to_string(), -10.0),
+            ("battery_full_wh".to_string(), 40.0),
+        ]);
+        let eval = |source: &str| expr(source).unwrap().eval(&vars);
+        assert_eq!(eval("energy_now / 1e6"), Some(25.0));
+        assert_eq!(eval("net_power_watts / battery_full_wh * 100"), Some(-25.0));
+        assert_eq!(eval("-(2 + 3) * 2 - 1"), Some(-11.0));
+        assert_eq!(eval("max(abs(net_power_watts), 1.5e1, 3)"), Some(15.0));
+        // Missing values and division by zero.
+        assert_eq!(eval("battery_percent * 2"), None);
+        assert_eq!(eval("1 / 0"), None);
+    }
+
+    #[test]
+    fn rejects() {
+        assert!(expr("energy_now /").is_err());
+        assert!(expr("(1 + 2").is_err());
+        assert!(expr("1 2").is_err());
+        assert!(expr("foo * 2").is_err());
+        assert!(expr("sqrt(4)").is_err());
+        assert!(expr("abs(1, 2)").is_err());
+    }
+
+    #[test]
+    fn names() {
+        assert!(Name::try_from("pack_wh".to_string()).is_ok());
+        assert!(Name::try_from("pack-wh2".to_string()).is_ok());
+        assert!(Name::try_from(String::new()).is_err());
+        assert!(Name::try_from("..".to_string()).is_err());
+        assert!(Name::try_from("a/b".to_string()).is_err());
+    }
+}
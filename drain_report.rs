@@ -0,0 +1,153 @@
+use crate::{sysfs, write_str};
+use log::error;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const POWERCAP_DIR: &str = "/sys/class/powercap";
+
+// How long to watch what's running for.
+const INTERVAL: Duration = Duration::from_secs(2);
+
+// How many processes to list.
+const TOP_PROCESSES: usize = 10;
+
+#[derive(Serialize)]
+struct Report {
+    timestamp: f64,
+    battery_percent: Option<f64>,
+    power_draw_watts: Option<f64>,
+    interval_secs: f64,
+    processes: Vec<Process>,
+    rapl: Vec<Domain>,
+}
+
+#[derive(Serialize)]
+struct Process {
+    pid: u32,
+    name: String,
+    // Of one CPU, so above 100 for multithreaded processes.
+    cpu_percent: f64,
+}
+
+#[derive(Serialize)]
+struct Domain {
+    name: String,
+    watts: f64,
+}
+
+// CPU time used by each process so far, in clock ticks, with its name.
+fn cpu_times() -> HashMap<u32, (String, u64)> {
+    let mut times = HashMap::new();
+    for entry in fs::read_dir("/proc").into_iter().flatten().flatten() {
+        let pid = match entry.file_name().to_string_lossy().parse::<u32>() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+        let stat = match fs::read_to_string(entry.path().join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        // The name is in parentheses and can contain anything, so the
+        // other fields are counted from the last one: utime and stime
+        // are the 12th and 13th after it.
+        let (name, rest) = match (stat.find('('), stat.rfind(')')) {
+            (Some(start), Some(end)) if start < end => (&stat[start + 1..end], &stat[end + 1..]),
+            _ => continue,
+        };
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+        let ticks = |i: usize| fields.get(i).and_then(|field| field.parse::<u64>().ok());
+        if let (Some(utime), Some(stime)) = (ticks(11), ticks(12)) {
+            times.insert(pid, (name.to_string(), utime + stime));
+        }
+    }
+    times
+}
+
+// RAPL energy counters, in µJ, by domain name.
+fn rapl_energy() -> Vec<(PathBuf, String, u64)> {
+    let mut domains: Vec<(PathBuf, String, u64)> = fs::read_dir(sysfs(POWERCAP_DIR))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            let name = fs::read_to_string(path.join("name")).ok()?;
+            let energy = fs::read_to_string(path.join("energy_uj")).ok()?;
+            Some((path, name.trim().to_string(), energy.trim().parse().ok()?))
+        })
+        .collect();
+    domains.sort();
+    domains
+}
+
+fn measure(battery_percent: Option<f64>, power_draw_watts: Option<f64>) -> Report {
+    let start = Instant::now();
+    let times_before = cpu_times();
+    let rapl_before = rapl_energy();
+    thread::sleep(INTERVAL);
+    let times_after = cpu_times();
+    let rapl_after = rapl_energy();
+    let secs = start.elapsed().as_secs_f64();
+
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+    let mut processes: Vec<Process> = times_after
+        .into_iter()
+        .filter_map(|(pid, (name, after))| {
+            let before = times_before.get(&pid).map_or(0, |(_, before)| *before);
+            let cpu_percent = after.checked_sub(before)? as f64 / ticks_per_sec / secs * 100.0;
+            (cpu_percent > 0.0).then_some(Process {
+                pid,
+                name,
+                cpu_percent,
+            })
+        })
+        .collect();
+    processes.sort_by(|a, b| b.cpu_percent.total_cmp(&a.cpu_percent));
+    processes.truncate(TOP_PROCESSES);
+
+    // Counters wrap around, skip those that did.
+    let rapl = rapl_after
+        .into_iter()
+        .filter_map(|(path, name, after)| {
+            let (_, _, before) = rapl_before.iter().find(|(p, _, _)| *p == path)?;
+            let joules = after.checked_sub(*before)? as f64 / 1e6;
+            Some(Domain {
+                name,
+                watts: joules / secs,
+            })
+        })
+        .collect();
+
+    Report {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |duration| duration.as_secs_f64()),
+        battery_percent,
+        power_draw_watts,
+        interval_secs: secs,
+        processes,
+        rapl,
+    }
+}
+
+// Find out what's using power, in the background as it takes a
+// moment, and write it to <dir_path>/drain_report.json.
+pub fn capture(dir_path: &str, battery_percent: Option<f64>, power_draw_watts: Option<f64>) {
+    let dir_path = dir_path.to_string();
+    let spawned = thread::Builder::new()
+        .name("drain_report".to_string())
+        .spawn(move || {
+            let report = measure(battery_percent, power_draw_watts);
+            match serde_json::to_string_pretty(&report) {
+                Ok(json) => write_str(&dir_path, "drain_report.json", Some(&json)),
+                Err(err) => error!("drain_report.json: {err}"),
+            }
+        });
+    if let Err(err) = spawned {
+        error!("drain_report: {err}");
+    }
+}
@@ -0,0 +1,16 @@
+use thiserror::Error;
+
+// What can keep vpower from starting, or go wrong while running.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("no battery found")]
+    NoBattery,
+    #[error("{path}: {message}")]
+    Config { path: String, message: String },
+    #[error("{command}: {reason}")]
+    Command { command: String, reason: String },
+    #[error("drop privileges to {user}: {reason}")]
+    DropPrivileges { user: String, reason: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
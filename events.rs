@@ -0,0 +1,43 @@
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+// What wakes the main loop up before its poll interval is over.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wakeup {
+    // A power_supply uevent.
+    PowerSupply,
+    // SIGHUP, SIGTERM or SIGINT, or a reload asked for another way.
+    Signal,
+    // A command on the socket for the main loop to act on.
+    Request,
+}
+
+// The threads listening for uevents, signals and socket commands send
+// to the main loop through this, instead of it polling each of them.
+pub struct EventLoop {
+    sender: Sender<Wakeup>,
+    receiver: Receiver<Wakeup>,
+}
+
+impl EventLoop {
+    pub fn new() -> EventLoop {
+        let (sender, receiver) = mpsc::channel();
+        EventLoop { sender, receiver }
+    }
+
+    pub fn sender(&self) -> Sender<Wakeup> {
+        self.sender.clone()
+    }
+
+    // Block until woken up or the timeout expires, returning why, all
+    // at once when several things happened meanwhile.
+    pub fn wait(&self, timeout: Duration) -> Vec<Wakeup> {
+        let mut wakeups = match self.receiver.recv_timeout(timeout) {
+            Ok(wakeup) => vec![wakeup],
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return Vec::new(),
+        };
+        wakeups.extend(self.receiver.try_iter());
+        wakeups.dedup();
+        wakeups
+    }
+}
@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COPIES: AtomicUsize = AtomicUsize::new(0);
+
+// A recorded sysfs tree under tests/fixtures.
+pub fn fixture_dir(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+// A path in the temporary directory that no other test uses, for a
+// file or a directory named after name.
+fn scratch_path(name: &str) -> PathBuf {
+    let copy = COPIES.fetch_add(1, Ordering::SeqCst);
+    let name = format!("vpower-test-{}-{copy}-{name}", std::process::id());
+    std::env::temp_dir().join(name)
+}
+
+// Such a path, for a test to create a file or a directory at. Removed
+// when dropped.
+pub struct Scratch {
+    pub path: PathBuf,
+}
+
+impl Scratch {
+    pub fn new(name: &str) -> Scratch {
+        Scratch {
+            path: scratch_path(name),
+        }
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path).or_else(|_| fs::remove_file(&self.path));
+    }
+}
+
+// A scratch copy of a device of a fixture, like "power_supply/BAT1" of
+// "steamdeck-discharging", for a test to change files in. Removed when
+// dropped.
+pub struct FixtureCopy {
+    pub path: PathBuf,
+}
+
+impl FixtureCopy {
+    pub fn new(name: &str, device: &str) -> FixtureCopy {
+        let path = scratch_path("sysfs");
+        fs::create_dir_all(&path).unwrap();
+        let src = fixture_dir(name).join(device);
+        for entry in fs::read_dir(src).unwrap().flatten() {
+            fs::copy(entry.path(), path.join(entry.file_name())).unwrap();
+        }
+        FixtureCopy { path }
+    }
+}
+
+impl Drop for FixtureCopy {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
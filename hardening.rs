@@ -0,0 +1,121 @@
+use crate::sysfs;
+use landlock::{
+    path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetError,
+    RulesetStatus, ABI,
+};
+use log::{error, info, warn};
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+use std::collections::BTreeMap;
+use std::env::consts::ARCH;
+use std::fs;
+use std::path::Path;
+
+// What vpower, its hooks and the commands it runs read, including
+// /etc/vpower, the configuration and the executables.
+const READABLE: &[&str] = &[
+    "/etc", "/usr", "/bin", "/sbin", "/lib", "/lib64", "/proc", "/run", "/dev",
+];
+
+// System calls nothing vpower runs has a use for, but an attacker
+// would, failing with EPERM.
+const DENIED_SYSCALLS: &[i64] = &[
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_adjtimex,
+    libc::SYS_bpf,
+    libc::SYS_chroot,
+    libc::SYS_clock_settime,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_init_module,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_mount,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_request_key,
+    libc::SYS_setns,
+    libc::SYS_settimeofday,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+    libc::SYS_userfaultfd,
+];
+
+// A rule for a path that doesn't exist fails the whole ruleset, and
+// there's nothing to allow under it anyway.
+fn existing<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> Vec<P> {
+    paths
+        .into_iter()
+        .filter(|path| path.as_ref().exists())
+        .collect()
+}
+
+// Only reading READABLE, writing the files under /sys (or
+// --sysfs-root), and anything under writable. Applies to the calling
+// thread and everything it starts from now on.
+fn restrict_paths(writable: &[&str]) -> Result<RulesetStatus, RulesetError> {
+    let abi = ABI::V2;
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(
+            existing(READABLE),
+            AccessFs::from_read(abi),
+        ))?
+        .add_rules(path_beneath_rules(
+            existing([sysfs("/sys/")]),
+            AccessFs::from_read(abi) | AccessFs::WriteFile,
+        ))?
+        .add_rules(path_beneath_rules(
+            existing(writable),
+            AccessFs::from_all(abi),
+        ))?
+        .restrict_self()?;
+    Ok(status.ruleset)
+}
+
+// For all threads.
+fn deny_syscalls() -> seccompiler::Result<()> {
+    let rules = DENIED_SYSCALLS
+        .iter()
+        .map(|&syscall| (syscall, Vec::new()))
+        .collect::<BTreeMap<_, _>>();
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        ARCH.try_into()?,
+    )?;
+    let program: BpfProgram = filter.try_into()?;
+    seccompiler::apply_filter_all_threads(&program)
+}
+
+// Sandbox vpower as well as the kernel allows: landlock for the
+// calling thread and those it starts later, so before starting any,
+// and seccomp for the whole process. The writable directories are
+// created first, as they can't be once sandboxed.
+pub fn apply(writable: &[&str]) {
+    for dir in writable {
+        if let Err(err) = fs::create_dir_all(dir) {
+            warn!("mkdir {dir}: {err}, not writable in the sandbox");
+        }
+    }
+    match restrict_paths(writable) {
+        Err(err) => error!("landlock: {err}"),
+        Ok(RulesetStatus::FullyEnforced) => info!("Restricted file access with landlock."),
+        Ok(RulesetStatus::PartiallyEnforced) => {
+            warn!("landlock: only partially supported by this kernel")
+        }
+        Ok(RulesetStatus::NotEnforced) => warn!("landlock: not supported by this kernel"),
+    }
+    match deny_syscalls() {
+        Err(err) => error!("seccomp: {err}"),
+        Ok(()) => info!("Restricted system calls with seccomp."),
+    }
+}
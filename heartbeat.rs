@@ -0,0 +1,60 @@
+use crate::write_str;
+use serde::Serialize;
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// <output_dir>/daemon_info.json, updated along with the heartbeat.
+#[derive(Serialize)]
+struct DaemonInfo {
+    version: &'static str,
+    pid: u32,
+    started_at: f64,
+    uptime_secs: f64,
+}
+
+// <output_dir>/heartbeat is rewritten after every successful
+// iteration as "<counter> <timestamp> <poll_interval_secs>": a counter
+// going up by one each time, the Unix time of the update, and how long
+// until the next one is due. Consumers should treat everything in
+// output_dir as stale, like when vpower hung or stopped, once
+// timestamp is more than 3 times poll_interval_secs in the past.
+pub struct Heartbeat {
+    started: Instant,
+    started_at: f64,
+    counter: u64,
+}
+
+fn now_secs() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |duration| duration.as_secs_f64())
+}
+
+impl Heartbeat {
+    pub fn new() -> Heartbeat {
+        Heartbeat {
+            started: Instant::now(),
+            started_at: now_secs(),
+            counter: 0,
+        }
+    }
+
+    pub fn beat(&mut self, dir_path: &str, poll_interval: Duration) {
+        self.counter += 1;
+        let info = DaemonInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            pid: process::id(),
+            started_at: self.started_at,
+            uptime_secs: self.started.elapsed().as_secs_f64(),
+        };
+        let json = serde_json::to_string(&info).unwrap();
+        write_str(dir_path, "daemon_info.json", Some(&json));
+        let heartbeat = format!(
+            "{} {:.3} {}",
+            self.counter,
+            now_secs(),
+            poll_interval.as_secs_f64()
+        );
+        write_str(dir_path, "heartbeat", Some(&heartbeat));
+    }
+}
@@ -0,0 +1,170 @@
+use crate::snapshot::Snapshot;
+use log::{error, info};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const HISTORY_DIR: &str = "/var/lib/vpower";
+
+const HEADER: &str =
+    "timestamp,os_version,ac_status,battery_percent,battery_status,power_draw_watts";
+
+// How often to drop samples past the retention period.
+const TRIM_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Samples appended to a CSV file, so that battery life can be compared
+// across OS updates.
+pub struct History {
+    path: PathBuf,
+    os_version: String,
+    interval: Duration,
+    retention: Duration,
+    last_sample: Option<Instant>,
+    // The latest sample that wasn't due yet, for flush().
+    pending: Option<String>,
+    last_trim: Option<Instant>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+// VERSION_ID from os-release, or BUILD_ID, which SteamOS bumps more
+// often.
+fn os_version() -> String {
+    let os_release = fs::read_to_string("/etc/os-release").unwrap_or_default();
+    let field = |key: &str| {
+        os_release.lines().find_map(|line| {
+            let val = line.strip_prefix(key)?.strip_prefix('=')?;
+            Some(val.trim_matches('"').to_string())
+        })
+    };
+    match (field("VERSION_ID"), field("BUILD_ID")) {
+        (Some(version), Some(build)) => format!("{version}+{build}"),
+        (version, build) => version.or(build).unwrap_or_default(),
+    }
+}
+
+pub(crate) fn csv_field(val: Option<&str>) -> String {
+    match val {
+        Some(val) if val.contains([',', '"']) => format!("\"{}\"", val.replace('"', "\"\"")),
+        Some(val) => val.to_string(),
+        None => String::new(),
+    }
+}
+
+fn csv_f64(val: Option<f64>) -> String {
+    val.map(|val| val.to_string()).unwrap_or_default()
+}
+
+impl History {
+    pub fn new(interval_secs: f64, retention_days: f64) -> History {
+        History {
+            path: Path::new(HISTORY_DIR).join("history.csv"),
+            os_version: os_version(),
+            interval: Duration::from_secs_f64(interval_secs.max(1.0)),
+            retention: Duration::from_secs_f64(retention_days.max(0.0) * 86400.0),
+            last_sample: None,
+            pending: None,
+            last_trim: None,
+        }
+    }
+
+    // Append a sample, if one is due.
+    pub fn record(&mut self, snapshot: &Snapshot) {
+        let row = self.row(snapshot);
+        if self
+            .last_sample
+            .is_some_and(|last_sample| last_sample.elapsed() < self.interval)
+        {
+            self.pending = Some(row);
+            return;
+        }
+        self.last_sample = Some(Instant::now());
+        self.pending = None;
+
+        if let Err(err) = self.append(&row) {
+            error!("write {}: {err}", self.path.display());
+        }
+
+        if self
+            .last_trim
+            .is_none_or(|last_trim| last_trim.elapsed() >= TRIM_INTERVAL)
+        {
+            self.last_trim = Some(Instant::now());
+            if let Err(err) = self.trim() {
+                error!("trim {}: {err}", self.path.display());
+            }
+        }
+    }
+
+    // Append the latest sample even if it isn't due yet, when stopping.
+    pub fn flush(&mut self) {
+        if let Some(row) = self.pending.take() {
+            if let Err(err) = self.append(&row) {
+                error!("write {}: {err}", self.path.display());
+            }
+        }
+    }
+
+    fn row(&self, snapshot: &Snapshot) -> String {
+        format!(
+            "{},{},{},{},{},{}",
+            now_secs(),
+            csv_field(Some(&self.os_version)),
+            csv_field(snapshot.ac_status.as_deref()),
+            csv_f64(snapshot.battery_percent),
+            csv_field(snapshot.battery_status.as_deref()),
+            csv_f64(snapshot.power_draw_watts),
+        )
+    }
+
+    fn append(&self, row: &str) -> io::Result<()> {
+        fs::create_dir_all(HISTORY_DIR)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{HEADER}")?;
+        }
+        writeln!(file, "{row}")
+    }
+
+    // Rewrite the file without the samples older than the retention
+    // period.
+    fn trim(&self) -> io::Result<()> {
+        let oldest = now_secs().saturating_sub(self.retention.as_secs());
+        let reader = BufReader::new(File::open(&self.path)?);
+        let mut kept = Vec::new();
+        let mut dropped = 0;
+        for line in reader.lines().skip(1) {
+            let line = line?;
+            let timestamp = line
+                .split(',')
+                .next()
+                .and_then(|timestamp| timestamp.parse::<u64>().ok());
+            if timestamp.is_some_and(|timestamp| timestamp >= oldest) {
+                kept.push(line);
+            } else {
+                dropped += 1;
+            }
+        }
+        if dropped == 0 {
+            return Ok(());
+        }
+
+        let tmp_path = self.path.with_extension("csv.tmp");
+        let mut file = File::create(&tmp_path)?;
+        writeln!(file, "{HEADER}")?;
+        for line in kept {
+            writeln!(file, "{line}")?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        info!("Dropped {dropped} history samples past retention.");
+        Ok(())
+    }
+}
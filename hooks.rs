@@ -0,0 +1,171 @@
+use crate::snapshot::Snapshot;
+use log::{error, info};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+
+pub const HOOKS_DIR: &str = "/etc/vpower/hooks.d";
+
+// Executables to run on power events, relative to HOOKS_DIR unless
+// absolute.
+#[derive(Clone, Default, Deserialize)]
+pub struct Hooks {
+    pub ac_connected: Option<String>,
+    pub ac_disconnected: Option<String>,
+    pub battery_low: Option<String>,
+    pub battery_critical: Option<String>,
+    pub battery_full: Option<String>,
+    pub battery_overheat: Option<String>,
+    pub full_dwell: Option<String>,
+    pub pd_changed: Option<String>,
+    pub load_shed: Option<String>,
+    pub shutdown_pending: Option<String>,
+    pub charge_stalled: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+pub enum Event {
+    AcConnected,
+    AcDisconnected,
+    BatteryLow,
+    BatteryCritical,
+    BatteryFull,
+    BatteryOverheat,
+    FullDwell,
+    PdChanged,
+    LoadShed,
+    ShutdownPending,
+    ChargeStalled,
+}
+
+impl Event {
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::AcConnected => "ac_connected",
+            Event::AcDisconnected => "ac_disconnected",
+            Event::BatteryLow => "battery_low",
+            Event::BatteryCritical => "battery_critical",
+            Event::BatteryFull => "battery_full",
+            Event::BatteryOverheat => "battery_overheat",
+            Event::FullDwell => "full_dwell",
+            Event::PdChanged => "pd_changed",
+            Event::LoadShed => "load_shed",
+            Event::ShutdownPending => "shutdown_pending",
+            Event::ChargeStalled => "charge_stalled",
+        }
+    }
+}
+
+impl Hooks {
+    fn get(&self, event: Event) -> Option<&str> {
+        let hook = match event {
+            Event::AcConnected => &self.ac_connected,
+            Event::AcDisconnected => &self.ac_disconnected,
+            Event::BatteryLow => &self.battery_low,
+            Event::BatteryCritical => &self.battery_critical,
+            Event::BatteryFull => &self.battery_full,
+            Event::BatteryOverheat => &self.battery_overheat,
+            Event::FullDwell => &self.full_dwell,
+            Event::PdChanged => &self.pd_changed,
+            Event::LoadShed => &self.load_shed,
+            Event::ShutdownPending => &self.shutdown_pending,
+            Event::ChargeStalled => &self.charge_stalled,
+        };
+        hook.as_deref()
+    }
+
+    pub fn print(&self) {
+        for event in [
+            Event::AcConnected,
+            Event::AcDisconnected,
+            Event::BatteryLow,
+            Event::BatteryCritical,
+            Event::BatteryFull,
+            Event::BatteryOverheat,
+            Event::FullDwell,
+            Event::PdChanged,
+            Event::LoadShed,
+            Event::ShutdownPending,
+            Event::ChargeStalled,
+        ] {
+            if let Some(hook) = self.get(event) {
+                info!("hooks.{}: {}", event.name(), resolve(hook).display());
+            }
+        }
+    }
+
+    // Run the hook for event, if any, describing the current state in
+    // its environment.
+    pub fn fire(&self, event: Event, snapshot: &Snapshot) {
+        if let Some(hook) = self.get(event) {
+            info!("Running {} hook.", event.name());
+            spawn(&resolve(hook).display().to_string(), &env(event, snapshot));
+        }
+    }
+}
+
+fn resolve(hook: &str) -> PathBuf {
+    PathBuf::from(HOOKS_DIR).join(hook)
+}
+
+fn env(event: Event, snapshot: &Snapshot) -> Vec<(&'static str, String)> {
+    let mut env = vec![("VPOWER_EVENT", event.name().to_string())];
+    if let Some(val) = &snapshot.ac_status {
+        env.push(("VPOWER_AC_STATUS", val.clone()));
+    }
+    if let Some(val) = snapshot.battery_percent {
+        env.push(("VPOWER_BATTERY_PERCENT", val.to_string()));
+    }
+    if let Some(val) = &snapshot.battery_status {
+        env.push(("VPOWER_BATTERY_STATUS", val.clone()));
+    }
+    if let Some(val) = &snapshot.pd_status {
+        env.push(("VPOWER_PD_STATUS", val.clone()));
+    }
+    if let Some(val) = snapshot.pd_wattage {
+        env.push(("VPOWER_PD_WATTAGE", val.to_string()));
+    }
+    if let Some(val) = snapshot.battery_temp_celsius {
+        env.push(("VPOWER_BATTERY_TEMP_CELSIUS", val.to_string()));
+    }
+    if let Some(val) = snapshot.power_draw_watts {
+        env.push(("VPOWER_POWER_DRAW_WATTS", val.to_string()));
+    }
+    if let Some(val) = snapshot.secs_until_battery_full {
+        env.push(("VPOWER_SECS_UNTIL_BATTERY_FULL", val.to_string()));
+    }
+    if let Some(val) = snapshot.secs_at_full {
+        env.push(("VPOWER_SECS_AT_FULL", val.to_string()));
+    }
+    if let Some(val) = snapshot.secs_until_battery_empty {
+        env.push(("VPOWER_SECS_UNTIL_BATTERY_EMPTY", val.to_string()));
+    }
+    if let Some(val) = snapshot.secs_until_shutdown_request {
+        env.push(("VPOWER_SECS_UNTIL_SHUTDOWN_REQUEST", val.to_string()));
+    }
+    env
+}
+
+// Run a command without waiting for it, reaping it in the background.
+pub fn spawn(command: &str, env: &[(&str, String)]) {
+    let mut cmd = Command::new(command);
+    for (key, val) in env {
+        cmd.env(key, val);
+    }
+    spawn_command(cmd);
+}
+
+pub fn spawn_command(mut cmd: Command) {
+    let command = cmd.get_program().to_string_lossy().into_owned();
+    match cmd.spawn() {
+        Err(err) => error!("{command}: {err}"),
+        Ok(mut child) => {
+            thread::spawn(move || match child.wait() {
+                Err(err) => error!("{command}: {err}"),
+                Ok(status) if !status.success() => error!("{command}: {status}"),
+                Ok(_) => {}
+            });
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::sensors::{matches, Error};
+use crate::sysfs;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const HWMON_DIR: &str = "/sys/class/hwmon";
+
+// A hwmon chip read directly through sysfs, for when libsensors isn't
+// available. Voltages are in mV and currents in mA there.
+pub struct Chip {
+    pub name: String,
+    pub path: PathBuf,
+    in_input: Result<PathBuf, Error>,
+    curr_input: Result<PathBuf, Error>,
+}
+
+// The first hwmon device whose name matches one of the patterns, tried
+// in order.
+pub fn find_chip(patterns: &[&str]) -> Result<Chip, Error> {
+    find_chip_in(&sysfs(HWMON_DIR), patterns)
+}
+
+fn find_chip_in(dir: &Path, patterns: &[&str]) -> Result<Chip, Error> {
+    let mut chips: Vec<(String, PathBuf)> = fs::read_dir(dir)
+        .map_err(|err| Error::Read(format!("{}: {err}", dir.display())))?
+        .flatten()
+        .filter_map(|entry| {
+            let name = fs::read_to_string(entry.path().join("name")).ok()?;
+            Some((name.trim().to_string(), entry.path()))
+        })
+        .collect();
+    chips.sort_by(|a, b| a.1.cmp(&b.1));
+
+    for pattern in patterns {
+        let found = chips
+            .iter()
+            .find(|(name, _)| matches(pattern.as_bytes(), name.as_bytes()));
+        if let Some((name, path)) = found {
+            return Ok(Chip::open(name.clone(), path.clone()));
+        }
+    }
+    Err(Error::ChipNotFound(patterns.join(", ")))
+}
+
+// The lowest numbered <prefix>N_input file, like libsensors would pick.
+fn find_input(path: &Path, prefix: &str) -> Result<PathBuf, Error> {
+    let mut inputs: Vec<(u32, PathBuf)> = fs::read_dir(path)
+        .map_err(|err| Error::Read(format!("{}: {err}", path.display())))?
+        .flatten()
+        .filter_map(|entry| {
+            let file_name = entry.file_name();
+            let number = file_name
+                .to_str()?
+                .strip_prefix(prefix)?
+                .strip_suffix("_input")?;
+            Some((u32::from_str(number).ok()?, entry.path()))
+        })
+        .collect();
+    inputs.sort();
+    inputs
+        .into_iter()
+        .next()
+        .map(|(_, path)| path)
+        .ok_or_else(|| Error::FeatureNotFound(format!("{}/{prefix}*_input", path.display())))
+}
+
+fn read_milli(input: &Result<PathBuf, Error>) -> Result<f64, Error> {
+    let input = input.as_ref().map_err(Error::clone)?;
+    let string = fs::read_to_string(input)
+        .map_err(|err| Error::Read(format!("{}: {err}", input.display())))?;
+    let val = f64::from_str(string.trim())
+        .map_err(|err| Error::Read(format!("{}: {err}", input.display())))?;
+    Ok(val / 1000.0)
+}
+
+impl Chip {
+    pub fn open(name: String, path: PathBuf) -> Chip {
+        Chip {
+            name,
+            in_input: find_input(&path, "in"),
+            curr_input: find_input(&path, "curr"),
+            path,
+        }
+    }
+
+    // Volts.
+    pub fn voltage(&self) -> Result<f64, Error> {
+        read_milli(&self.in_input)
+    }
+
+    // Amps.
+    pub fn current(&self) -> Result<f64, Error> {
+        read_milli(&self.curr_input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::fixture_dir;
+
+    #[test]
+    fn finds_chip() {
+        // The fixture's hwmon dir, next to power_supply without a name.
+        let dir = fixture_dir("steamdeck-charging");
+        let chip = find_chip_in(&dir, &["jupiter", "steamdeck_*"]).unwrap();
+        assert_eq!(chip.name, "steamdeck_hwmon");
+        assert_eq!(chip.path, dir.join("hwmon"));
+        assert_eq!(chip.voltage().unwrap(), 15.0);
+        assert_eq!(chip.current().unwrap(), 3.0);
+
+        assert!(matches!(
+            find_chip_in(&dir, &["jupiter"]),
+            Err(Error::ChipNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn finds_lowest_input() {
+        let dir = fixture_dir("steamdeck-charging").join("hwmon");
+        assert_eq!(find_input(&dir, "in").unwrap(), dir.join("in0_input"));
+        assert!(matches!(
+            find_input(&dir, "temp"),
+            Err(Error::FeatureNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,66 @@
+use crate::config::Config;
+use crate::{logind, report_once};
+use log::warn;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+fn pid_alive(pid: u32) -> bool {
+    Path::new(&format!("/proc/{pid}")).exists()
+}
+
+fn pidfile_blocker(pidfile: &str) -> Option<String> {
+    let pid = u32::from_str(fs::read_to_string(pidfile).ok()?.trim()).ok()?;
+    pid_alive(pid).then(|| format!("{pidfile} (pid {pid})"))
+}
+
+fn process_blocker(name: &str) -> Option<String> {
+    fs::read_dir("/proc").ok()?.flatten().find_map(|entry| {
+        let pid = u32::from_str(entry.file_name().to_str()?).ok()?;
+        let comm = fs::read_to_string(entry.path().join("comm")).ok()?;
+        (comm.trim() == name).then(|| format!("{name} (pid {pid})"))
+    })
+}
+
+// The first reason not to shut down right now, if any.
+fn find_blocker(config: &Config) -> Option<String> {
+    if let Some(blockers) = report_once("logind inhibitors", logind::shutdown_blockers()) {
+        if let Some(blocker) = blockers.into_iter().next() {
+            return Some(blocker);
+        }
+    }
+    config
+        .shutdown_inhibit_pidfiles
+        .iter()
+        .find_map(|pidfile| pidfile_blocker(pidfile))
+        .or_else(|| {
+            config
+                .shutdown_inhibit_processes
+                .iter()
+                .find_map(|name| process_blocker(name))
+        })
+}
+
+// Whether to hold off the shutdown for something blocking it, for up
+// to max_shutdown_inhibit_secs since the first time: an interrupted
+// update is bad, but so is the battery dying in the middle of it.
+// inhibited keeps since when, and the last blocker, across calls.
+pub fn holds_off(config: &Config, inhibited: &mut Option<(Instant, String)>) -> bool {
+    let Some(blocker) = find_blocker(config) else {
+        return false;
+    };
+    let max = Duration::from_secs_f64(config.max_shutdown_inhibit_secs.max(0.0));
+    let since = inhibited
+        .as_ref()
+        .map_or_else(Instant::now, |(since, _)| *since);
+    if since.elapsed() >= max {
+        warn!("Shutdown still inhibited by {blocker}, not waiting any longer.");
+        return false;
+    }
+    if inhibited.as_ref().map(|(_, last)| last) != Some(&blocker) {
+        warn!("Shutdown inhibited by {blocker}, delaying.");
+    }
+    *inhibited = Some((since, blocker));
+    true
+}
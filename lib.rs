@@ -0,0 +1,254 @@
+//! The daemon's logic, driven by the vpower binary: PowerMonitor reads
+//! the power supplies, derives the published values from them and acts
+//! on them, in a loop.
+//!
+//! The reading and derivation steps are usable on their own, for tools
+//! that want the same corrected values without running the daemon:
+//!
+//! ```no_run
+//! use std::time::Instant;
+//! use vpower::battery::{aggregate, find_batteries};
+//! use vpower::config::Config;
+//! use vpower::power_state::{Inputs, PowerState};
+//! use vpower::{ac, charger};
+//!
+//! let config = Config::default();
+//! let batteries = find_batteries(&config);
+//! let adapters = ac::read(&ac::adapter_paths());
+//! let mut state = PowerState::new(config.power_smoothing_samples);
+//!
+//! let readings: Vec<_> = batteries.iter().map(|battery| battery.read()).collect();
+//! let reading = aggregate(&readings);
+//! let outputs = state.compute(
+//!     &config,
+//!     &Inputs {
+//!         reading: &reading,
+//!         pdcs: None,
+//!         pdvl: None,
+//!         pdam: None,
+//!         ac_online: ac::online(&adapters),
+//!         charge_limit_percent: 100.0,
+//!         now: Instant::now(),
+//!     },
+//! );
+//! let connected = outputs
+//!     .ac_status
+//!     .is_some_and(|status| status.starts_with("Connected"));
+//! let charger = charger::identify(connected);
+//! println!("{:?}% {:?}", outputs.battery_percent, charger.model);
+//! ```
+
+pub mod ac;
+mod action;
+pub mod battery;
+mod brownout;
+pub mod calibration;
+mod charge_limit;
+mod charge_stall;
+pub mod charger;
+pub mod check;
+mod codes;
+pub mod config;
+mod config_watch;
+mod dbus;
+mod derived;
+mod drain_report;
+pub mod error;
+mod events;
+#[cfg(test)]
+mod fixtures;
+mod hardening;
+mod heartbeat;
+mod history;
+mod hooks;
+mod hwmon;
+mod inhibit;
+#[cfg(feature = "steamdeck-sensors")]
+mod libsensors;
+pub mod logging;
+mod logind;
+mod metrics;
+pub mod monitor;
+mod mqtt;
+mod notifications;
+mod notify;
+pub mod oneshot;
+mod osd;
+mod overrides;
+pub mod pd;
+mod pd_history;
+mod pending_shutdown;
+mod peripherals;
+mod platform_info;
+mod platform_profile;
+#[cfg(feature = "lua")]
+mod policy;
+mod power_saving;
+pub mod power_state;
+mod pre_shutdown;
+mod prediction;
+mod privileges;
+mod publisher;
+mod quirks;
+mod sample;
+mod schema;
+pub mod sensors;
+mod shutdown_record;
+mod signals;
+mod smoothing;
+pub mod snapshot;
+mod socket;
+mod stats;
+mod suspend;
+mod telemetry;
+mod typec;
+mod uevent;
+mod units;
+mod wake_alarm;
+
+pub use self::error::{Error, Result};
+pub use self::monitor::{Options, PowerMonitor};
+
+use lazy_static::lazy_static;
+use log::error;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+
+lazy_static! {
+    static ref failed: Mutex<HashSet<String>> = Default::default();
+}
+
+// Where /sys is, from --sysfs-root, to run against a copy.
+static SYSFS_ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Read /sys from dir instead, like a copy of another device's, from
+/// then on. Only the first call has an effect.
+pub fn set_sysfs_root(dir: &str) {
+    let _ = SYSFS_ROOT.set(PathBuf::from(dir));
+}
+
+// Whether /sys is elsewhere, which libsensors can't follow.
+#[cfg(feature = "steamdeck-sensors")]
+fn sysfs_root_set() -> bool {
+    SYSFS_ROOT.get().is_some()
+}
+
+// path, which starts with /sys, under the sysfs root.
+fn sysfs(path: &str) -> PathBuf {
+    match SYSFS_ROOT.get() {
+        Some(root) => root.join(path.strip_prefix("/sys/").unwrap_or(path)),
+        None => PathBuf::from(path),
+    }
+}
+
+fn read_battery_string(path_bat: &Path, var_name: &str) -> Option<String> {
+    let path = format!("{}/{var_name}", path_bat.display());
+    match fs::read_to_string(&path) {
+        Err(err) => {
+            if !failed.lock().unwrap().contains(&path) {
+                error!("read {path}: {err}");
+                failed.lock().unwrap().insert(path);
+            }
+            None
+        }
+        Ok(string) => Some(string.trim().to_owned()),
+    }
+}
+
+fn read_battery_f64(path_bat: &Path, var_name: &str) -> Option<f64> {
+    let path = format!("{}/{var_name}", path_bat.display());
+    match fs::read_to_string(&path) {
+        Err(err) => {
+            if !failed.lock().unwrap().contains(&path) {
+                error!("read {path}: {err}");
+                failed.lock().unwrap().insert(path);
+            }
+            None
+        }
+        Ok(string) => match f64::from_str(string.trim()) {
+            Err(err) => {
+                error!("read {path}: {err}");
+                None
+            }
+            Ok(val) => {
+                if !val.is_finite() {
+                    error!("read {path}: {val} is not finite");
+                    None
+                } else {
+                    Some(val)
+                }
+            }
+        },
+    }
+}
+
+// Log errors only the first time they happen, like for sysfs reads.
+fn report_once<T, E: Display>(what: &str, result: std::result::Result<T, E>) -> Option<T> {
+    match result {
+        Ok(val) => Some(val),
+        Err(err) => {
+            let msg = format!("{what}: {err}");
+            if !failed.lock().unwrap().contains(&msg) {
+                error!("{msg}");
+                failed.lock().unwrap().insert(msg);
+            }
+            None
+        }
+    }
+}
+
+fn write_str(dir_path: &str, var_name: &str, val: Option<&str>) {
+    let val = match val {
+        Some(val) => val,
+        None => return,
+    };
+
+    write_file(dir_path, var_name, val);
+    for legacy_name in schema::legacy_names(var_name) {
+        write_file(dir_path, legacy_name, val);
+    }
+}
+
+fn write_file(dir_path: &str, var_name: &str, val: &str) {
+    if let Err(err) = fs::create_dir(dir_path) {
+        if err.kind() != io::ErrorKind::AlreadyExists {
+            error!("mkdir {dir_path}: {err}");
+            return;
+        }
+    }
+
+    // Write to a temporary path first.
+    let dot_path = format!("{dir_path}/.{var_name}");
+    if let Err(err) = fs::write(&dot_path, format!("{val}\n")) {
+        error!("write {dot_path}: {err}");
+        return;
+    }
+
+    // Then move into place for atomicity.
+    let final_path = format!("{dir_path}/{var_name}");
+    if let Err(err) = fs::rename(&dot_path, &final_path) {
+        error!("rename {dot_path} -> {final_path}: {err}");
+    }
+}
+
+fn write_f64(dir_path: &str, var_name: &str, val: Option<f64>) {
+    if let Some(val) = val {
+        write_str(dir_path, var_name, Some(&val.to_string()))
+    }
+}
+
+fn remove_output(dir_path: &str, var_name: &str) {
+    for name in std::iter::once(var_name).chain(schema::legacy_names(var_name)) {
+        let path = format!("{dir_path}/{name}");
+        if let Err(err) = fs::remove_file(&path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                error!("remove {path}: {err}");
+            }
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use crate::sensors::Error;
+use libc::*;
+use std::ffi::CStr;
+use std::mem::{self, MaybeUninit};
+use std::path::PathBuf;
+use std::ptr;
+
+#[repr(C)]
+struct sensors_bus_id {
+    ty: c_short,
+    nr: c_short,
+}
+
+#[repr(C)]
+struct sensors_chip_name {
+    prefix: *mut c_char,
+    bus: sensors_bus_id,
+    addr: c_int,
+    path: *mut c_char,
+}
+
+#[repr(C)]
+struct sensors_feature {
+    name: *mut c_char,
+    number: c_int,
+    ty: c_int,
+    first_subfeature: c_int,
+    padding1: c_int,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct sensors_subfeature {
+    name: *mut c_char,
+    number: c_int,
+    ty: c_int,
+    mapping: c_int,
+    flags: c_uint,
+}
+
+type InitFn = unsafe extern "C" fn(input: *mut FILE) -> c_int;
+type CleanupFn = unsafe extern "C" fn();
+type GetDetectedChipsFn =
+    unsafe extern "C" fn(mat: *const sensors_chip_name, nr: *mut c_int) -> *const sensors_chip_name;
+type GetFeaturesFn =
+    unsafe extern "C" fn(name: *const sensors_chip_name, nr: *mut c_int) -> *const sensors_feature;
+type GetSubfeatureFn = unsafe extern "C" fn(
+    name: *const sensors_chip_name,
+    feature: *const sensors_feature,
+    ty: c_int,
+) -> *const sensors_subfeature;
+type GetValueFn = unsafe extern "C" fn(
+    name: *const sensors_chip_name,
+    subfeat_nr: c_int,
+    value: *mut c_double,
+) -> c_int;
+
+// libsensors is loaded at runtime, so that vpower still starts (and
+// falls back to reading hwmon directly) on systems without it.
+const SONAMES: [&CStr; 2] = [c"libsensors.so.5", c"libsensors.so"];
+
+struct Api {
+    handle: *mut c_void,
+    sensors_init: InitFn,
+    sensors_cleanup: CleanupFn,
+    sensors_get_detected_chips: GetDetectedChipsFn,
+    sensors_get_features: GetFeaturesFn,
+    sensors_get_subfeature: GetSubfeatureFn,
+    sensors_get_value: GetValueFn,
+}
+
+fn dlerror_string() -> String {
+    let err = unsafe { dlerror() };
+    if err.is_null() {
+        return "unknown error".to_string();
+    }
+    unsafe { CStr::from_ptr(err) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+unsafe fn symbol<T>(handle: *mut c_void, name: &CStr) -> Result<T, Error> {
+    let sym = dlsym(handle, name.as_ptr());
+    if sym.is_null() {
+        return Err(Error::Load(dlerror_string()));
+    }
+    Ok(mem::transmute_copy(&sym))
+}
+
+impl Api {
+    fn load() -> Result<Api, Error> {
+        let mut handle = ptr::null_mut();
+        for soname in SONAMES {
+            handle = unsafe { dlopen(soname.as_ptr(), RTLD_NOW | RTLD_LOCAL) };
+            if !handle.is_null() {
+                break;
+            }
+        }
+        if handle.is_null() {
+            return Err(Error::Load(dlerror_string()));
+        }
+
+        let api = unsafe {
+            (|| {
+                Ok(Api {
+                    handle,
+                    sensors_init: symbol(handle, c"sensors_init")?,
+                    sensors_cleanup: symbol(handle, c"sensors_cleanup")?,
+                    sensors_get_detected_chips: symbol(handle, c"sensors_get_detected_chips")?,
+                    sensors_get_features: symbol(handle, c"sensors_get_features")?,
+                    sensors_get_subfeature: symbol(handle, c"sensors_get_subfeature")?,
+                    sensors_get_value: symbol(handle, c"sensors_get_value")?,
+                })
+            })()
+        };
+        if api.is_err() {
+            unsafe { dlclose(handle) };
+        }
+        api
+    }
+}
+
+pub const SENSORS_FEATURE_IN: c_int = 0x00;
+pub const SENSORS_FEATURE_CURR: c_int = 0x05;
+
+pub const SENSORS_SUBFEATURE_IN_INPUT: c_int = SENSORS_FEATURE_IN << 8;
+pub const SENSORS_SUBFEATURE_CURR_INPUT: c_int = SENSORS_FEATURE_CURR << 8;
+
+// Keeps libsensors initialized for as long as it's alive.
+pub struct Library {
+    api: Api,
+}
+
+impl Library {
+    pub fn init() -> Result<Library, Error> {
+        let api = Api::load()?;
+        let err = unsafe { (api.sensors_init)(ptr::null_mut()) };
+        if err != 0 {
+            unsafe { dlclose(api.handle) };
+            return Err(Error::Init(err));
+        }
+        Ok(Library { api })
+    }
+
+    pub fn chips(&self) -> Vec<Chip<'_>> {
+        let mut chips = Vec::new();
+        let mut nr = 0;
+        loop {
+            let chip = unsafe { (self.api.sensors_get_detected_chips)(ptr::null(), &mut nr) };
+            if chip.is_null() {
+                return chips;
+            }
+            chips.push(Chip {
+                library: self,
+                raw: unsafe { &*chip },
+            });
+        }
+    }
+
+    pub fn value(&self, chip: &ChipHandle, subfeature_num: c_int) -> Result<f64, Error> {
+        let mut val = MaybeUninit::uninit();
+        let err =
+            unsafe { (self.api.sensors_get_value)(&chip.raw, subfeature_num, val.as_mut_ptr()) };
+        if err != 0 {
+            return Err(Error::Value(err));
+        }
+        Ok(unsafe { val.assume_init() })
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe {
+            (self.api.sensors_cleanup)();
+            dlclose(self.api.handle);
+        }
+    }
+}
+
+// A detected chip, only valid while the Library is.
+#[derive(Clone, Copy)]
+pub struct Chip<'a> {
+    library: &'a Library,
+    raw: &'a sensors_chip_name,
+}
+
+impl Chip<'_> {
+    pub fn prefix(&self) -> String {
+        unsafe { CStr::from_ptr(self.raw.prefix) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(
+            unsafe { CStr::from_ptr(self.raw.path) }
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+
+    fn features(&self) -> Vec<Feature> {
+        let mut features = Vec::new();
+        let mut nr = 0;
+        loop {
+            let feature = unsafe { (self.library.api.sensors_get_features)(self.raw, &mut nr) };
+            if feature.is_null() {
+                return features;
+            }
+
+            // Only the input subfeatures are of interest.
+            let ty = unsafe { (*feature).ty };
+            let subfeature =
+                unsafe { (self.library.api.sensors_get_subfeature)(self.raw, feature, ty << 8) };
+            let mut subfeatures = Vec::new();
+            if !subfeature.is_null() {
+                let subfeature = unsafe { &*subfeature };
+                subfeatures.push((subfeature.ty, subfeature.number));
+            }
+            features.push(Feature { ty, subfeatures });
+        }
+    }
+
+    // Take a copy of everything needed to read values later on.
+    pub fn to_handle(self) -> ChipHandle {
+        ChipHandle {
+            raw: sensors_chip_name {
+                prefix: self.raw.prefix,
+                bus: sensors_bus_id {
+                    ty: self.raw.bus.ty,
+                    nr: self.raw.bus.nr,
+                },
+                addr: self.raw.addr,
+                path: self.raw.path,
+            },
+            prefix: self.prefix(),
+            path: self.path(),
+            features: self.features(),
+        }
+    }
+}
+
+pub struct Feature {
+    pub ty: c_int,
+    pub subfeatures: Vec<(c_int, c_int)>, // (type, number)
+}
+
+// Find the number of the first subfeature of the given types.
+pub fn find_subfeature(
+    features: &[Feature],
+    feature_ty: c_int,
+    subfeature_ty: c_int,
+) -> Result<c_int, Error> {
+    features
+        .iter()
+        .filter(|feature| feature.ty == feature_ty)
+        .flat_map(|feature| feature.subfeatures.iter())
+        .find(|(ty, _)| *ty == subfeature_ty)
+        .map(|(_, number)| *number)
+        .ok_or_else(|| {
+            Error::FeatureNotFound(format!(
+                "subfeature {subfeature_ty:#x} of feature {feature_ty:#x}"
+            ))
+        })
+}
+
+// An owned description of a chip, usable with the Library it came from.
+pub struct ChipHandle {
+    raw: sensors_chip_name,
+    pub prefix: String,
+    pub path: PathBuf,
+    pub features: Vec<Feature>,
+}
@@ -0,0 +1,175 @@
+use log::kv::{self, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::env;
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+// MESSAGE_ID values for the events worth filtering on, as in
+// journalctl MESSAGE_ID=...
+pub const MESSAGE_STARTED: &str = "226657fa564f465e8af4e5be2dc8f9da";
+pub const MESSAGE_RELOADED: &str = "559a32563ae14520b71533582d039a49";
+pub const MESSAGE_BATTERY_LOW: &str = "fbfd6d89d6b2401d8ba46478cb2f9dd2";
+pub const MESSAGE_BATTERY_OVERHEAT: &str = "d93c4072cefe40bfae93a1e63b6a0777";
+pub const MESSAGE_BATTERY_CRITICAL: &str = "f36cb0808f7843d89a2c534354ca9e1e";
+pub const MESSAGE_SHUTDOWN: &str = "ee2a83ec45624a4f96ddf02c64bdb42b";
+pub const MESSAGE_STOPPED: &str = "79beac76de004580bddbe8dc7a832def";
+
+// The level from the configuration or RUST_LOG, and whether debug
+// messages were turned on at runtime on top of it.
+static LEVEL: Mutex<LevelFilter> = Mutex::new(LevelFilter::Info);
+// From --verbose or --quiet, which take precedence over both.
+static COMMAND_LINE_LEVEL: Mutex<Option<LevelFilter>> = Mutex::new(None);
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+// Logs to the journal when started by systemd, and to stderr otherwise
+// or if the journal can't be reached.
+struct Logger {
+    journal: Option<UnixDatagram>,
+}
+
+// Collects key-values as journal fields, like message_id=... becoming
+// MESSAGE_ID=...
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0
+            .push((key.as_str().to_uppercase(), value.to_string()));
+        Ok(())
+    }
+}
+
+// Syslog priorities, as expected in PRIORITY.
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// Native journal protocol: KEY=value lines, or KEY, a little endian
+// length and the raw value for values spanning several lines.
+fn append_field(buf: &mut Vec<u8>, key: &str, val: &str) {
+    buf.extend_from_slice(key.as_bytes());
+    if val.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(val.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(val.as_bytes());
+    buf.push(b'\n');
+}
+
+impl Logger {
+    fn send_journal(&self, journal: &UnixDatagram, record: &Record) -> io::Result<()> {
+        let mut buf = Vec::new();
+        append_field(&mut buf, "MESSAGE", &record.args().to_string());
+        append_field(&mut buf, "PRIORITY", &priority(record.level()).to_string());
+        append_field(&mut buf, "SYSLOG_IDENTIFIER", "vpower");
+        if let Some(file) = record.file() {
+            append_field(&mut buf, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            append_field(&mut buf, "CODE_LINE", &line.to_string());
+        }
+        let mut fields = Fields(Vec::new());
+        let _ = record.key_values().visit(&mut fields);
+        for (key, val) in &fields.0 {
+            append_field(&mut buf, key, val);
+        }
+        journal.send_to(&buf, JOURNAL_SOCKET)?;
+        Ok(())
+    }
+
+    fn write_stderr(&self, record: &Record) {
+        let prefix = match record.level() {
+            Level::Error => "Error: ",
+            Level::Warn => "Warning: ",
+            Level::Info => "",
+            Level::Debug => "Debug: ",
+            Level::Trace => "Trace: ",
+        };
+        let _ = writeln!(io::stderr(), "{prefix}{}", record.args());
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match &self.journal {
+            Some(journal) if self.send_journal(journal, record).is_ok() => {}
+            _ => self.write_stderr(record),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Set up logging at the default level, until the configuration is
+// read. In the foreground, always log to stderr.
+pub fn init(foreground: bool) {
+    // systemd sets JOURNAL_STREAM when stderr goes to the journal.
+    let journal = env::var_os("JOURNAL_STREAM")
+        .filter(|_| !foreground)
+        .and_then(|_| UnixDatagram::unbound().ok());
+    if log::set_boxed_logger(Box::new(Logger { journal })).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+// Use level whatever the configuration or RUST_LOG say.
+pub fn set_command_line_level(level: LevelFilter) {
+    *COMMAND_LINE_LEVEL.lock().unwrap() = Some(level);
+    *LEVEL.lock().unwrap() = level;
+    apply_level();
+}
+
+// Use the configured level, unless overridden by RUST_LOG or the
+// command line.
+pub fn set_level(config_level: &str) {
+    if COMMAND_LINE_LEVEL.lock().unwrap().is_some() {
+        return;
+    }
+    let level = match env::var("RUST_LOG") {
+        Ok(env_level) => LevelFilter::from_str(&env_level).map_err(|_| ("RUST_LOG", env_level)),
+        Err(_) => {
+            LevelFilter::from_str(config_level).map_err(|_| ("log_level", config_level.to_string()))
+        }
+    };
+    match level {
+        Ok(level) => *LEVEL.lock().unwrap() = level,
+        Err((what, val)) => {
+            let level = *LEVEL.lock().unwrap();
+            log::warn!("{what}: unknown level {val}, using {level}");
+        }
+    }
+    apply_level();
+}
+
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::SeqCst);
+    apply_level();
+}
+
+fn apply_level() {
+    let level = *LEVEL.lock().unwrap();
+    if VERBOSE.load(Ordering::SeqCst) {
+        log::set_max_level(level.max(LevelFilter::Debug));
+    } else {
+        log::set_max_level(level);
+    }
+}
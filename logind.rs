@@ -0,0 +1,47 @@
+use crate::action::CriticalAction;
+use zbus::{blocking::Connection, proxy, Result};
+
+// (what, who, why, mode, uid, pid)
+type Inhibitor = (String, String, String, String, u32, u32);
+
+#[proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait Manager {
+    fn list_inhibitors(&self) -> Result<Vec<Inhibitor>>;
+    fn power_off(&self, interactive: bool) -> Result<()>;
+    fn hibernate(&self, interactive: bool) -> Result<()>;
+    fn hybrid_sleep(&self, interactive: bool) -> Result<()>;
+    fn suspend(&self, interactive: bool) -> Result<()>;
+}
+
+// Who is blocking shutdown and why, as "who (why)". Delay locks only
+// hold shutdown back for a few seconds, so they don't count.
+pub fn shutdown_blockers() -> Result<Vec<String>> {
+    let connection = Connection::system()?;
+    let manager = ManagerProxyBlocking::new(&connection)?;
+    let blockers = manager
+        .list_inhibitors()?
+        .into_iter()
+        .filter(|(what, _, _, mode, _, _)| {
+            mode == "block" && what.split(':').any(|what| what == "shutdown")
+        })
+        .map(|(_, who, why, _, _, _)| format!("{who} ({why})"))
+        .collect();
+    Ok(blockers)
+}
+
+// Ask logind to run action, which polkit allows without root. Returns
+// once the request is accepted, like systemctl.
+pub fn run(action: CriticalAction) -> Result<()> {
+    let connection = Connection::system()?;
+    let manager = ManagerProxyBlocking::new(&connection)?;
+    match action {
+        CriticalAction::Poweroff => manager.power_off(false),
+        CriticalAction::Hibernate => manager.hibernate(false),
+        CriticalAction::HybridSleep => manager.hybrid_sleep(false),
+        CriticalAction::Suspend => manager.suspend(false),
+    }
+}
@@ -0,0 +1,125 @@
+use crate::snapshot::Snapshot;
+use log::error;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const AC_STATUSES: [&str; 3] = ["Connected", "Connected slow", "Disconnected"];
+
+// Serves the latest values in the Prometheus text format on
+// GET /metrics.
+pub struct MetricsServer {
+    body: Arc<Mutex<String>>,
+}
+
+impl MetricsServer {
+    pub fn new(addr: &str) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(addr)?;
+        let body: Arc<Mutex<String>> = Default::default();
+        let server = MetricsServer { body: body.clone() };
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Err(err) => error!("metrics accept: {err}"),
+                    Ok(stream) => {
+                        let body = body.clone();
+                        thread::spawn(move || {
+                            if let Err(err) = handle_client(stream, &body) {
+                                error!("metrics: {err}");
+                            }
+                        });
+                    }
+                }
+            }
+        });
+
+        Ok(server)
+    }
+
+    // loop_duration is how long the last iteration took to read and
+    // publish everything.
+    pub fn publish(&self, snapshot: &Snapshot, loop_duration: Duration) {
+        let mut body = String::new();
+        gauge(
+            &mut body,
+            "battery_percent",
+            "Battery charge.",
+            snapshot.battery_percent,
+        );
+        gauge(
+            &mut body,
+            "power_draw_watts",
+            "Power drawn from or charged into the battery.",
+            snapshot.power_draw_watts,
+        );
+        gauge(
+            &mut body,
+            "battery_temp_celsius",
+            "Battery temperature.",
+            snapshot.battery_temp_celsius,
+        );
+        gauge(
+            &mut body,
+            "loop_duration_seconds",
+            "Time spent in the last iteration.",
+            Some(loop_duration.as_secs_f64()),
+        );
+        if let Some(ac_status) = &snapshot.ac_status {
+            header(&mut body, "ac_status", "Power adapter status.");
+            for status in AC_STATUSES {
+                let val = u8::from(status == ac_status);
+                let _ = writeln!(body, "vpower_ac_status{{status=\"{status}\"}} {val}");
+            }
+        }
+        *self.body.lock().unwrap() = body;
+    }
+}
+
+fn header(body: &mut String, name: &str, help: &str) {
+    let _ = writeln!(body, "# HELP vpower_{name} {help}");
+    let _ = writeln!(body, "# TYPE vpower_{name} gauge");
+}
+
+fn gauge(body: &mut String, name: &str, help: &str, val: Option<f64>) {
+    if let Some(val) = val {
+        header(body, name, help);
+        let _ = writeln!(body, "vpower_{name} {val}");
+    }
+}
+
+fn handle_client(stream: TcpStream, body: &Mutex<String>) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, nothing in them matters.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut stream = stream;
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = body.lock().unwrap().clone();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        _ => write!(
+            stream,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        ),
+    }
+}
@@ -0,0 +1,1480 @@
+use crate::action::CriticalAction;
+use crate::battery::{find_batteries, Battery, Direction};
+use crate::brownout::SagDetector;
+use crate::charge_stall::ChargeStall;
+use crate::config::{Config, CONFIG_PATH};
+use crate::dbus::DbusService;
+use crate::error::{Error, Result};
+use crate::heartbeat::Heartbeat;
+use crate::events::EventLoop;
+use crate::history::HISTORY_DIR;
+use crate::hooks::{self, Event};
+use crate::logging::{
+    self, MESSAGE_BATTERY_CRITICAL, MESSAGE_BATTERY_LOW, MESSAGE_BATTERY_OVERHEAT, MESSAGE_RELOADED,
+    MESSAGE_SHUTDOWN, MESSAGE_STARTED, MESSAGE_STOPPED,
+};
+use crate::metrics::MetricsServer;
+use crate::notifications::{self, Urgency};
+use crate::osd::{self, Severity};
+use crate::overrides::{Overrides, OVERRIDE_PATH};
+use crate::pd_history::PdHistory;
+use crate::pending_shutdown::{Conditions, PendingShutdown, Postpone, Step, SLEEP_TIMEOUT};
+use crate::platform_profile::{self, ProfileSwitcher};
+#[cfg(feature = "lua")]
+use crate::policy::Policy;
+use crate::power_saving::PowerSaving;
+use crate::power_state::{Outputs, PowerState};
+use crate::publisher::{Publisher, Sinks};
+use crate::sample::Sample;
+use crate::sensors::Sensors;
+use crate::snapshot::Snapshot;
+use crate::socket::{Request, SocketServer};
+use crate::stats::SessionTracker;
+use crate::suspend::SuspendDetector;
+use crate::uevent::UeventMonitor;
+use crate::wake_alarm::WakeAlarm;
+use crate::{
+    ac, battery, calibration, charge_limit, charger, codes, config_watch, derived, drain_report,
+    hardening, inhibit, logind, notify, peripherals, platform_info, pre_shutdown, privileges,
+    quirks, remove_output, report_once, schema, shutdown_record, signals, sysfs, write_f64,
+    write_str,
+};
+use crate::failed;
+use log::{debug, error, info, warn};
+use std::any::Any;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::ops::ControlFlow;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How long to wait for a battery to show up at startup.
+const BATTERY_WAIT_SECS: u32 = 30;
+
+// How long to keep polling fast after the charger was plugged in or
+// out, for the PD contract to settle.
+const FAST_POLL_AFTER_AC_CHANGE: Duration = Duration::from_secs(5);
+
+// Under output_dir, for the [derived] values.
+const DERIVED_DIR: &str = "derived";
+
+pub(crate) fn read_battery_maxchargelevel(path: &str) -> Option<f64> {
+    // retry 3 times, as there seems to be a strange bug in which some
+    // /sys files sometimes disappear, so not adding to the problem by
+    // also failing and adding noise to the logs
+    for _i in 1..3 {
+	let bat_maxchargelevel_from_file = fs::read_to_string(path).unwrap_or("-1.0".to_string());
+	let bat_maxchargelevel = i32::from_str(bat_maxchargelevel_from_file.trim()).unwrap_or(-1);
+
+	if bat_maxchargelevel == 0 {
+	    // limit is disabled, returning 100% instead
+	    return Some(100.0);
+	}
+	else if bat_maxchargelevel > 0 {
+	    // success, returning supposedly good value
+	    return Some(bat_maxchargelevel as f64);
+	}
+	else {
+	    // problem, sleep and retry
+	    thread::sleep(Duration::from_millis(333));
+	}
+    }
+
+    // default
+    if !failed.lock().unwrap().contains(path) {
+	error!("read '{path}': could not read from file 3 times in a row");
+        failed.lock().unwrap().insert(path.to_string());
+    }
+    None
+}
+
+// The file with the battery's MaxChargeLevel, or an empty path if
+// there is none.
+pub(crate) fn find_maxchargelevel_file(path_bat: &Path) -> PathBuf {
+    // MaxChargeLevel files
+    let maxchargelevel_path_steamdeck = sysfs("/sys/devices/pci0000:00/0000:00:14.3/PNP0C09:00/VLV0100:00/steamdeck-hwmon/hwmon/hwmon3/max_battery_charge_level");
+    let maxchargelevel_path_std = path_bat.display().to_string() + "/charge_control_end_threshold";
+    let maxchargelevel_filenames = vec![
+	// SteamDeck, LCD and OLED models
+	maxchargelevel_path_steamdeck.to_str().unwrap_or_default(),
+	// generic value supported by e.g. many consumer laptops
+	&maxchargelevel_path_std,
+    ];
+    for maxchargelevel_file in maxchargelevel_filenames.into_iter() {
+	let path_maxchargelevel_file = PathBuf::from(maxchargelevel_file);
+	if path_maxchargelevel_file.exists() {
+	    info!("using {} file for reading battery's MaxChargeLevel feature", path_maxchargelevel_file.display());
+	    return path_maxchargelevel_file;
+	}
+    }
+
+    // empty means that file was not found
+    PathBuf::from("")
+}
+
+// What a panic was about, from its payload.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+
+// How often to poll: fast near the shutdown threshold and right after
+// the charger changed, idle when full on AC.
+#[derive(Clone, Copy)]
+enum Pace {
+    Idle,
+    Normal,
+    Fast,
+}
+
+// Announce the last force_shutdown_timeout_secs before the action, as
+// osd_type.
+fn start_countdown(
+    config: &Config,
+    dir_path: &str,
+    action: CriticalAction,
+    battery_percent: Option<f64>,
+    osd_type: &str,
+) -> PendingShutdown {
+    let timeout = config.force_shutdown_timeout_secs;
+    warn!("Forcing {action} in {timeout} seconds.");
+    let body = format!("Connect a charger, forcing {action} in {timeout} seconds.");
+    notifications::send(config, Urgency::Critical, "Battery critical", &body);
+    osd::write(dir_path, osd_type, Severity::Critical, timeout, &body);
+    let timeout = Duration::from_secs_f64(timeout.max(0.0));
+    PendingShutdown::countdown(action, battery_percent, timeout, Instant::now())
+}
+
+// Start counting down to a forced shutdown, first giving a
+// shutdown_confirmation_secs warning, if set, like to finish saving.
+fn begin_shutdown(
+    config: &Config,
+    dir_path: &str,
+    action: CriticalAction,
+    battery_percent: Option<f64>,
+    snapshot: &Snapshot,
+) -> PendingShutdown {
+    let Some(window) = config.shutdown_confirmation_secs else {
+        return start_countdown(config, dir_path, action, battery_percent, "battery_critical");
+    };
+    warn!("Shutdown pending, {action} in {window} seconds unless postponed.");
+    config.hooks.fire(Event::ShutdownPending, snapshot);
+    let body = format!("Save your work or connect a charger, {action} in {window} seconds.");
+    notifications::send(config, Urgency::Critical, "Battery critical", &body);
+    osd::write(dir_path, "shutdown_pending", Severity::Critical, window, &body);
+    PendingShutdown::confirmation(action, battery_percent, Duration::from_secs_f64(window), Instant::now())
+}
+
+fn end_countdown(dir_path: &str) {
+    for name in ["shutdown_pending", "shutdown_postpones_left", "shutdown_countdown"] {
+        remove_output(dir_path, name);
+    }
+}
+
+// What running the critical action came to.
+enum Outcome {
+    DryRun,
+    // Requested, but the system only suspends a little later.
+    Sleeping,
+    PoweringOff,
+}
+
+fn run_critical_action(
+    options: &Options,
+    config: &Config,
+    dir_path: &str,
+    action: CriticalAction,
+    snapshot: &Snapshot,
+) -> Result<Outcome> {
+    if options.dry_run {
+        warn!("Dry run, not running {action}.");
+        return Ok(Outcome::DryRun);
+    }
+
+    remove_output(dir_path, "last_shutdown_report");
+    pre_shutdown::run(&config.pre_shutdown, &format!("{dir_path}/last_shutdown_report"));
+
+    warn!(message_id = MESSAGE_SHUTDOWN; "Running {action} now.");
+    if action.resumes() {
+        shutdown_record::write(action, snapshot);
+        match request_action(action, "systemctl", &[action.to_string()]) {
+            // The record stays until the resume, in case the battery
+            // runs out while hibernated.
+            Ok(()) => return Ok(Outcome::Sleeping),
+            Err(err) => error!("{err}, powering off instead"),
+        }
+    }
+
+    shutdown_record::write(CriticalAction::Poweroff, snapshot);
+    let result = match &config.shutdown_command {
+        Some(command) => run_command(command, &config.shutdown_args),
+        None => request_action(CriticalAction::Poweroff, "poweroff", &[]),
+    };
+    if result.is_err() {
+        shutdown_record::clear();
+    }
+    result.map(|()| Outcome::PoweringOff)
+}
+
+// Ask logind, which works without root and without the poweroff or
+// systemctl binaries around, like in containers, and run command
+// instead if that fails while still root.
+fn request_action(action: CriticalAction, command: &str, args: &[String]) -> Result<()> {
+    let err = match logind::run(action) {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    if !privileges::is_root() {
+        return Err(Error::Command {
+            command: format!("logind {action}"),
+            reason: err.to_string(),
+        });
+    }
+    warn!("logind {action}: {err}, running {command} instead");
+    run_command(command, args)
+}
+
+fn run_command(command: &str, args: &[String]) -> Result<()> {
+    match Command::new(command).args(args).status() {
+        Err(err) => Err(Error::Command {
+            command: command.to_string(),
+            reason: err.to_string(),
+        }),
+        Ok(status) if !status.success() => Err(Error::Command {
+            command: command.to_string(),
+            reason: status.to_string(),
+        }),
+        Ok(_) => Ok(()),
+    }
+}
+
+#[cfg(feature = "lua")]
+fn load_policy(config: &Config) -> Option<Policy> {
+    let path = config.policy_script.as_deref()?;
+    match Policy::load(path) {
+        Err(err) => {
+            error!("{path}: {err}");
+            None
+        }
+        Ok(policy) => {
+            info!("Loaded policy script {path}.");
+            Some(policy)
+        }
+    }
+}
+
+#[cfg(not(feature = "lua"))]
+fn load_policy(config: &Config) {
+    if config.policy_script.is_some() {
+        warn!("policy_script: vpower was built without the lua feature, ignoring it");
+    }
+}
+
+fn start_dbus_service() -> Option<DbusService> {
+    match DbusService::new() {
+        Err(err) => {
+            error!("dbus: {err}");
+            None
+        }
+        Ok(dbus_service) => Some(dbus_service),
+    }
+}
+
+// Whether the configuration and quirks can be read, so that a broken
+// edit doesn't replace a working configuration with the defaults.
+fn validate_config(config_path: &str) -> Result<()> {
+    if let Some(Err(message)) = Config::validate(config_path) {
+        return Err(Error::Config {
+            path: config_path.to_string(),
+            message,
+        });
+    }
+    quirks::validate()
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
+
+// Remove what vpower wrote to dir_path, but not the directory itself,
+// nor any directories but its own.
+fn clear_output_dir(dir_path: &str) {
+    let entries = match fs::read_dir(dir_path) {
+        Err(err) => {
+            error!("read {dir_path}: {err}");
+            return;
+        }
+        Ok(entries) => entries,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+        let result = if !is_dir {
+            fs::remove_file(&path)
+        } else if ["ac", DERIVED_DIR, "peripherals", "platform"].iter().any(|name| entry.file_name() == *name) {
+            fs::remove_dir_all(&path)
+        } else {
+            continue;
+        };
+        if let Err(err) = result {
+            error!("remove {}: {err}", path.display());
+        }
+    }
+}
+
+// Note why an iteration failed.
+fn record_failure(dir_path: &str, message: &str) {
+    error!("Iteration failed: {message}");
+    write_str(dir_path, "last_error", Some(&format!("{} {message}", timestamp())));
+}
+
+// What the command line says, which takes precedence over the
+// configuration when reloading it too.
+pub struct Options {
+    pub config_path: String,
+    pub output_dir: Option<String>,
+    // Change nothing on the system: no critical action, charge limit,
+    // platform profile, power saving or wake alarm.
+    pub dry_run: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            config_path: CONFIG_PATH.to_string(),
+            output_dir: None,
+            dry_run: false,
+        }
+    }
+}
+
+// Watches the batteries and chargers, and publishes and acts on what
+// it finds, until the system goes down.
+pub struct PowerMonitor {
+    config: Config,
+    options: Options,
+    batteries: Vec<Battery>,
+    path_maxchargelevel_file: PathBuf,
+    path_maxchargelevel_file_found: bool,
+    sensors: Sensors,
+    events: EventLoop,
+    heartbeat: Heartbeat,
+    dbus_service: Option<DbusService>,
+    // Fixed from the start, as is metrics_listen.
+    output_dir: String,
+    socket_server: Option<SocketServer>,
+    metrics_listen: Option<String>,
+    metrics_server: Option<MetricsServer>,
+    // History, MQTT and telemetry, in a thread of their own.
+    publisher: Publisher,
+    #[cfg(feature = "lua")]
+    policy: Option<Policy>,
+
+    // Keep for heuristics.
+    prev_ac_status: Option<&'static str>,
+    prev_battery_status: Option<&'static str>,
+    prev_low_battery_warning: bool,
+    prev_battery_overheat: bool,
+
+    // What ac_status, battery_status and the estimates are derived
+    // from, besides the current readings.
+    power_state: PowerState,
+
+    // Recent PD contract changes.
+    pd_history: PdHistory,
+
+    // Noticing resumes, which leave everything above stale.
+    suspend_detector: SuspendDetector,
+
+    // Switching to low_power_profile on low battery.
+    profile_switcher: ProfileSwitcher,
+
+    // And the other power saving actions.
+    power_saving: PowerSaving,
+
+    // How often to poll, and when the charger was last plugged in or
+    // out.
+    pace: Pace,
+    ac_changed_at: Option<Instant>,
+
+    // Since when the battery has been at 100% on AC, and whether the
+    // full_dwell reminder went out for that stretch.
+    full_since: Option<Instant>,
+    full_dwell_fired: bool,
+
+    // Statistics of the sessions on battery, published when they
+    // change.
+    sessions: SessionTracker,
+    stats_changed: bool,
+
+    // Waking up before the battery runs out while suspended.
+    wake_alarm: WakeAlarm,
+
+    // Whether the power draw could be read last time, to only log
+    // when that changes.
+    power_readable: bool,
+
+    // Correction from --calibrate, for charge_full.
+    capacity_factor: f64,
+
+    // Incremented for every status.json written.
+    sequence: u64,
+
+    last_bat_maxchargelevel: f64,
+
+    // Names of the peripherals in <output_dir>/peripherals.
+    published_peripherals: HashSet<String>,
+
+    // And of the AC adapters in <output_dir>/ac.
+    published_ac_adapters: HashSet<String>,
+
+    // The AC adapters found last time, to tell when they come and go.
+    ac_paths: Vec<PathBuf>,
+
+    // Whether reaching hibernate_battery_percent hibernates, which it
+    // only does once per discharge.
+    hibernate_armed: bool,
+
+    pending_shutdown: Option<PendingShutdown>,
+
+    // Values pinned in /etc/vpower/override.toml.
+    overrides: Overrides,
+
+    // From vpowerctl, which takes precedence over
+    // enable_forced_shutdown until restarted, and whether reaching
+    // the threshold without it was logged.
+    forced_shutdown: Option<bool>,
+    skipped_shutdown: bool,
+
+    // How many iterations in a row the voltage was below
+    // emergency_voltage_volts while discharging.
+    low_voltage_samples: u32,
+    sag_detector: SagDetector,
+    charge_stall: ChargeStall,
+}
+
+impl PowerMonitor {
+    // Find the hardware and start the services, failing with
+    // Error::NoBattery on systems without one.
+    pub fn new(config: Config, options: Options) -> Result<PowerMonitor> {
+        // Mains/AC, looked for again on every iteration as USB-C ports
+        // can come and go, and some only register a while after boot
+        let ac_paths = ac::adapter_paths();
+        for path_ac in &ac_paths {
+	    info!("Found AC power supply: '{}'", path_ac.display());
+        }
+        if ac_paths.is_empty() {
+	    warn!("Could not find device for AC/Mains yet, some functionality might be missing or not accurate until it shows up.");
+        }
+
+        // Find all batteries, giving them some time to show up
+        // when starting early at boot, otherwise it's a system without
+        // battery -- bail-out
+        let mut batteries = find_batteries(&config);
+        let mut secs_waited = 0;
+        while batteries.is_empty() {
+	    if secs_waited >= BATTERY_WAIT_SECS {
+		return Err(Error::NoBattery);
+	    }
+	    thread::sleep(Duration::from_secs(1));
+	    secs_waited += 1;
+	    batteries = find_batteries(&config);
+        }
+        if batteries.len() > 1 {
+	    info!("found {} batteries, reporting aggregated values", batteries.len());
+        }
+        let path_bat = batteries[0].path.clone();
+
+        let path_maxchargelevel_file = find_maxchargelevel_file(&path_bat);
+        let path_maxchargelevel_file_found = if path_maxchargelevel_file.display().to_string().is_empty() {
+	    warn!("cound not find suitable file for reading battery's MaxChargeLevel feature, assuming MaxChargeLevel=100%");
+	    false
+        }
+        else {
+	    true
+        };
+
+        // Values are written to output_dir, which is fixed from now on.
+        let output_dir = config.output_dir.clone();
+        if let Err(err) = fs::create_dir_all(&output_dir) {
+            error!("mkdir {output_dir}: {err}");
+        }
+
+        // Sandbox before starting any thread: landlock only applies to
+        // the calling thread and those it starts later.
+        if config.sandbox {
+            // And the directory telemetry rotates its file in.
+            let telemetry_dir = config
+                .telemetry
+                .as_ref()
+                .filter(|telemetry| telemetry.path != "-")
+                .and_then(|telemetry| Path::new(&telemetry.path).parent()?.to_str())
+                .filter(|dir| !dir.is_empty());
+            let writable: Vec<&str> = [output_dir.as_str(), HISTORY_DIR].into_iter().chain(telemetry_dir).collect();
+            hardening::apply(&writable);
+        }
+
+        // Sleep between iterations until something happens: uevents,
+        // signals and socket commands wake the main loop up.
+        let events = EventLoop::new();
+
+        // Re-read /etc/vpower.toml on SIGHUP, or when it or the quirks
+        // change.
+        signals::install(events.sender());
+        config_watch::start(&options.config_path, quirks::QUIRKS_DIR);
+
+        // Initialize libsensors, or read hwmon directly without it.
+        let sensors = Sensors::new(config.sensors_chip.as_deref(), config.charger_supply.as_deref());
+
+        // Listen for power_supply uevents to react to changes immediately.
+        let uevents = UeventMonitor::new();
+        if !uevents.is_available() {
+            warn!("could not listen for uevents, falling back to polling.");
+        }
+        uevents.start(events.sender());
+
+        // Publish values on the system bus too, if enabled.
+        let dbus_service = if config.dbus { start_dbus_service() } else { None };
+
+        schema::write_version(&output_dir);
+        write_str(&output_dir, "device_model", config.quirks.model.as_deref());
+        platform_info::publish(&output_dir, &sensors, &config.quirks);
+        shutdown_record::publish_previous(&output_dir);
+
+        // Serve the same values on a Unix socket.
+        let socket_path = format!("{output_dir}/vpower.sock");
+        let socket_server = match SocketServer::new(&socket_path, events.sender()) {
+            Err(err) => {
+                error!("{socket_path}: {err}");
+                None
+            }
+            Ok(socket_server) => Some(socket_server),
+        };
+
+        // And for Prometheus, if enabled.
+        let metrics_listen = config.metrics_listen.clone();
+        let metrics_server = metrics_listen.as_deref().and_then(|addr| match MetricsServer::new(addr) {
+            Err(err) => {
+                error!("metrics {addr}: {err}");
+                None
+            }
+            Ok(metrics_server) => Some(metrics_server),
+        });
+
+        // Left over if the previous instance was stopped mid-countdown.
+        remove_output(&output_dir, "shutdown_countdown");
+        remove_output(&output_dir, "daemon_stopped");
+
+        // Everything that needs root is open by now.
+        if let Some(user) = &config.user {
+            privileges::drop_to(user, &[&output_dir, HISTORY_DIR]).map_err(|err| Error::DropPrivileges {
+                user: user.clone(),
+                reason: err.to_string(),
+            })?;
+            info!("Running as {user} from now on.");
+        }
+
+        // Keep samples for later analysis, and publish them over MQTT
+        // and as telemetry, if enabled.
+        let publisher = Publisher::new(Sinks::new(&config));
+        #[cfg(feature = "lua")]
+        let policy = load_policy(&config);
+        #[cfg(not(feature = "lua"))]
+        load_policy(&config);
+
+        Ok(PowerMonitor {
+            power_state: PowerState::new(config.power_smoothing_samples),
+            config,
+            options,
+            batteries,
+            path_maxchargelevel_file,
+            path_maxchargelevel_file_found,
+            sensors,
+            events,
+            heartbeat: Heartbeat::new(),
+            dbus_service,
+            output_dir,
+            socket_server,
+            metrics_listen,
+            metrics_server,
+            publisher,
+            #[cfg(feature = "lua")]
+            policy,
+            prev_ac_status: None,
+            prev_battery_status: None,
+            prev_low_battery_warning: false,
+            prev_battery_overheat: false,
+            pd_history: PdHistory::default(),
+            suspend_detector: SuspendDetector::new(),
+            profile_switcher: ProfileSwitcher::default(),
+            power_saving: PowerSaving::default(),
+            pace: Pace::Normal,
+            ac_changed_at: None,
+            full_since: None,
+            full_dwell_fired: false,
+            sessions: SessionTracker::new(),
+            stats_changed: true,
+            wake_alarm: WakeAlarm::default(),
+            power_readable: true,
+            capacity_factor: calibration::capacity_factor(),
+            sequence: 0,
+            last_bat_maxchargelevel: -999.9,
+            published_peripherals: HashSet::new(),
+            published_ac_adapters: HashSet::new(),
+            ac_paths,
+            hibernate_armed: true,
+            pending_shutdown: None,
+            overrides: Overrides::default(),
+            forced_shutdown: None,
+            skipped_shutdown: false,
+            low_voltage_samples: 0,
+            sag_detector: SagDetector::default(),
+            charge_stall: ChargeStall::default(),
+        })
+    }
+
+    // Every poll interval, or whenever a power_supply uevent arrives,
+    // until the critical action powered off.
+    pub fn run(&mut self) {
+        info!(message_id = MESSAGE_STARTED; "Running.");
+        report_once("sd_notify", notify::notify("READY=1"));
+
+        loop {
+            if signals::stop_requested() {
+                self.stop();
+                return;
+            }
+            let loop_start = Instant::now();
+
+            // Some unexpected sysfs contents shouldn't stop the daemon:
+            // give up on this iteration, note why, and try again.
+            let iteration = panic::catch_unwind(AssertUnwindSafe(|| self.iterate(loop_start)));
+            match iteration {
+                // Powering off: save the history while there's time.
+                Ok(ControlFlow::Break(())) => {
+                    self.publisher.stop();
+                    return;
+                }
+                Ok(ControlFlow::Continue(())) => self.heartbeat.beat(&self.output_dir, self.poll_interval()),
+                Err(payload) => record_failure(&self.output_dir, &panic_message(&*payload)),
+            }
+
+            // Sleep until the next iteration, or until woken up.
+            let wakeups = self.events.wait(self.poll_interval());
+            if !wakeups.is_empty() {
+                debug!("Woken up by {wakeups:?}.");
+            }
+        }
+    }
+
+    // On SIGTERM or SIGINT, leave output_dir so that nobody takes what
+    // was last written there for current values, and save the
+    // discharge session so far.
+    fn stop(&mut self) {
+        info!(message_id = MESSAGE_STOPPED; "Stopping.");
+        report_once("sd_notify", notify::notify("STOPPING=1"));
+        self.sessions.end();
+        self.publisher.stop();
+        if self.config.remove_output_on_stop {
+            clear_output_dir(&self.output_dir);
+        } else {
+            write_str(&self.output_dir, "daemon_stopped", Some(&timestamp().to_string()));
+        }
+    }
+
+    // Counting down to shutdown needs to be more responsive.
+    fn poll_interval(&self) -> Duration {
+        let config = &self.config;
+        let mut poll_interval_secs = match self.pace {
+            Pace::Idle => config.idle_poll_interval_secs.max(config.poll_interval_secs),
+            Pace::Normal => config.poll_interval_secs,
+            Pace::Fast => config.fast_poll_interval_secs.min(config.poll_interval_secs),
+        };
+        if self.pending_shutdown.is_some() {
+            poll_interval_secs = poll_interval_secs.min(1.0);
+        }
+        // Pinging the watchdog in time, whatever the configuration.
+        let poll_interval = Duration::from_secs_f64(poll_interval_secs);
+        match notify::watchdog_interval() {
+            Some(watchdog_interval) => poll_interval.min(watchdog_interval),
+            None => poll_interval,
+        }
+    }
+
+    fn reload(&mut self) {
+        let options = &self.options;
+        info!(message_id = MESSAGE_RELOADED; "Reloading {}.", options.config_path);
+        report_once("sd_notify", notify::notify("RELOADING=1"));
+        let sensors_chip = self.config.sensors_chip.take();
+        let charger_supply = self.config.charger_supply.take();
+        let user = self.config.user.take();
+        self.config = Config::load(&options.config_path);
+        if let Some(output_dir) = &options.output_dir {
+            self.config.output_dir = output_dir.clone();
+        }
+        let config = &self.config;
+        logging::set_level(&config.log_level);
+        config.print();
+
+        if config.sensors_chip != sensors_chip {
+            self.sensors.select_chip(config.sensors_chip.as_deref());
+        }
+        if config.charger_supply != charger_supply {
+            self.sensors.select_charger_supply(config.charger_supply.as_deref());
+        }
+        platform_info::publish(&self.output_dir, &self.sensors, &config.quirks);
+
+        self.power_state.set_smoothing(config.power_smoothing_samples);
+
+        // Written again by the next iteration, without the values no
+        // longer in [derived].
+        if let Err(err) = fs::remove_dir_all(format!("{}/{DERIVED_DIR}", self.output_dir)) {
+            if err.kind() != io::ErrorKind::NotFound {
+                error!("remove {}/{DERIVED_DIR}: {err}", self.output_dir);
+            }
+        }
+
+        self.capacity_factor = calibration::capacity_factor();
+
+        self.publisher.configure(Sinks::new(config));
+        #[cfg(feature = "lua")]
+        {
+            self.policy = load_policy(config);
+        }
+        #[cfg(not(feature = "lua"))]
+        load_policy(config);
+        if config.metrics_listen != self.metrics_listen {
+            warn!("metrics_listen: changes take effect after a restart");
+        }
+        if config.output_dir != self.output_dir {
+            warn!("output_dir: changes take effect after a restart");
+        }
+        if config.user != user {
+            warn!("user: changes take effect after a restart");
+        }
+
+        if config.dbus && self.dbus_service.is_none() {
+            self.dbus_service = start_dbus_service();
+        } else if !config.dbus {
+            self.dbus_service = None;
+        }
+        report_once("sd_notify", notify::notify("READY=1"));
+    }
+
+    // Read everything once, publish the derived values and act on
+    // them. Breaks once the system is going down.
+    fn iterate(&mut self, loop_start: Instant) -> ControlFlow<()> {
+        // Start over after a resume.
+        if let Some(suspended) = self.suspend_detector.check() {
+            info!("Resumed after {:.0} seconds suspended.", suspended.as_secs_f64());
+            if let Some(pending) = self.pending_shutdown.take_if(|pending| pending.is_requested()) {
+                info!("Resumed from {}.", pending.action);
+                shutdown_record::clear();
+            }
+            self.power_state.reset();
+            self.sag_detector.reset();
+            self.charge_stall.reset();
+            self.wake_alarm.resumed(suspended);
+            write_str(&self.output_dir, "last_resume", Some(&timestamp().to_string()));
+        }
+
+        let reload = signals::reload_requested()
+            && match validate_config(&self.options.config_path) {
+                Ok(()) => true,
+                Err(err) => {
+                    error!("Not reloading, keeping the current configuration: {err}");
+                    false
+                }
+            };
+        if reload {
+            self.reload();
+        }
+
+        let PowerMonitor {
+            config,
+            options,
+            batteries,
+            path_maxchargelevel_file,
+            path_maxchargelevel_file_found,
+            sensors,
+            dbus_service,
+            output_dir,
+            socket_server,
+            metrics_server,
+            publisher,
+            prev_ac_status,
+            prev_battery_status,
+            prev_low_battery_warning,
+            prev_battery_overheat,
+            power_state,
+            pd_history,
+            profile_switcher,
+            power_saving,
+            pace,
+            ac_changed_at,
+            full_since,
+            full_dwell_fired,
+            sessions,
+            stats_changed,
+            wake_alarm,
+            power_readable,
+            capacity_factor,
+            sequence,
+            last_bat_maxchargelevel,
+            published_peripherals,
+            published_ac_adapters,
+            ac_paths,
+            hibernate_armed,
+            pending_shutdown,
+            overrides,
+            forced_shutdown,
+            skipped_shutdown,
+            low_voltage_samples,
+            sag_detector,
+            charge_stall,
+            #[cfg(feature = "lua")]
+            policy,
+            ..
+        } = self;
+        let dir_path = output_dir.as_str();
+
+        // A configured knob takes precedence over the detected one.
+        let path_charge_limit = match &config.charge_limit_path {
+            Some(path) => Some(PathBuf::from(path)),
+            None => path_maxchargelevel_file_found.then(|| path_maxchargelevel_file.clone()),
+        };
+
+        // Keep the configured charge limit set.
+        let charge_limit_percent = config.charge_limit_percent.filter(|_| !options.dry_run);
+        if let (Some(path), Some(percent)) = (&path_charge_limit, charge_limit_percent) {
+            let what = format!("write {}", path.display());
+            if report_once(&what, charge_limit::apply(path, percent)) == Some(true) {
+                info!("Set charge limit to {percent}% in {}.", path.display());
+            }
+        }
+
+	// Get max charge battery level, if set
+	let mut bat_maxchargelevel = match &path_charge_limit {
+	    None => 100.0,
+	    Some(path) => read_battery_maxchargelevel(&path.display().to_string()).unwrap_or(-999.9),
+	};
+
+	// sanity check, if out of bounds either take from previous
+	// value (if looks ok-ish) or otherwise clamp to sane default
+	if !(0.0..=100.0).contains(&bat_maxchargelevel) {
+	    if (0.0..=100.0).contains(&*last_bat_maxchargelevel) {
+		bat_maxchargelevel = *last_bat_maxchargelevel;
+	    }
+	    else {
+		bat_maxchargelevel = 100.0;
+	    }
+	}
+
+	// update value for next iteration
+	if bat_maxchargelevel != *last_bat_maxchargelevel {
+	    *last_bat_maxchargelevel = bat_maxchargelevel;
+
+	    // print new detected value, skipping first time (uninitialized)
+	    if *last_bat_maxchargelevel >= 0.0 {
+		info!("New MaxChargeLevel value detected for battery = '{}'", *last_bat_maxchargelevel);
+	    }
+	}
+
+        // Read battery variables.
+        // Batteries can be removed, replaced or added at any time.
+        let battery_paths = battery::battery_paths(config);
+        if battery_paths.iter().ne(batteries.iter().map(|battery| &battery.path)) {
+            info!("Batteries changed, rescanning.");
+            *batteries = find_batteries(config);
+        }
+
+        // Adopt AC adapters registered after startup, like USB-PD
+        // ports whose driver loads late.
+        let found_ac_paths = ac::adapter_paths();
+        if found_ac_paths != *ac_paths {
+            for path_ac in found_ac_paths.iter().filter(|path| !ac_paths.contains(path)) {
+                info!("Found AC power supply: '{}'", path_ac.display());
+            }
+            for path_ac in ac_paths.iter().filter(|path| !found_ac_paths.contains(path)) {
+                info!("AC power supply went away: '{}'", path_ac.display());
+            }
+            *ac_paths = found_ac_paths;
+        }
+
+        let sample = Sample::read(config, batteries, *capacity_factor, sensors, ac_paths, bat_maxchargelevel, loop_start);
+        let Sample { readings, reading, pdcs, pdvl, pdam, ac_adapters, .. } = &sample;
+        let (pdcs, pdvl, pdam) = (*pdcs, *pdvl, *pdam);
+        let battery_present = !batteries.is_empty() && reading.present != Some(false);
+        if battery_present && reading.power_now.is_some() != *power_readable {
+            *power_readable = reading.power_now.is_some();
+            if *power_readable {
+                info!("Power draw readable again.");
+            } else {
+                warn!("Cannot read the power draw, time estimates are unavailable until it's back.");
+            }
+        }
+
+        // Derive everything else.
+        let inputs = sample.inputs();
+        let outputs = sample.derive(config, power_state, overrides);
+        let Outputs {
+            ac_status,
+            pd_wattage,
+            pd_status,
+            charger_watts,
+            charger_type,
+            charger_class,
+            net_power_watts,
+            net_drain,
+            battery_percent,
+            battery_percent_accuracy,
+            battery_status,
+            power_draw_watts_smoothed: power_watts,
+            secs_until_battery_full,
+            secs_until_battery_empty,
+            secs_until_shutdown_request,
+            secs_until_shutdown_request_min,
+            secs_until_shutdown_request_max,
+            low_battery_warning,
+            battery_overheat,
+        } = outputs.clone();
+        let power_watts_raw = reading.power_watts;
+
+        // Keep a wake alarm set for the battery running out while
+        // suspended, if enabled.
+        if config.wake_alarm && !options.dry_run {
+            let rsbp = config.request_shutdown_battery_percent;
+            let wh = reading
+                .charge_now
+                .zip(reading.charge_full)
+                .and_then(|(charge_now, charge_full)| reading.to_wh(charge_now - charge_full * rsbp / 100.0));
+            let discharging = battery_status == Some("Discharging");
+            wake_alarm.update(discharging, wh, secs_until_shutdown_request, config.wake_alarm_margin_secs);
+        } else {
+            wake_alarm.clear();
+        }
+
+        // Save power on low battery, if enabled.
+        let on_ac = ac_status.is_some_and(|x| x != "Disconnected");
+        let low = battery_percent.is_some_and(|x| x <= config.low_power_profile_battery_percent);
+        if !options.dry_run {
+            profile_switcher.update(config.low_power_profile.as_deref(), low, on_ac);
+            power_saving.update(&config.backlight_cap, &config.power_limit, battery_percent, on_ac);
+        }
+        let platform_profile = platform_profile::read();
+
+        // Identify what's providing power.
+        let charger = charger::identify(on_ac);
+        let battery_temp_celsius = reading.temp_celsius;
+
+        // Time spent at 100% on AC.
+        let at_full = on_ac && (battery_status == Some("Full") || battery_percent.is_some_and(|x| x >= 100.0));
+        if !at_full {
+            *full_since = None;
+            *full_dwell_fired = false;
+        } else if full_since.is_none() {
+            *full_since = Some(loop_start);
+        }
+        let secs_at_full = full_since.map(|since| loop_start.duration_since(since).as_secs_f64());
+
+        let snapshot = Snapshot::new(
+            &inputs,
+            &outputs,
+            &charger,
+            platform_profile.clone(),
+            secs_at_full,
+            battery_present,
+        );
+        *sequence += 1;
+
+        // Write to <output_dir>/*
+        // generation.pending goes first and generation last, so a
+        // reader that finds generation.pending equal to the
+        // generation it read before the other files got them all
+        // from the same iteration, and should retry otherwise.
+        write_str(dir_path, "generation.pending", Some(&sequence.to_string()));
+        // The strings in the configured language, if any, and
+        // numbers that stay the same for UIs.
+        let locale = &config.locale;
+        write_str(dir_path, "ac_status", ac_status.map(|x| codes::localize(locale, x)));
+        write_f64(dir_path, "ac_status_code", snapshot.ac_status_code.map(f64::from));
+        write_f64(dir_path, "battery_percent", battery_percent);
+        write_str(dir_path, "battery_percent_accuracy", battery_percent_accuracy);
+        write_str(dir_path, "battery_status", battery_status.map(|x| codes::localize(locale, x)));
+        write_f64(dir_path, "battery_status_code", snapshot.battery_status_code.map(f64::from));
+        write_str(dir_path, "battery_present", Some(if battery_present { "1" } else { "0" }));
+        write_str(dir_path, "ac_present", snapshot.ac_present.map(|x| if x { "1" } else { "0" }));
+        write_f64(dir_path, "charger_watts", charger_watts);
+        write_str(dir_path, "charger_type", charger_type.map(|x| codes::localize(locale, x)));
+        write_f64(dir_path, "charger_type_code", snapshot.charger_type_code.map(f64::from));
+        write_str(dir_path, "charger_class", charger_class.as_deref());
+        match &charger.model {
+            Some(model) => write_str(dir_path, "charger_model", Some(model)),
+            None => remove_output(dir_path, "charger_model"),
+        }
+        write_str(dir_path, "platform_profile", platform_profile.as_deref());
+        write_str(dir_path, "dock_connected", Some(if charger.dock_connected { "1" } else { "0" }));
+        write_f64(dir_path, "pd_voltage", pdvl);
+        write_f64(dir_path, "pd_current", pdam);
+        write_f64(dir_path, "pd_wattage", pd_wattage);
+        write_str(dir_path, "pd_status", pd_status.as_deref());
+
+        let val = secs_until_battery_full;
+        write_f64(dir_path, "secs_until_battery_full", val);
+        write_f64(dir_path, "secs_at_full", Some(secs_at_full.unwrap_or(0.0)));
+
+        write_f64(dir_path, "charge_limit_percent", snapshot.charge_limit_percent);
+        write_f64(dir_path, "battery_health_percent", snapshot.battery_health_percent);
+        write_f64(dir_path, "cycle_count", snapshot.cycle_count);
+        for (name, val) in [
+            ("charge_full_uah", snapshot.charge_full_uah),
+            ("charge_full_design_uah", snapshot.charge_full_design_uah),
+            ("energy_full_uwh", snapshot.energy_full_uwh),
+            ("energy_full_design_uwh", snapshot.energy_full_design_uwh),
+            ("battery_full_wh", snapshot.battery_full_wh),
+            ("battery_full_design_wh", snapshot.battery_full_design_wh),
+        ] {
+            match val {
+                Some(val) => write_f64(dir_path, name, Some(val)),
+                None => remove_output(dir_path, name),
+            }
+        }
+
+        write_f64(dir_path, "battery_temp_celsius", battery_temp_celsius);
+        let val = battery_overheat.map(|x| if x { "1" } else { "0" });
+        write_str(dir_path, "battery_overheat", val);
+
+        write_f64(dir_path, "power_draw_watts", power_watts_raw);
+        write_f64(dir_path, "net_power_watts", net_power_watts);
+        // In V and A, the current positive when charging and
+        // negative when draining whatever the driver's convention.
+        write_f64(dir_path, "voltage_now_volts", snapshot.voltage_now_volts);
+        write_f64(dir_path, "current_now_amps", snapshot.current_now_amps);
+        write_str(dir_path, "battery_direction", snapshot.battery_direction.as_deref());
+        let val = net_drain.map(|x| if x { "1" } else { "0" });
+        write_str(dir_path, "net_drain", val);
+        write_f64(dir_path, "power_draw_watts_smoothed", power_watts);
+
+        let val = secs_until_battery_empty;
+        write_f64(dir_path, "secs_until_battery_empty", val);
+
+        let val = secs_until_shutdown_request;
+        write_f64(dir_path, "secs_until_shutdown_request", val);
+        write_f64(dir_path, "secs_until_shutdown_request_min", secs_until_shutdown_request_min);
+        write_f64(dir_path, "secs_until_shutdown_request_max", secs_until_shutdown_request_max);
+
+        let val = low_battery_warning.map(|x| if x { "1" } else { "0" });
+        write_str(dir_path, "low_battery_warning", val);
+        write_str(dir_path, "override_active", Some(if overrides.is_active() { "1" } else { "0" }));
+
+        // And the [derived] values, in <output_dir>/derived.
+        if !config.derived.is_empty() {
+            let derived_dir = format!("{dir_path}/{DERIVED_DIR}");
+            report_once(&derived_dir, fs::create_dir_all(&derived_dir));
+            let vars = derived::variables(&snapshot, reading);
+            for (name, expr) in &config.derived {
+                match expr.eval(&vars) {
+                    Some(val) => write_f64(&derived_dir, name.as_str(), Some(val)),
+                    None => remove_output(&derived_dir, name.as_str()),
+                }
+            }
+        }
+
+        let status_json = snapshot.to_json(*sequence);
+        write_str(dir_path, "status.json", Some(&status_json));
+        write_str(dir_path, "generation", Some(&sequence.to_string()));
+
+        if let Some(socket_server) = &socket_server {
+            socket_server.publish(&snapshot, &status_json);
+        }
+
+        if let Some(metrics_server) = &metrics_server {
+            metrics_server.publish(&snapshot, loop_start.elapsed());
+        }
+
+        publisher.publish(&snapshot, &status_json);
+
+        if let Some(dbus_service) = &dbus_service {
+            if let Err(err) = dbus_service.update(&snapshot) {
+                error!("dbus: {err}");
+            }
+        }
+
+        let on_battery = ac_status == Some("Disconnected");
+        *stats_changed |= sessions.update(loop_start, on_battery, battery_percent, power_watts_raw);
+        if *stats_changed {
+            if let Some(socket_server) = &socket_server {
+                socket_server.publish_stats(&sessions.stats().to_json());
+            }
+            if let Some(dbus_service) = &dbus_service {
+                if let Err(err) = dbus_service.update_stats(sessions.stats()) {
+                    error!("dbus: {err}");
+                }
+            }
+            *stats_changed = false;
+        }
+
+        ac::publish(dir_path, ac_adapters, published_ac_adapters);
+
+        // Per-battery values, when there is more than one.
+        if config.publish_peripherals {
+            peripherals::publish(dir_path, published_peripherals);
+        } else {
+            peripherals::clear(dir_path, published_peripherals);
+        }
+
+        if batteries.len() > 1 {
+            for (battery, reading) in batteries.iter().zip(readings) {
+                let bat_dir_path = format!("{dir_path}/{}", battery.name);
+                write_f64(&bat_dir_path, "battery_percent", reading.battery_percent());
+                write_f64(&bat_dir_path, "battery_health_percent", reading.battery_health_percent());
+                write_f64(&bat_dir_path, "cycle_count", reading.cycle_count);
+            }
+        }
+
+        // Fire hooks on transitions.
+        let was_connected = prev_ac_status.map(|x| x != "Disconnected");
+        let is_connected = ac_status.map(|x| x != "Disconnected");
+        let percent_body = format!("Battery at {:.0}%", battery_percent.unwrap_or(0.0));
+        match (was_connected, is_connected) {
+            (Some(false), Some(true)) => {
+                config.hooks.fire(Event::AcConnected, &snapshot);
+                notifications::send(config, Urgency::Normal, "Charger connected", &percent_body);
+                osd::write(dir_path, "ac_connected", Severity::Info, 5.0, &format!("Charger connected. {percent_body}"));
+            }
+            (Some(true), Some(false)) => {
+                config.hooks.fire(Event::AcDisconnected, &snapshot);
+                notifications::send(config, Urgency::Normal, "Charger disconnected", &percent_body);
+                osd::write(dir_path, "ac_disconnected", Severity::Info, 5.0, &format!("Charger disconnected. {percent_body}"));
+            }
+            _ => {}
+        }
+
+        if prev_ac_status.is_some() && *prev_ac_status != Some("Connected slow") && ac_status == Some("Connected slow") {
+            let body = match charger_watts {
+                Some(charger_watts) => format!("Charging slowly with a {charger_watts:.0} W charger."),
+                None => "Charging slowly.".to_string(),
+            };
+            notifications::send(config, Urgency::Normal, "Slow charger", &body);
+            osd::write(dir_path, "slow_charger", Severity::Warning, 10.0, &body);
+        }
+
+        if pdcs.is_some() && pd_history.update(pd_status.as_deref(), pdvl, pdam) {
+            config.hooks.fire(Event::PdChanged, &snapshot);
+        }
+        if pdcs.is_some() {
+            pd_history.write(dir_path);
+        }
+
+        if prev_battery_status.is_some() && *prev_battery_status != Some("Full") && battery_status == Some("Full") {
+            config.hooks.fire(Event::BatteryFull, &snapshot);
+            osd::write(dir_path, "battery_full", Severity::Info, 5.0, "Battery full.");
+        }
+
+        // Remind to unplug after sitting at full for a while.
+        if let Some((full_dwell_secs, secs_at_full)) = config.full_dwell_secs.zip(secs_at_full) {
+            if secs_at_full >= full_dwell_secs && !*full_dwell_fired {
+                *full_dwell_fired = true;
+                info!("Battery full on AC for {:.0} minutes.", secs_at_full / 60.0);
+                config.hooks.fire(Event::FullDwell, &snapshot);
+                let body = "The battery has been full for a while. Unplugging the charger helps the battery last longer.";
+                notifications::send(config, Urgency::Normal, "Battery full", body);
+            }
+        }
+
+        // Act on requests from the socket.
+        let mut simulate_battery_low = false;
+        if let Some(socket_server) = &socket_server {
+            for request in socket_server.take_requests() {
+                match request {
+                    Request::SimulateBatteryLow => simulate_battery_low = true,
+                    Request::ForcedShutdown { enabled, uid, pid } => {
+                        let state = if enabled { "on" } else { "off" };
+                        warn!("Forced shutdown turned {state} by uid {uid} (pid {pid}), until restarted.");
+                        *forced_shutdown = Some(enabled);
+                    }
+                    Request::PostponeShutdown { uid, pid } => {
+                        let window = config.shutdown_confirmation_secs.unwrap_or(0.0);
+                        let postponed = pending_shutdown.as_mut().map(|pending| {
+                            let duration = Duration::from_secs_f64(window.max(0.0));
+                            (pending.action, pending.postpone(config.max_shutdown_postpones, duration, Instant::now()))
+                        });
+                        match postponed {
+                            Some((action, Postpone::Postponed)) => {
+                                warn!("{action} postponed for another {window} seconds by uid {uid} (pid {pid}).");
+                            }
+                            Some((action, Postpone::Exhausted)) => {
+                                warn!("{action} can't be postponed anymore, as asked by uid {uid} (pid {pid}).");
+                            }
+                            _ => info!("Nothing to postpone, as asked by uid {uid} (pid {pid})."),
+                        }
+                    }
+                }
+            }
+        }
+
+        // Run the warning command once when crossing the threshold.
+        let reached_warning = low_battery_warning == Some(true) && !*prev_low_battery_warning;
+        if reached_warning || simulate_battery_low {
+            if reached_warning {
+                warn!(message_id = MESSAGE_BATTERY_LOW; "Reached warning threshold at {}% battery.", battery_percent.unwrap_or(0.0));
+            } else {
+                warn!("Simulating low battery.");
+            }
+            if let Some(command) = &config.warning_command {
+                hooks::spawn(command, &[]);
+            }
+            config.hooks.fire(Event::BatteryLow, &snapshot);
+            drain_report::capture(dir_path, battery_percent, power_watts_raw);
+            notifications::send(config, Urgency::Normal, "Battery low", &percent_body);
+            osd::write(dir_path, "battery_low", Severity::Warning, 10.0, &format!("Battery low. {percent_body}"));
+        }
+        if let Some(low_battery_warning) = low_battery_warning {
+            *prev_low_battery_warning = low_battery_warning;
+        }
+
+        if let Some(battery_overheat) = battery_overheat {
+            if battery_overheat && !*prev_battery_overheat {
+                warn!(message_id = MESSAGE_BATTERY_OVERHEAT; "Battery temperature {}°C exceeds maximum.", battery_temp_celsius.unwrap_or(0.0));
+                config.hooks.fire(Event::BatteryOverheat, &snapshot);
+                osd::write(dir_path, "battery_overheat", Severity::Warning, 10.0, "Battery too hot.");
+            }
+            *prev_battery_overheat = battery_overheat;
+        }
+
+        // Neither hibernate nor run the critical action when disabled.
+        let forced_shutdown = forced_shutdown.unwrap_or(config.enable_forced_shutdown);
+        write_str(dir_path, "forced_shutdown_enabled", Some(if forced_shutdown { "1" } else { "0" }));
+
+        // Hibernate ahead of the critical action, if enabled, when
+        // reaching hibernate_battery_percent on battery. Only once,
+        // so that resuming doesn't go straight back to hibernation.
+        let on_battery = ac_status == Some("Disconnected");
+        match (config.hibernate_battery_percent, battery_percent) {
+            (Some(hbp), Some(battery_percent)) if on_battery && battery_percent <= hbp => {
+                if *hibernate_armed && pending_shutdown.is_none() && forced_shutdown && !overrides.shutdown_disabled() {
+                    *hibernate_armed = false;
+                    if CriticalAction::Hibernate.resolve() != CriticalAction::Hibernate {
+                        warn!("Reached {hbp}% battery, but hibernate is not available.");
+                    } else {
+                        warn!("Reached {hbp}% battery.");
+                        let action = CriticalAction::Hibernate;
+                        *pending_shutdown = Some(start_countdown(config, dir_path, action, Some(battery_percent), "hibernate"));
+                    }
+                }
+            }
+            _ => *hibernate_armed = true,
+        }
+
+        // With forced shutdowns disabled, like for battery rundown
+        // tests, only say so, once per time the threshold is reached.
+        let reached_shutdown = secs_until_shutdown_request.is_some_and(|x| x == 0.0);
+        if !reached_shutdown {
+            *skipped_shutdown = false;
+        } else if !forced_shutdown && !*skipped_shutdown && !overrides.shutdown_disabled() {
+            *skipped_shutdown = true;
+            config.hooks.fire(Event::BatteryCritical, &snapshot);
+            let rsbp = config.request_shutdown_battery_percent;
+            error!(message_id = MESSAGE_BATTERY_CRITICAL; "Reached {rsbp}% battery, but forced shutdown is disabled: NOT running {}.", config.critical_action);
+        }
+
+        // Start counting down to a forced shutdown.
+        if pending_shutdown.is_none() && forced_shutdown && !overrides.shutdown_disabled() && reached_shutdown {
+            config.hooks.fire(Event::BatteryCritical, &snapshot);
+
+            let rsbp = config.request_shutdown_battery_percent;
+            warn!(message_id = MESSAGE_BATTERY_CRITICAL; "Reached {rsbp}% battery.");
+            let action = config.critical_action.resolve();
+            if action != config.critical_action {
+                warn!("{} is not available, falling back to {action}.", config.critical_action);
+            }
+            *pending_shutdown = Some(begin_shutdown(config, dir_path, action, battery_percent, &snapshot));
+        }
+
+        // A worn battery's voltage can collapse under load well above
+        // request_shutdown_battery_percent: once it stayed below
+        // emergency_voltage_volts for emergency_voltage_samples
+        // iterations while discharging, run the critical action right
+        // away, without waiting for inhibitors.
+        let discharging = reading.direction == Some(Direction::Discharging);
+        let sagging = config
+            .emergency_voltage_volts
+            .zip(snapshot.voltage_now_volts)
+            .is_some_and(|(min, volts)| discharging && volts < min);
+        *low_voltage_samples = if sagging { *low_voltage_samples + 1 } else { 0 };
+        if *low_voltage_samples == config.emergency_voltage_samples.max(1) {
+            let volts = snapshot.voltage_now_volts.unwrap_or(0.0);
+            config.hooks.fire(Event::BatteryCritical, &snapshot);
+            if !forced_shutdown || overrides.shutdown_disabled() {
+                error!(message_id = MESSAGE_BATTERY_CRITICAL; "Battery voltage collapsed to {volts:.2} V, but shutdown is disabled: NOT running {}.", config.critical_action);
+            } else {
+                let action = config.critical_action.resolve();
+                error!(message_id = MESSAGE_BATTERY_CRITICAL; "Battery voltage collapsed to {volts:.2} V, running {action} now.");
+                *pending_shutdown = Some(PendingShutdown::emergency(action, Instant::now()));
+            }
+        }
+
+        // Shed load on a fast voltage sag rather than letting the EC
+        // cut the power.
+        if let Some(volts_per_sec) = config.sag_volts_per_sec {
+            let watts = reading.power_watts.filter(|_| discharging);
+            if sag_detector.update(snapshot.voltage_now_volts, watts, Instant::now(), volts_per_sec, config.sag_min_watts) {
+                warn!(
+                    "Battery voltage sagging to {:.2} V under {:.1} W, shedding load.",
+                    snapshot.voltage_now_volts.unwrap_or(0.0),
+                    watts.unwrap_or(0.0)
+                );
+                config.hooks.fire(Event::LoadShed, &snapshot);
+            }
+        }
+        write_str(dir_path, "sag_events", Some(&sag_detector.events().to_string()));
+
+        // Notice a charger that should do better not charging the
+        // battery, short of the charge limit.
+        if let Some(stall_secs) = config.charge_stall_secs {
+            let limit = snapshot.charge_limit_percent.filter(|&x| x > 0.0).unwrap_or(100.0);
+            let can_charge = on_ac
+                && charger_watts.is_some_and(|watts| watts >= config.charge_stall_charger_watts)
+                && battery_percent.is_some_and(|x| x < limit - 2.0);
+            let charge_watts = reading
+                .power_watts
+                .map(|watts| if reading.direction == Some(Direction::Charging) { watts } else { 0.0 });
+            if charge_stall.update(can_charge, charge_watts, loop_start, Duration::from_secs_f64(stall_secs.max(0.0))) {
+                warn!(
+                    "Not charging for {stall_secs} seconds with a {:.0} W charger, check the cable, hub or temperature.",
+                    charger_watts.unwrap_or(0.0)
+                );
+                config.hooks.fire(Event::ChargeStalled, &snapshot);
+            }
+            write_str(dir_path, "charge_stalled", Some(if charge_stall.stalled() { "1" } else { "0" }));
+        } else {
+            charge_stall.reset();
+            remove_output(dir_path, "charge_stalled");
+        }
+
+        // Keep watching during the countdown: no need to shut down
+        // anymore once charging.
+        if let Some(pending) = pending_shutdown {
+            let conditions = Conditions {
+                ac_connected: ac_status == Some("Connected"),
+                battery_percent,
+                forced_shutdown,
+                shutdown_disabled: overrides.shutdown_disabled(),
+            };
+            let holds_off = |inhibited: &mut _| inhibit::holds_off(config, inhibited);
+            match pending.step(&conditions, config.max_shutdown_postpones, Instant::now(), holds_off) {
+                // Waiting for the system to suspend, see the top.
+                Step::Sleeping => {}
+                Step::SleepTimedOut(action) => {
+                    error!("{action} didn't happen within {} seconds, powering off instead.", SLEEP_TIMEOUT.as_secs());
+                }
+                Step::Charging => {
+                    info!("Charging again, cancelling {}.", pending.action);
+                    let message = format!("Charging again, cancelled {}.", pending.action);
+                    osd::write(dir_path, "shutdown_cancelled", Severity::Info, 5.0, &message);
+                    end_countdown(dir_path);
+                    *pending_shutdown = None;
+                }
+                Step::Overridden => {
+                    info!("Shutdown disabled in {OVERRIDE_PATH}, cancelling {}.", pending.action);
+                    end_countdown(dir_path);
+                    *pending_shutdown = None;
+                }
+                Step::Disabled => {
+                    warn!("Forced shutdown disabled, cancelling {}.", pending.action);
+                    end_countdown(dir_path);
+                    *pending_shutdown = None;
+                }
+                Step::Confirmed => {
+                    end_countdown(dir_path);
+                    *pending_shutdown = Some(start_countdown(config, dir_path, pending.action, pending.battery_percent, "battery_critical"));
+                }
+                Step::Confirming { remaining, postpones_left } => {
+                    write_f64(dir_path, "shutdown_pending", Some(remaining.as_secs_f64().ceil()));
+                    write_str(dir_path, "shutdown_postpones_left", Some(&postpones_left.to_string()));
+                }
+                Step::Inhibited => write_f64(dir_path, "shutdown_countdown", Some(0.0)),
+                Step::CountingDown(remaining) => {
+                    write_f64(dir_path, "shutdown_countdown", Some(remaining.as_secs_f64().ceil()));
+                }
+                Step::Run => {
+                    end_countdown(dir_path);
+                    sessions.end();
+                    match run_critical_action(options, config, dir_path, pending.action, &snapshot) {
+                        Ok(Outcome::PoweringOff) => return ControlFlow::Break(()),
+                        Ok(Outcome::Sleeping) => pending.requested(Instant::now()),
+                        Ok(Outcome::DryRun) => *pending_shutdown = None,
+                        // Trying again next time.
+                        Err(err) => record_failure(dir_path, &err.to_string()),
+                    }
+                }
+            }
+        }
+
+        // Run what the policy script asks for, the same way as when
+        // reaching request_shutdown_battery_percent.
+        #[cfg(feature = "lua")]
+        if let Some(policy) = policy.as_mut().filter(|_| pending_shutdown.is_none()) {
+            match policy.evaluate(&status_json) {
+                Err(err) => {
+                    report_once::<(), _>(policy.path(), Err(err));
+                }
+                Ok(None) => {}
+                Ok(Some(action)) if !forced_shutdown || overrides.shutdown_disabled() => {
+                    warn!("{} asked for {action}, but shutdown is disabled: NOT running it.", policy.path());
+                }
+                Ok(Some(action)) => {
+                    config.hooks.fire(Event::BatteryCritical, &snapshot);
+                    warn!(message_id = MESSAGE_BATTERY_CRITICAL; "{} asked for {action}.", policy.path());
+                    let resolved = action.resolve();
+                    if resolved != action {
+                        warn!("{action} is not available, falling back to {resolved}.");
+                    }
+                    *pending_shutdown = Some(begin_shutdown(config, dir_path, resolved, battery_percent, &snapshot));
+                }
+            }
+        }
+
+        // Poll faster when things are about to happen, and slower
+        // when nothing will.
+        if prev_ac_status.is_some() && *prev_ac_status != ac_status {
+            *ac_changed_at = Some(loop_start);
+        }
+        let after_ac_change = ac_changed_at.is_some_and(|at| loop_start.duration_since(at) < FAST_POLL_AFTER_AC_CHANGE);
+        let margin = config.fast_poll_battery_percent;
+        let near_shutdown = !on_ac && battery_percent.is_some_and(|x| x <= config.request_shutdown_battery_percent + margin);
+        *pace = if after_ac_change || near_shutdown {
+            Pace::Fast
+        } else if at_full {
+            Pace::Idle
+        } else {
+            Pace::Normal
+        };
+
+        // Update prev_*.
+        *prev_ac_status = ac_status;
+        *prev_battery_status = battery_status;
+
+        // Tell systemd we're alive, along with a summary for systemctl
+        // status.
+        let status = match (battery_percent, &battery_status) {
+            (Some(battery_percent), Some(battery_status)) => {
+                format!("Battery at {battery_percent:.0}%, {battery_status}")
+            }
+            _ => "Battery state unknown".to_string(),
+        };
+        report_once("sd_notify", notify::notify(&format!("WATCHDOG=1\nSTATUS={status}")));
+        ControlFlow::Continue(())
+    }
+}
@@ -0,0 +1,256 @@
+use crate::snapshot::Snapshot;
+use log::{error, info};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS, Transport};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const CA_BUNDLE: &str = "/etc/ssl/certs/ca-certificates.crt";
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+// Where and how to publish the values over MQTT.
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    // host, or host:port, 1883 or 8883 with tls by default.
+    pub broker: String,
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+    #[serde(default)]
+    pub tls: bool,
+    // PEM certificates to trust, instead of the system's.
+    pub ca_file: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Announce the values to Home Assistant under discovery_prefix, so
+    // that the device shows up there without any YAML.
+    #[serde(default = "default_discovery")]
+    pub discovery: bool,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_topic_prefix() -> String {
+    "vpower".to_string()
+}
+
+fn default_discovery() -> bool {
+    true
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+// The Home Assistant sensors: value name, entity name, device_class
+// and unit.
+const SENSORS: [(&str, &str, Option<&str>, Option<&str>); 5] = [
+    ("battery_percent", "Battery", Some("battery"), Some("%")),
+    (
+        "power_draw_watts",
+        "Battery power",
+        Some("power"),
+        Some("W"),
+    ),
+    ("battery_status", "Battery status", None, None),
+    ("ac_status", "AC status", None, None),
+    ("charger_watts", "Charger power", Some("power"), Some("W")),
+];
+
+impl MqttConfig {
+    fn host_port(&self) -> (String, u16) {
+        let default_port = if self.tls { 8883 } else { 1883 };
+        match self.broker.rsplit_once(':') {
+            Some((host, port)) => match port.parse() {
+                Ok(port) => (host.to_string(), port),
+                Err(_) => (self.broker.clone(), default_port),
+            },
+            None => (self.broker.clone(), default_port),
+        }
+    }
+}
+
+// Publishes the values, retained, under topic_prefix whenever they
+// change. The connection is kept up in the background, and
+// <topic_prefix>/available says whether vpower is connected.
+pub struct MqttPublisher {
+    config: MqttConfig,
+    client: Client,
+    connected: Arc<AtomicBool>,
+    // Everything is published again after connecting, in case the
+    // broker lost it.
+    published: HashMap<&'static str, String>,
+    discovered: bool,
+}
+
+impl MqttPublisher {
+    pub fn new(config: &MqttConfig) -> io::Result<MqttPublisher> {
+        let (host, port) = config.host_port();
+        let mut options = MqttOptions::new(
+            format!("vpower-{}", hostname().unwrap_or_default()),
+            host,
+            port,
+        );
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(
+            format!("{}/available", config.topic_prefix),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+        if config.tls {
+            let ca_file = config.ca_file.as_deref().unwrap_or(CA_BUNDLE);
+            let ca = fs::read(ca_file)
+                .map_err(|err| io::Error::new(err.kind(), format!("{ca_file}: {err}")))?;
+            options.set_transport(Transport::tls(ca, None, None));
+        }
+
+        let (client, mut connection) = Client::new(options, 64);
+        let connected: Arc<AtomicBool> = Default::default();
+        let broker = config.broker.clone();
+        let connection_state = connected.clone();
+        // Ends once the client is dropped.
+        thread::spawn(move || {
+            let mut failing = false;
+            for event in connection.iter() {
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker {broker}.");
+                        failing = false;
+                        connection_state.store(true, Ordering::Relaxed);
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        if !failing {
+                            error!("mqtt {broker}: {err}");
+                            failing = true;
+                        }
+                        connection_state.store(false, Ordering::Relaxed);
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+
+        Ok(MqttPublisher {
+            config: config.clone(),
+            client,
+            connected,
+            published: HashMap::new(),
+            discovered: false,
+        })
+    }
+
+    // Home Assistant's discovery messages, one per sensor, all
+    // belonging to one device named after the host.
+    fn publish_discovery(&mut self) {
+        let hostname = hostname().unwrap_or_else(|| "vpower".to_string());
+        let node_id: String = hostname
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let prefix = &self.config.topic_prefix;
+        let device = json!({
+            "identifiers": [format!("vpower_{node_id}")],
+            "name": hostname,
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        for (name, entity_name, device_class, unit) in SENSORS {
+            let mut payload = json!({
+                "name": entity_name,
+                "unique_id": format!("vpower_{node_id}_{name}"),
+                "state_topic": format!("{prefix}/{name}"),
+                "availability_topic": format!("{prefix}/available"),
+                "device": device,
+            });
+            if let Some(device_class) = device_class {
+                payload["device_class"] = json!(device_class);
+                payload["state_class"] = json!("measurement");
+            }
+            if let Some(unit) = unit {
+                payload["unit_of_measurement"] = json!(unit);
+            }
+            let topic = format!(
+                "{}/sensor/vpower_{node_id}/{name}/config",
+                self.config.discovery_prefix
+            );
+            if let Err(err) =
+                self.client
+                    .try_publish(topic, QoS::AtLeastOnce, true, payload.to_string())
+            {
+                error!("mqtt discovery {name}: {err}");
+                return;
+            }
+        }
+        self.discovered = true;
+    }
+
+    fn publish_value(&mut self, name: &'static str, value: Option<String>) {
+        let value = value.unwrap_or_default();
+        if self.published.get(name) == Some(&value) {
+            return;
+        }
+        let topic = format!("{}/{name}", self.config.topic_prefix);
+        match self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, true, value.clone())
+        {
+            Err(err) => error!("mqtt {name}: {err}"),
+            Ok(()) => {
+                self.published.insert(name, value);
+            }
+        }
+    }
+
+    pub fn publish(&mut self, snapshot: &Snapshot) {
+        if !self.connected.load(Ordering::Relaxed) {
+            self.published.clear();
+            self.discovered = false;
+            return;
+        }
+        if self.config.discovery && !self.discovered {
+            self.publish_discovery();
+        }
+        self.publish_value("available", Some("online".to_string()));
+        self.publish_value(
+            "battery_percent",
+            snapshot.battery_percent.map(|val| format!("{val:.0}")),
+        );
+        self.publish_value(
+            "power_draw_watts",
+            snapshot.power_draw_watts.map(|val| format!("{val:.1}")),
+        );
+        self.publish_value("battery_status", snapshot.battery_status.clone());
+        self.publish_value("ac_status", snapshot.ac_status.clone());
+        self.publish_value(
+            "charger_watts",
+            snapshot.charger_watts.map(|val| format!("{val:.1}")),
+        );
+    }
+}
+
+impl Drop for MqttPublisher {
+    fn drop(&mut self) {
+        if self.connected.load(Ordering::Relaxed) {
+            let topic = format!("{}/available", self.config.topic_prefix);
+            let _ = self
+                .client
+                .try_publish(topic, QoS::AtLeastOnce, true, "offline");
+            let _ = self.client.try_disconnect();
+        }
+    }
+}
+
+fn hostname() -> Option<String> {
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname").ok()?;
+    Some(hostname.trim().to_string())
+}
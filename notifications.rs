@@ -0,0 +1,110 @@
+use crate::config::Config;
+use crate::hooks;
+use log::error;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::thread;
+use zbus::blocking::Connection;
+use zbus::zvariant::Value;
+
+const NAME: &str = "org.freedesktop.Notifications";
+const PATH: &str = "/org/freedesktop/Notifications";
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bus {
+    // The session bus of every logged in user.
+    #[default]
+    Session,
+    System,
+}
+
+#[derive(Clone, Copy)]
+pub enum Urgency {
+    Normal = 1,
+    Critical = 2,
+}
+
+// Show a desktop notification, if enabled.
+pub fn send(config: &Config, urgency: Urgency, summary: &str, body: &str) {
+    if !config.notifications {
+        return;
+    }
+    match config.notifications_bus {
+        Bus::Session => send_session(urgency, summary, body),
+        Bus::System => send_system(urgency, summary, body),
+    }
+}
+
+// A session bus only accepts connections from its own user, so call
+// Notify through busctl running as each of them.
+fn send_session(urgency: Urgency, summary: &str, body: &str) {
+    let entries = match fs::read_dir("/run/user") {
+        Err(err) => {
+            error!("read /run/user: {err}");
+            return;
+        }
+        Ok(entries) => entries,
+    };
+    for entry in entries.flatten() {
+        let bus_path = entry.path().join("bus");
+        let metadata = match fs::metadata(&bus_path) {
+            Err(_) => continue,
+            Ok(metadata) => metadata,
+        };
+
+        let mut cmd = Command::new("busctl");
+        cmd.uid(metadata.uid())
+            .gid(metadata.gid())
+            .env("XDG_RUNTIME_DIR", entry.path())
+            .env(
+                "DBUS_SESSION_BUS_ADDRESS",
+                format!("unix:path={}", bus_path.display()),
+            )
+            .args(["--user", "--quiet", "call", NAME, PATH, NAME, "Notify"])
+            .args([
+                "susssasa{sv}i",
+                "vpower",
+                "0",
+                "battery",
+                summary,
+                body,
+                "0",
+            ])
+            .args(["1", "urgency", "y", &(urgency as u8).to_string(), "-1"]);
+        hooks::spawn_command(cmd);
+    }
+}
+
+fn send_system(urgency: Urgency, summary: &str, body: &str) {
+    let summary = summary.to_owned();
+    let body = body.to_owned();
+    thread::spawn(move || {
+        let hints = HashMap::from([("urgency", Value::U8(urgency as u8))]);
+        let result = Connection::system().and_then(|connection| {
+            connection.call_method(
+                Some(NAME),
+                PATH,
+                Some(NAME),
+                "Notify",
+                &(
+                    "vpower",
+                    0u32,
+                    "battery",
+                    summary,
+                    body,
+                    Vec::<&str>::new(),
+                    hints,
+                    -1i32,
+                ),
+            )
+        });
+        if let Err(err) = result {
+            error!("notification: {err}");
+        }
+    });
+}
@@ -0,0 +1,38 @@
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::Duration;
+
+// The sd_notify() protocol: newline separated assignments sent as one
+// datagram to $NOTIFY_SOCKET, set by systemd for Type=notify services.
+// Without it, there's nobody to tell and nothing is sent.
+pub fn notify(state: &str) -> io::Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let path = path.to_string_lossy();
+
+    // A leading @ means an abstract socket.
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path.as_ref())?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+// How often to send WATCHDOG=1 at the least, half of WatchdogSec= as
+// systemd passes it in $WATCHDOG_USEC, if it's meant for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
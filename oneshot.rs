@@ -0,0 +1,111 @@
+use crate::ac;
+use crate::battery::find_batteries;
+use crate::calibration;
+use crate::charger;
+use crate::config::Config;
+use crate::monitor::{find_maxchargelevel_file, read_battery_maxchargelevel};
+use crate::overrides::Overrides;
+use crate::platform_profile;
+use crate::power_state::PowerState;
+use crate::sample::Sample;
+use crate::sensors::Sensors;
+use crate::snapshot::Snapshot;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// How --oneshot prints the snapshot.
+#[derive(Clone, Copy, Default)]
+pub enum Format {
+    /// One JSON document, like status.json.
+    #[default]
+    Json,
+    /// key=value lines, leaving out unknown values, for shell scripts.
+    KeyValue,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "json" => Some(Format::Json),
+            "kv" => Some(Format::KeyValue),
+            _ => None,
+        }
+    }
+}
+
+// Read and derive everything once, like an iteration of the main
+// loop without anything to compare with: estimates are from the
+// current power draw only.
+fn read(config: &Config) -> Option<Snapshot> {
+    let batteries = find_batteries(config);
+    let path_bat = &batteries.first()?.path;
+    let sensors = Sensors::new(
+        config.sensors_chip.as_deref(),
+        config.charger_supply.as_deref(),
+    );
+
+    let path_charge_limit = match &config.charge_limit_path {
+        Some(path) => Some(PathBuf::from(path)),
+        None => {
+            Some(find_maxchargelevel_file(path_bat)).filter(|path| !path.as_os_str().is_empty())
+        }
+    };
+    let charge_limit_percent = match &path_charge_limit {
+        None => 100.0,
+        Some(path) => read_battery_maxchargelevel(&path.display().to_string())
+            .filter(|percent| (0.0..=100.0).contains(percent))
+            .unwrap_or(100.0),
+    };
+
+    let sample = Sample::read(
+        config,
+        &batteries,
+        calibration::capacity_factor(),
+        &sensors,
+        &ac::adapter_paths(),
+        charge_limit_percent,
+        Instant::now(),
+    );
+    let mut power_state = PowerState::new(config.power_smoothing_samples);
+    let outputs = sample.derive(config, &mut power_state, &mut Overrides::default());
+    let inputs = sample.inputs();
+    let on_ac = outputs.ac_status.is_some_and(|x| x != "Disconnected");
+    let charger = charger::identify(on_ac);
+    Some(Snapshot::new(
+        &inputs,
+        &outputs,
+        &charger,
+        platform_profile::read(),
+        None,
+        true,
+    ))
+}
+
+fn print_key_values(snapshot: &Snapshot) {
+    let Ok(Value::Object(values)) = serde_json::to_value(snapshot) else {
+        return;
+    };
+    for (key, val) in values {
+        match val {
+            Value::Null => {}
+            Value::String(string) => println!("{key}={string}"),
+            Value::Bool(bool) => println!("{key}={}", if bool { 1 } else { 0 }),
+            val => println!("{key}={val}"),
+        }
+    }
+}
+
+/// Print the values vpower would publish, without writing them or
+/// acting on them, and whether a battery was found.
+pub fn run(config: &Config, format: Format) -> bool {
+    let Some(snapshot) = read(config) else {
+        eprintln!("No battery found.");
+        return false;
+    };
+    match format {
+        Format::Json => println!("{}", snapshot.to_json(0)),
+        Format::KeyValue => print_key_values(&snapshot),
+    }
+    true
+}
@@ -0,0 +1,55 @@
+// One file, <output_dir>/osd_event, describing the latest event worth
+// showing on screen, so that the UI doesn't have to work them out from
+// the raw values. It's replaced atomically on every transition:
+//
+//   type                severity  ttl        when
+//   ac_connected        info      5          ac_status leaves Disconnected
+//   ac_disconnected     info      5          ac_status becomes Disconnected
+//   slow_charger        warning   10         ac_status becomes Connected slow
+//   battery_full        info      5          battery_status becomes Full
+//   battery_low         warning   10         low_battery_warning becomes 1
+//   battery_overheat    warning   10         battery_overheat becomes 1
+//   shutdown_pending    critical  window     the shutdown confirmation window starts
+//   battery_critical    critical  countdown  the forced shutdown countdown starts
+//   hibernate           critical  countdown  the hibernate_battery_percent countdown starts
+//   shutdown_cancelled  info      5          charging again during the countdown
+//
+// ttl is how many seconds the event is worth showing for, and
+// timestamp tells a new event from one already shown.
+use crate::write_str;
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(Serialize)]
+struct OsdEvent<'a> {
+    #[serde(rename = "type")]
+    ty: &'a str,
+    message: &'a str,
+    severity: Severity,
+    ttl: f64,
+    timestamp: f64,
+}
+
+pub fn write(dir_path: &str, ty: &str, severity: Severity, ttl: f64, message: &str) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |duration| duration.as_secs_f64());
+    let event = OsdEvent {
+        ty,
+        message,
+        severity,
+        ttl,
+        timestamp,
+    };
+    if let Ok(json) = serde_json::to_string(&event) {
+        write_str(dir_path, "osd_event", Some(&json));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::power_state::Outputs;
+use log::{error, info};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const OVERRIDE_PATH: &str = "/etc/vpower/override.toml";
+
+// The values ac_status can be pinned to.
+const AC_STATUSES: [&str; 3] = ["Connected", "Connected slow", "Disconnected"];
+
+// Values pinned by an operator, like for a demo kiosk running from a
+// bench supply with a dead battery.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Pins {
+    // Unix time after which the file is ignored, never if unset.
+    expires: Option<u64>,
+    ac_status: Option<String>,
+    battery_percent: Option<f64>,
+    // Neither hibernate nor run critical_action.
+    #[serde(default)]
+    disable_shutdown: bool,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+fn read(path: &Path) -> Option<Pins> {
+    let string = match fs::read_to_string(path) {
+        Err(err) => {
+            error!("read {}: {err}", path.display());
+            return None;
+        }
+        Ok(string) => string,
+    };
+    let pins: Pins = match toml::from_str(&string) {
+        Err(err) => {
+            error!("read {}: {err}", path.display());
+            return None;
+        }
+        Ok(pins) => pins,
+    };
+    if let Some(ac_status) = &pins.ac_status {
+        if !AC_STATUSES.contains(&ac_status.as_str()) {
+            error!("read {}: unknown ac_status {ac_status}", path.display());
+            return None;
+        }
+    }
+    Some(pins)
+}
+
+// OVERRIDE_PATH, read again whenever it changes, and whether it
+// applies or expired.
+pub struct Overrides {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    pins: Option<Pins>,
+    active: bool,
+}
+
+impl Default for Overrides {
+    fn default() -> Self {
+        Overrides::at(PathBuf::from(OVERRIDE_PATH))
+    }
+}
+
+impl Overrides {
+    // Reading the pins from path instead.
+    fn at(path: PathBuf) -> Self {
+        Overrides {
+            path,
+            modified: None,
+            pins: None,
+            active: false,
+        }
+    }
+
+    pub fn update(&mut self) {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified != self.modified {
+            self.modified = modified;
+            self.pins = modified.and_then(|_| read(&self.path));
+        }
+
+        let active = self
+            .pins
+            .as_ref()
+            .is_some_and(|pins| pins.expires.is_none_or(|expires| now_secs() < expires));
+        let path = self.path.display();
+        if active && !self.active {
+            info!("Applying the values pinned in {path}.");
+        } else if !active && self.active {
+            info!("No longer applying the values pinned in {path}.");
+        }
+        self.active = active;
+    }
+
+    fn pins(&self) -> Option<&Pins> {
+        self.pins.as_ref().filter(|_| self.active)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn apply(&self, outputs: &mut Outputs) {
+        let Some(pins) = self.pins() else {
+            return;
+        };
+        if let Some(ac_status) = &pins.ac_status {
+            outputs.ac_status = AC_STATUSES.into_iter().find(|status| status == ac_status);
+        }
+        if let Some(battery_percent) = pins.battery_percent {
+            outputs.battery_percent = Some(battery_percent.clamp(0.0, 100.0));
+        }
+    }
+
+    pub fn shutdown_disabled(&self) -> bool {
+        self.pins().is_some_and(|pins| pins.disable_shutdown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Scratch;
+
+    // The overrides read from a file with contents.
+    fn overrides(contents: &str) -> (Scratch, Overrides) {
+        let scratch = Scratch::new("override.toml");
+        fs::write(&scratch.path, contents).unwrap();
+        let mut overrides = Overrides::at(scratch.path.clone());
+        overrides.update();
+        (scratch, overrides)
+    }
+
+    fn outputs() -> Outputs {
+        Outputs {
+            ac_status: Some("Disconnected"),
+            battery_percent: Some(2.0),
+            ..Outputs::default()
+        }
+    }
+
+    #[test]
+    fn pins_values() {
+        let (_scratch, overrides) = overrides(
+            r#"
+            ac_status = "Connected"
+            battery_percent = 150
+            "#,
+        );
+        assert!(overrides.is_active());
+        let mut outputs = outputs();
+        overrides.apply(&mut outputs);
+        assert_eq!(outputs.ac_status, Some("Connected"));
+        assert_eq!(outputs.battery_percent, Some(100.0));
+        assert!(!overrides.shutdown_disabled());
+    }
+
+    #[test]
+    fn expires() {
+        let expires = |secs: u64| format!("expires = {secs}\ndisable_shutdown = true\n");
+        let (_scratch, overrides) = self::overrides(&expires(now_secs() + 3600));
+        assert!(overrides.is_active());
+        assert!(overrides.shutdown_disabled());
+        let (_scratch, overrides) = self::overrides(&expires(now_secs() - 1));
+        assert!(!overrides.is_active());
+        assert!(!overrides.shutdown_disabled());
+    }
+
+    #[test]
+    fn rejects_unknown_ac_status() {
+        let (_scratch, overrides) = overrides(
+            r#"
+            ac_status = "Plugged"
+            disable_shutdown = true
+            "#,
+        );
+        assert!(!overrides.is_active());
+        assert!(!overrides.shutdown_disabled());
+        let mut outputs = outputs();
+        overrides.apply(&mut outputs);
+        assert_eq!(outputs.ac_status, Some("Disconnected"));
+    }
+
+    #[test]
+    fn follows_the_file() {
+        let (scratch, mut overrides) = overrides("disable_shutdown = true\n");
+        assert!(overrides.shutdown_disabled());
+        fs::remove_file(&scratch.path).unwrap();
+        overrides.update();
+        assert!(!overrides.is_active());
+        assert!(!overrides.shutdown_disabled());
+    }
+}
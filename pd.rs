@@ -0,0 +1,30 @@
+//! Decoding of the PD contract status (pdcs) reported by the Steam
+//! Deck's EC.
+
+pub const PDCS_CONNECTED: u8 = 1 << 0;
+const PDCS_SOURCE: u8 = 1 << 4;
+
+pub fn is_connected(pdcs: u8) -> bool {
+    pdcs & PDCS_CONNECTED != 0
+}
+
+/// Whether the Deck is taking power, rather than supplying it to
+/// another device.
+pub fn is_sink(pdcs: u8) -> bool {
+    pdcs & PDCS_SOURCE == 0
+}
+
+/// A comma separated description, like "connected,sink,contract". The
+/// contract is considered established when it has a wattage.
+pub fn status(pdcs: u8, wattage: Option<f64>) -> String {
+    if !is_connected(pdcs) {
+        return "disconnected".to_string();
+    }
+    let role = if is_sink(pdcs) { "sink" } else { "source" };
+    let contract = if wattage.is_some_and(|wattage| wattage > 0.0) {
+        "contract"
+    } else {
+        "no-contract"
+    };
+    format!("connected,{role},{contract}")
+}
@@ -0,0 +1,92 @@
+use crate::write_str;
+use log::info;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// How many contract changes to keep in pd_history.json.
+const MAX_TRANSITIONS: usize = 20;
+
+// Changes smaller than these are measurement noise, not a new
+// contract.
+const VOLTAGE_TOLERANCE: f64 = 0.5;
+const CURRENT_TOLERANCE: f64 = 0.25;
+
+#[derive(Clone, Serialize)]
+struct Transition {
+    timestamp: f64,
+    status: Option<String>,
+    voltage: Option<f64>,
+    current: Option<f64>,
+    wattage: Option<f64>,
+}
+
+// The PD contract as last seen, and how it changed lately, like when
+// a dock starts sharing its power or a cable acts up.
+#[derive(Default)]
+pub struct PdHistory {
+    last: Option<Transition>,
+    transitions: VecDeque<Transition>,
+}
+
+fn differs(a: Option<f64>, b: Option<f64>, tolerance: f64) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => (a - b).abs() >= tolerance,
+        (a, b) => a.is_some() != b.is_some(),
+    }
+}
+
+impl PdHistory {
+    // Whether the contract changed since the last time, the first
+    // one seen not counting as a change.
+    pub fn update(
+        &mut self,
+        status: Option<&str>,
+        voltage: Option<f64>,
+        current: Option<f64>,
+    ) -> bool {
+        let changed = match &self.last {
+            None => false,
+            Some(last) => {
+                last.status.as_deref() != status
+                    || differs(last.voltage, voltage, VOLTAGE_TOLERANCE)
+                    || differs(last.current, current, CURRENT_TOLERANCE)
+            }
+        };
+        if self.last.is_some() && !changed {
+            return false;
+        }
+
+        let transition = Transition {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |duration| duration.as_secs_f64()),
+            status: status.map(str::to_string),
+            voltage,
+            current,
+            wattage: voltage
+                .zip(current)
+                .map(|(voltage, current)| voltage * current),
+        };
+        if changed {
+            info!(
+                "PD contract changed: {}, {:.1} V, {:.2} A.",
+                status.unwrap_or("unknown"),
+                voltage.unwrap_or(0.0),
+                current.unwrap_or(0.0)
+            );
+        }
+        if self.transitions.len() >= MAX_TRANSITIONS {
+            self.transitions.pop_front();
+        }
+        self.transitions.push_back(transition.clone());
+        self.last = Some(transition);
+        changed
+    }
+
+    pub fn write(&self, dir_path: &str) {
+        if let Ok(json) = serde_json::to_string(&self.transitions) {
+            write_str(dir_path, "pd_history.json", Some(&json));
+        }
+    }
+}
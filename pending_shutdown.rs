@@ -0,0 +1,324 @@
+use crate::action::CriticalAction;
+use std::time::{Duration, Instant};
+
+// How long a requested sleep action may take to actually suspend the
+// system before powering off instead.
+pub const SLEEP_TIMEOUT: Duration = Duration::from_secs(120);
+
+// A forced shutdown counting down, which plugging in cancels. While
+// confirming, the countdown hasn't started yet, and can be postponed.
+// Once it's over, the action still waits for inhibitors, if it should.
+// A sleep action stays pending once requested, until the system
+// actually suspended.
+pub struct PendingShutdown {
+    deadline: Instant,
+    pub action: CriticalAction,
+    pub battery_percent: Option<f64>,
+    confirming: bool,
+    postpones: u32,
+    wait_for_inhibitors: bool,
+    // Since when, and by what, the action is held off.
+    inhibited: Option<(Instant, String)>,
+    // When the sleep action was requested.
+    requested: Option<Instant>,
+}
+
+// What the main loop sees of the world, for the pending shutdown.
+pub struct Conditions {
+    pub ac_connected: bool,
+    pub battery_percent: Option<f64>,
+    pub forced_shutdown: bool,
+    // By the override file.
+    pub shutdown_disabled: bool,
+}
+
+// What to do about a pending shutdown, this iteration.
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    // Waiting for a requested sleep action to suspend the system.
+    Sleeping,
+    // The sleep action never happened, powering off instead.
+    SleepTimedOut(CriticalAction),
+    Charging,
+    Overridden,
+    Disabled,
+    // The confirmation window is over, start the countdown.
+    Confirmed,
+    Confirming {
+        remaining: Duration,
+        postpones_left: u32,
+    },
+    Inhibited,
+    CountingDown(Duration),
+    Run,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Postpone {
+    Postponed,
+    Exhausted,
+    NotConfirming,
+}
+
+impl PendingShutdown {
+    fn new(action: CriticalAction, battery_percent: Option<f64>, deadline: Instant) -> Self {
+        PendingShutdown {
+            deadline,
+            action,
+            battery_percent,
+            confirming: false,
+            postpones: 0,
+            wait_for_inhibitors: true,
+            inhibited: None,
+            requested: None,
+        }
+    }
+
+    pub fn countdown(
+        action: CriticalAction,
+        battery_percent: Option<f64>,
+        timeout: Duration,
+        now: Instant,
+    ) -> Self {
+        Self::new(action, battery_percent, now + timeout)
+    }
+
+    pub fn confirmation(
+        action: CriticalAction,
+        battery_percent: Option<f64>,
+        window: Duration,
+        now: Instant,
+    ) -> Self {
+        PendingShutdown {
+            confirming: true,
+            ..Self::new(action, battery_percent, now + window)
+        }
+    }
+
+    // Right away, without waiting for inhibitors, replacing whatever
+    // was pending.
+    pub fn emergency(action: CriticalAction, now: Instant) -> Self {
+        PendingShutdown {
+            wait_for_inhibitors: false,
+            ..Self::new(action, None, now)
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.is_some()
+    }
+
+    // The sleep action was requested, and the system should suspend.
+    pub fn requested(&mut self, now: Instant) {
+        self.requested = Some(now);
+    }
+
+    // Restart the confirmation window, up to max_postpones times.
+    pub fn postpone(&mut self, max_postpones: u32, window: Duration, now: Instant) -> Postpone {
+        if !self.confirming {
+            return Postpone::NotConfirming;
+        }
+        if self.postpones >= max_postpones {
+            return Postpone::Exhausted;
+        }
+        self.postpones += 1;
+        self.deadline = now + window;
+        Postpone::Postponed
+    }
+
+    // holds_off tells whether inhibitors hold off the action, keeping
+    // track of them in what it's passed.
+    pub fn step(
+        &mut self,
+        conditions: &Conditions,
+        max_postpones: u32,
+        now: Instant,
+        holds_off: impl FnOnce(&mut Option<(Instant, String)>) -> bool,
+    ) -> Step {
+        if let Some(requested) = self.requested {
+            if now.saturating_duration_since(requested) < SLEEP_TIMEOUT {
+                return Step::Sleeping;
+            }
+            let action = self.action;
+            *self = Self::emergency(CriticalAction::Poweroff, now);
+            return Step::SleepTimedOut(action);
+        }
+
+        let charging = conditions.ac_connected
+            || conditions
+                .battery_percent
+                .zip(self.battery_percent)
+                .is_some_and(|(battery_percent, start)| battery_percent > start);
+        let remaining = self.deadline.saturating_duration_since(now);
+        if charging {
+            Step::Charging
+        } else if conditions.shutdown_disabled {
+            Step::Overridden
+        } else if !conditions.forced_shutdown {
+            Step::Disabled
+        } else if self.confirming && remaining.is_zero() {
+            Step::Confirmed
+        } else if self.confirming {
+            Step::Confirming {
+                remaining,
+                postpones_left: max_postpones.saturating_sub(self.postpones),
+            }
+        } else if !remaining.is_zero() {
+            Step::CountingDown(remaining)
+        } else if self.wait_for_inhibitors && holds_off(&mut self.inhibited) {
+            Step::Inhibited
+        } else {
+            Step::Run
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECS: Duration = Duration::from_secs(1);
+
+    fn conditions() -> Conditions {
+        Conditions {
+            ac_connected: false,
+            battery_percent: Some(1.0),
+            forced_shutdown: true,
+            shutdown_disabled: false,
+        }
+    }
+
+    fn step(pending: &mut PendingShutdown, conditions: &Conditions, now: Instant) -> Step {
+        pending.step(conditions, 2, now, |_| false)
+    }
+
+    fn pending(now: Instant) -> PendingShutdown {
+        PendingShutdown::countdown(CriticalAction::Poweroff, Some(1.0), 10 * SECS, now)
+    }
+
+    #[test]
+    fn counts_down() {
+        let now = Instant::now();
+        let mut pending = pending(now);
+        let conditions = conditions();
+        assert_eq!(
+            step(&mut pending, &conditions, now + 4 * SECS),
+            Step::CountingDown(6 * SECS)
+        );
+        assert_eq!(step(&mut pending, &conditions, now + 10 * SECS), Step::Run);
+    }
+
+    #[test]
+    fn confirms() {
+        let now = Instant::now();
+        let action = CriticalAction::Poweroff;
+        let mut pending = PendingShutdown::confirmation(action, Some(1.0), 30 * SECS, now);
+        let conditions = conditions();
+        assert_eq!(
+            step(&mut pending, &conditions, now + 10 * SECS),
+            Step::Confirming {
+                remaining: 20 * SECS,
+                postpones_left: 2,
+            }
+        );
+        assert_eq!(
+            step(&mut pending, &conditions, now + 30 * SECS),
+            Step::Confirmed
+        );
+    }
+
+    #[test]
+    fn postpones_up_to_the_limit() {
+        let now = Instant::now();
+        let action = CriticalAction::Poweroff;
+        let mut pending = PendingShutdown::confirmation(action, Some(1.0), 30 * SECS, now);
+        let later = now + 20 * SECS;
+        assert_eq!(pending.postpone(2, 30 * SECS, later), Postpone::Postponed);
+        assert_eq!(pending.postpone(2, 30 * SECS, later), Postpone::Postponed);
+        assert_eq!(pending.postpone(2, 30 * SECS, later), Postpone::Exhausted);
+        assert_eq!(
+            step(&mut pending, &conditions(), later),
+            Step::Confirming {
+                remaining: 30 * SECS,
+                postpones_left: 0,
+            }
+        );
+
+        let mut pending = self::pending(now);
+        assert_eq!(pending.postpone(2, 30 * SECS, now), Postpone::NotConfirming);
+    }
+
+    #[test]
+    fn cancels() {
+        let now = Instant::now();
+        let mut pending = pending(now);
+        let charger = Conditions {
+            ac_connected: true,
+            ..conditions()
+        };
+        assert_eq!(step(&mut pending, &charger, now), Step::Charging);
+        let charging = Conditions {
+            battery_percent: Some(2.0),
+            ..conditions()
+        };
+        assert_eq!(step(&mut pending, &charging, now), Step::Charging);
+        let overridden = Conditions {
+            shutdown_disabled: true,
+            ..conditions()
+        };
+        assert_eq!(step(&mut pending, &overridden, now), Step::Overridden);
+        let disabled = Conditions {
+            forced_shutdown: false,
+            ..conditions()
+        };
+        assert_eq!(step(&mut pending, &disabled, now), Step::Disabled);
+    }
+
+    #[test]
+    fn waits_for_inhibitors() {
+        let now = Instant::now();
+        let mut pending = pending(now);
+        let later = now + 10 * SECS;
+        let holds_off = |inhibited: &mut Option<(Instant, String)>| {
+            *inhibited = Some((later, "update".to_string()));
+            true
+        };
+        let step = pending.step(&conditions(), 2, later, holds_off);
+        assert_eq!(step, Step::Inhibited);
+        assert_eq!(pending.inhibited, Some((later, "update".to_string())));
+        let step = pending.step(&conditions(), 2, later, |_| false);
+        assert_eq!(step, Step::Run);
+    }
+
+    #[test]
+    fn emergency_preempts() {
+        let now = Instant::now();
+        let action = CriticalAction::Poweroff;
+        let mut pending = PendingShutdown::confirmation(action, Some(1.0), 30 * SECS, now);
+        assert_ne!(step(&mut pending, &conditions(), now), Step::Run);
+        let mut pending = PendingShutdown::emergency(action, now);
+        let step = pending.step(&conditions(), 2, now, |_| panic!("waited for inhibitors"));
+        assert_eq!(step, Step::Run);
+    }
+
+    #[test]
+    fn waits_for_sleep() {
+        let now = Instant::now();
+        let mut pending = PendingShutdown::countdown(CriticalAction::Hibernate, None, SECS, now);
+        pending.requested(now);
+        let charger = Conditions {
+            ac_connected: true,
+            ..conditions()
+        };
+        assert_eq!(step(&mut pending, &charger, now + SECS), Step::Sleeping);
+        assert_eq!(
+            step(&mut pending, &conditions(), now + SLEEP_TIMEOUT),
+            Step::SleepTimedOut(CriticalAction::Hibernate)
+        );
+        assert_eq!(pending.action, CriticalAction::Poweroff);
+        assert_eq!(
+            step(&mut pending, &conditions(), now + SLEEP_TIMEOUT),
+            Step::Run
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use crate::battery::all_battery_paths;
+use crate::{read_battery_string, write_str};
+use log::error;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+
+// Values worth showing for peripheral batteries, not all of which
+// report a percentage.
+const FILES: [&str; 4] = ["capacity", "capacity_level", "status", "model_name"];
+
+// Mirror the batteries of peripherals in <dir_path>/peripherals/<name>,
+// removing the directories of those that went away. published keeps
+// track of what was written before.
+pub fn publish(dir_path: &str, published: &mut HashSet<String>) {
+    let peripherals_path = format!("{dir_path}/peripherals");
+    if let Err(err) = fs::create_dir_all(&peripherals_path) {
+        error!("mkdir {peripherals_path}: {err}");
+        return;
+    }
+
+    let mut present = HashSet::new();
+    for (path, _) in all_battery_paths()
+        .into_iter()
+        .filter(|(_, peripheral)| *peripheral)
+    {
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let peripheral_path = format!("{peripherals_path}/{name}");
+        for file in FILES {
+            if path.join(file).exists() {
+                write_str(
+                    &peripheral_path,
+                    file,
+                    read_battery_string(&path, file).as_deref(),
+                );
+            }
+        }
+        present.insert(name);
+    }
+
+    for name in published.difference(&present) {
+        remove_dir(&format!("{peripherals_path}/{name}"));
+    }
+    *published = present;
+}
+
+// Remove everything, when no longer enabled.
+pub fn clear(dir_path: &str, published: &mut HashSet<String>) {
+    if !published.is_empty() {
+        remove_dir(&format!("{dir_path}/peripherals"));
+        published.clear();
+    }
+}
+
+fn remove_dir(path: &str) {
+    if let Err(err) = fs::remove_dir_all(path) {
+        if err.kind() != io::ErrorKind::NotFound {
+            error!("remove {path}: {err}");
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use crate::quirks::Quirks;
+use crate::sensors::Sensors;
+use crate::{remove_output, write_str};
+use std::fs;
+use std::path::Path;
+
+// Attributes of the Steam Deck's EC driver, on the device the hwmon
+// chip belongs to or on its parent.
+const EC_ATTRIBUTES: [&str; 2] = ["firmware_version", "board_id"];
+
+pub fn read_ec_attribute(chip_path: &Path, name: &str) -> Option<String> {
+    let device = chip_path.join("device");
+    [device.join(name), device.join("..").join(name)]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .map(|val| val.trim().to_string())
+}
+
+fn publish_value(dir_path: &str, var_name: &str, val: Option<&str>) {
+    match val {
+        Some(val) => write_str(dir_path, var_name, Some(val)),
+        None => remove_output(dir_path, var_name),
+    }
+}
+
+// Describe the firmware and the chip the PD contract is read from in
+// <dir_path>/platform, so that bug reports can tell which BIOS and EC
+// firmware a battery oddity happened with.
+pub fn publish(dir_path: &str, sensors: &Sensors, quirks: &Quirks) {
+    let platform_path = format!("{dir_path}/platform");
+    publish_value(
+        &platform_path,
+        "product_name",
+        quirks.product_name.as_deref(),
+    );
+    publish_value(&platform_path, "board_name", quirks.board_name.as_deref());
+    publish_value(
+        &platform_path,
+        "bios_version",
+        quirks.bios_version.as_deref(),
+    );
+
+    let (backend, chip) = sensors.describe();
+    publish_value(&platform_path, "sensors_backend", Some(backend));
+    publish_value(&platform_path, "hwmon_chip", chip.ok().as_deref());
+    let chip_path = sensors
+        .chip_path()
+        .ok()
+        .map(|path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+    let path = chip_path.as_ref().map(|path| path.display().to_string());
+    publish_value(&platform_path, "hwmon_path", path.as_deref());
+    for attribute in EC_ATTRIBUTES {
+        let val = chip_path
+            .as_ref()
+            .and_then(|path| read_ec_attribute(path, attribute));
+        publish_value(&platform_path, attribute, val.as_deref());
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{report_once, sysfs};
+use log::{info, warn};
+use std::fs;
+
+const PLATFORM_PROFILE: &str = "/sys/firmware/acpi/platform_profile";
+const PLATFORM_PROFILE_CHOICES: &str = "/sys/firmware/acpi/platform_profile_choices";
+
+// The current platform profile, like "balanced" or "low-power".
+pub fn read() -> Option<String> {
+    let string = fs::read_to_string(sysfs(PLATFORM_PROFILE)).ok()?;
+    Some(string.trim().to_string())
+}
+
+fn is_choice(profile: &str) -> bool {
+    fs::read_to_string(sysfs(PLATFORM_PROFILE_CHOICES))
+        .unwrap_or_default()
+        .split_whitespace()
+        .any(|choice| choice == profile)
+}
+
+fn write(profile: &str) -> bool {
+    report_once(
+        &format!("write {PLATFORM_PROFILE}"),
+        fs::write(sysfs(PLATFORM_PROFILE), profile),
+    )
+    .is_some()
+}
+
+// Switches to a low power profile when the battery gets low, and back
+// to the one before once plugged in.
+#[derive(Default)]
+pub struct ProfileSwitcher {
+    // Whether switching was attempted during this discharge.
+    tried: bool,
+    // The profile to restore, while switched.
+    saved: Option<String>,
+}
+
+impl ProfileSwitcher {
+    pub fn update(&mut self, low_power_profile: Option<&str>, low: bool, on_ac: bool) {
+        if on_ac {
+            self.tried = false;
+            if let Some(saved) = self.saved.take() {
+                if write(&saved) {
+                    info!("Restored platform profile {saved}.");
+                }
+            }
+            return;
+        }
+
+        let low_power_profile = match low_power_profile {
+            Some(low_power_profile) if low && !self.tried => low_power_profile,
+            _ => return,
+        };
+        self.tried = true;
+        let current = match read() {
+            Some(current) => current,
+            None => return,
+        };
+        if current == low_power_profile {
+            return;
+        }
+        if !is_choice(low_power_profile) {
+            warn!("platform profile {low_power_profile} is not available");
+        } else if write(low_power_profile) {
+            info!("Switched platform profile from {current} to {low_power_profile}.");
+            self.saved = Some(current);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use crate::action::CriticalAction;
+use log::info;
+use mlua::{Function, HookTriggers, Lua, LuaOptions, StdLib, Table, Value};
+use serde_json::Value as JsonValue;
+use std::cell::Cell;
+use std::fs;
+use std::rc::Rc;
+
+// Enough for any policy, and keeps a leaking script from taking the
+// system down with it.
+const MEMORY_LIMIT: usize = 16 << 20;
+
+// A call gets aborted after this many times INSTRUCTION_STEP
+// instructions, so that a runaway loop can't stall the main loop.
+const INSTRUCTION_STEP: u32 = 10_000;
+const MAX_INSTRUCTION_STEPS: u32 = 100;
+
+// A custom policy in Lua, for what thresholds can't express. The
+// script's policy(state) function gets the values of status.json every
+// iteration, and returns the name of a critical action to run, or nil.
+// Scripts only get the table, string, math and utf8 libraries, plus
+// vpower.log(message), so no files, processes or modules.
+pub struct Policy {
+    lua: Lua,
+    path: String,
+    steps: Rc<Cell<u32>>,
+    // What the last call returned, to run an action only once when
+    // it keeps getting returned.
+    prev: Option<CriticalAction>,
+}
+
+fn parse_action(name: &str) -> Result<CriticalAction, String> {
+    match name {
+        "poweroff" => Ok(CriticalAction::Poweroff),
+        "hibernate" => Ok(CriticalAction::Hibernate),
+        "hybrid-sleep" => Ok(CriticalAction::HybridSleep),
+        "suspend" => Ok(CriticalAction::Suspend),
+        name => Err(format!("unknown action '{name}'")),
+    }
+}
+
+impl Policy {
+    pub fn load(path: &str) -> Result<Policy, String> {
+        let source = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let libs = StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8;
+        let lua = Lua::new_with(libs, LuaOptions::default()).map_err(|err| err.to_string())?;
+        lua.set_memory_limit(MEMORY_LIMIT)
+            .map_err(|err| err.to_string())?;
+
+        let steps = Rc::new(Cell::new(0));
+        let hook_steps = steps.clone();
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(INSTRUCTION_STEP),
+            move |_, _| {
+                hook_steps.set(hook_steps.get() + 1);
+                if hook_steps.get() > MAX_INSTRUCTION_STEPS {
+                    return Err(mlua::Error::runtime("took too long"));
+                }
+                Ok(())
+            },
+        );
+
+        let log_path = path.to_string();
+        let setup = || -> mlua::Result<()> {
+            let vpower = lua.create_table()?;
+            let log = lua.create_function(move |_, message: String| {
+                info!("{log_path}: {message}");
+                Ok(())
+            })?;
+            vpower.set("log", log)?;
+            lua.globals().set("vpower", vpower)?;
+            lua.load(&source).set_name(path).exec()?;
+            lua.globals().get::<_, Function>("policy")?;
+            Ok(())
+        };
+        steps.set(0);
+        setup().map_err(|err| err.to_string())?;
+
+        Ok(Policy {
+            lua,
+            path: path.to_string(),
+            steps,
+            prev: None,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn call(&self, status_json: &str) -> mlua::Result<Option<String>> {
+        let status: serde_json::Map<String, JsonValue> =
+            serde_json::from_str(status_json).map_err(mlua::Error::external)?;
+        let state: Table = self.lua.create_table()?;
+        for (key, val) in status {
+            let val = match val {
+                JsonValue::Bool(val) => Value::Boolean(val),
+                JsonValue::Number(val) => val.as_f64().map_or(Value::Nil, Value::Number),
+                JsonValue::String(val) => Value::String(self.lua.create_string(&val)?),
+                _ => Value::Nil,
+            };
+            state.set(key, val)?;
+        }
+        self.steps.set(0);
+        let policy: Function = self.lua.globals().get("policy")?;
+        policy.call(state)
+    }
+
+    // The action policy() asks for, if it wasn't already asking for it
+    // the last time.
+    pub fn evaluate(&mut self, status_json: &str) -> Result<Option<CriticalAction>, String> {
+        let action = match self.call(status_json) {
+            Err(err) => {
+                self.prev = None;
+                return Err(err.to_string());
+            }
+            Ok(None) => None,
+            Ok(Some(name)) => Some(parse_action(&name)?),
+        };
+        let new = action.filter(|&action| self.prev != Some(action));
+        self.prev = action;
+        Ok(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn load(name: &str, source: &str) -> Result<Policy, String> {
+        let name = format!("vpower-policy-{name}-{}.lua", std::process::id());
+        let path = env::temp_dir().join(name);
+        fs::write(&path, source).unwrap();
+        let policy = Policy::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        policy
+    }
+
+    #[test]
+    fn asks_once() {
+        let source = r#"
+            function policy(state)
+                if state.battery_percent < 8 and state.ac_status == "Disconnected" then
+                    return "hibernate"
+                end
+            end
+        "#;
+        let mut policy = load("once", source).unwrap();
+        let low = r#"{"battery_percent":7.5,"ac_status":"Disconnected"}"#;
+        let ok = r#"{"battery_percent":50,"ac_status":"Disconnected"}"#;
+        assert_eq!(policy.evaluate(ok), Ok(None));
+        assert_eq!(policy.evaluate(low), Ok(Some(CriticalAction::Hibernate)));
+        assert_eq!(policy.evaluate(low), Ok(None));
+        assert_eq!(policy.evaluate(ok), Ok(None));
+        assert_eq!(policy.evaluate(low), Ok(Some(CriticalAction::Hibernate)));
+    }
+
+    #[test]
+    fn sandboxed() {
+        assert!(load("io", "io.open('/etc/passwd')").is_err());
+        assert!(load("missing", "x = 1").is_err());
+        let mut policy = load("loop", "function policy(state) while true do end end").unwrap();
+        assert!(policy.evaluate("{}").is_err());
+        let mut policy = load("bad", "function policy(state) return 'explode' end").unwrap();
+        assert!(policy.evaluate("{}").is_err());
+    }
+}
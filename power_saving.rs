@@ -0,0 +1,182 @@
+use crate::sensors::matches;
+use crate::{report_once, sysfs};
+use log::info;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const BACKLIGHT_DIR: &str = "/sys/class/backlight";
+
+// At or below battery_percent on battery, keep the screen at most at
+// brightness_percent of its maximum.
+#[derive(Clone, Deserialize)]
+pub struct BacklightCap {
+    pub battery_percent: f64,
+    pub brightness_percent: f64,
+}
+
+// At or below battery_percent on battery, write value to the sysfs
+// knob at path, like a TDP or GPU power limit. Wildcards in path are
+// matched against the files present.
+#[derive(Clone, Deserialize)]
+pub struct PowerLimit {
+    pub battery_percent: f64,
+    pub path: String,
+    pub value: String,
+}
+
+fn read_u64(path: &Path) -> Option<u64> {
+    u64::from_str(fs::read_to_string(path).ok()?.trim()).ok()
+}
+
+// The first backlight device, sorted by name.
+fn backlight_path() -> Option<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(sysfs(BACKLIGHT_DIR))
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    paths.sort();
+    paths.into_iter().next()
+}
+
+// The existing paths matching pattern, sorted, one component at a time.
+fn expand(pattern: &str) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("/")];
+    for component in Path::new(pattern).components().skip(1) {
+        let component = component.as_os_str().to_string_lossy();
+        if !component.contains(['*', '?']) {
+            paths = paths
+                .into_iter()
+                .map(|path| path.join(&*component))
+                .collect();
+            continue;
+        }
+        let mut expanded: Vec<PathBuf> = paths
+            .iter()
+            .filter_map(|path| fs::read_dir(path).ok())
+            .flat_map(|entries| entries.flatten())
+            .filter(|entry| matches(component.as_bytes(), entry.file_name().as_encoded_bytes()))
+            .map(|entry| entry.path())
+            .collect();
+        expanded.sort();
+        paths = expanded;
+    }
+    paths.retain(|path| path.exists());
+    paths
+}
+
+// Actions that stretch the battery when it gets low, undone once
+// plugged in.
+#[derive(Default)]
+pub struct PowerSaving {
+    // The brightness from before capping it.
+    saved_brightness: Option<(PathBuf, u64)>,
+    // The values of the power limits from before writing them.
+    saved_limits: Vec<(PathBuf, String)>,
+}
+
+impl PowerSaving {
+    pub fn update(
+        &mut self,
+        backlight_caps: &[BacklightCap],
+        power_limits: &[PowerLimit],
+        battery_percent: Option<f64>,
+        on_ac: bool,
+    ) {
+        if on_ac {
+            self.restore();
+            return;
+        }
+
+        // The lowest cap of the thresholds reached.
+        let cap = battery_percent.and_then(|battery_percent| {
+            backlight_caps
+                .iter()
+                .filter(|cap| battery_percent <= cap.battery_percent)
+                .map(|cap| cap.brightness_percent)
+                .reduce(f64::min)
+        });
+        if let Some(cap) = cap {
+            self.cap_brightness(cap);
+        }
+
+        // For each knob, the value of the lowest threshold reached.
+        let mut reached: Vec<&PowerLimit> = power_limits
+            .iter()
+            .filter(|limit| battery_percent.is_some_and(|x| x <= limit.battery_percent))
+            .collect();
+        reached.sort_by(|a, b| a.battery_percent.total_cmp(&b.battery_percent));
+        let mut written = Vec::new();
+        for limit in reached {
+            for path in expand(&limit.path) {
+                if !written.contains(&path) {
+                    self.limit(&path, &limit.value);
+                    written.push(path);
+                }
+            }
+        }
+    }
+
+    fn limit(&mut self, path: &Path, value: &str) {
+        let current = match fs::read_to_string(path) {
+            Ok(current) => current.trim().to_string(),
+            Err(_) => return,
+        };
+        if current == value {
+            return;
+        }
+        let what = format!("write {}", path.display());
+        if report_once(&what, fs::write(path, value)).is_some() {
+            info!("Set {} to {value}, from {current}.", path.display());
+            if !self.saved_limits.iter().any(|(saved, _)| saved == path) {
+                self.saved_limits.push((path.to_path_buf(), current));
+            }
+        }
+    }
+
+    fn cap_brightness(&mut self, brightness_percent: f64) {
+        let path = match backlight_path() {
+            Some(path) => path,
+            None => return,
+        };
+        let (brightness, max_brightness) = match (
+            read_u64(&path.join("brightness")),
+            read_u64(&path.join("max_brightness")),
+        ) {
+            (Some(brightness), Some(max_brightness)) => (brightness, max_brightness),
+            _ => return,
+        };
+        let cap = (max_brightness as f64 * brightness_percent / 100.0).round() as u64;
+        if brightness <= cap {
+            return;
+        }
+
+        let brightness_path = path.join("brightness");
+        let what = format!("write {}", brightness_path.display());
+        if report_once(&what, fs::write(&brightness_path, cap.to_string())).is_some() {
+            info!("Capped brightness at {brightness_percent}%.");
+            if self.saved_brightness.is_none() {
+                self.saved_brightness = Some((path, brightness));
+            }
+        }
+    }
+
+    fn restore(&mut self) {
+        for (path, value) in self.saved_limits.drain(..) {
+            let what = format!("write {}", path.display());
+            if report_once(&what, fs::write(&path, &value)).is_some() {
+                info!("Restored {} to {value}.", path.display());
+            }
+        }
+
+        if let Some((path, brightness)) = self.saved_brightness.take() {
+            let brightness_path = path.join("brightness");
+            let what = format!("write {}", brightness_path.display());
+            if report_once(&what, fs::write(&brightness_path, brightness.to_string())).is_some() {
+                info!("Restored brightness.");
+            }
+        }
+    }
+}
@@ -0,0 +1,872 @@
+use crate::battery::BatteryReading;
+use crate::config::Config;
+use crate::pd;
+use crate::prediction::{RateHistory, LONG_WINDOW, SHORT_WINDOW};
+use crate::smoothing::Ema;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::time::Instant;
+
+/// Everything read from the system in one iteration of the main loop.
+pub struct Inputs<'a> {
+    pub reading: &'a BatteryReading,
+    pub pdcs: Option<u8>,
+    pub pdvl: Option<f64>,
+    pub pdam: Option<f64>,
+    /// The AC adapter's online file, only looked at without a PD
+    /// contract status.
+    pub ac_online: Option<&'a str>,
+    pub charge_limit_percent: f64,
+    pub now: Instant,
+}
+
+/// The values derived from Inputs.
+#[derive(Clone, Default)]
+pub struct Outputs {
+    pub ac_status: Option<&'static str>,
+    pub pd_wattage: Option<f64>,
+    pub pd_status: Option<String>,
+    pub charger_watts: Option<f64>,
+    pub charger_type: Option<&'static str>,
+    pub charger_class: Option<String>,
+    pub net_power_watts: Option<f64>,
+    pub net_drain: Option<bool>,
+    pub battery_percent: Option<f64>,
+    /// Whether battery_percent is "measured" from the charge, or
+    /// "estimated" from the voltage.
+    pub battery_percent_accuracy: Option<&'static str>,
+    pub battery_status: Option<&'static str>,
+    pub power_draw_watts_smoothed: Option<f64>,
+    pub secs_until_battery_full: Option<f64>,
+    pub secs_until_battery_empty: Option<f64>,
+    pub secs_until_shutdown_request: Option<f64>,
+    /// Pessimistic and optimistic, from the power draw averaged over
+    /// the last 30 seconds and 5 minutes.
+    pub secs_until_shutdown_request_min: Option<f64>,
+    pub secs_until_shutdown_request_max: Option<f64>,
+    pub low_battery_warning: Option<bool>,
+    pub battery_overheat: Option<bool>,
+}
+
+// A tier of charger wattage: chargers below below_watts, or any
+// charger for the last tier, which leaves it out.
+#[derive(Clone, Deserialize)]
+pub struct ChargerClass {
+    pub name: String,
+    pub below_watts: Option<f64>,
+}
+
+pub fn default_charger_classes() -> Vec<ChargerClass> {
+    let tier = |name: &str, below_watts| ChargerClass {
+        name: name.to_string(),
+        below_watts,
+    };
+    vec![
+        tier("trickle", Some(10.0)),
+        tier("slow", Some(30.0)),
+        tier("ok", Some(45.0)),
+        tier("fast", None),
+    ]
+}
+
+fn charger_class(classes: &[ChargerClass], watts: f64) -> Option<&str> {
+    classes
+        .iter()
+        .find(|class| {
+            class
+                .below_watts
+                .is_none_or(|below_watts| watts < below_watts)
+        })
+        .map(|class| class.name.as_str())
+}
+
+// Net power below this (in Watts) doesn't tell a direction.
+const NET_POWER_DEADBAND_WATTS: f64 = 0.1;
+
+// A battery_status guessed by the heuristics, and since when and from
+// which percentage on.
+struct Trend {
+    status: Option<&'static str>,
+    since: Instant,
+    battery_percent: Option<f64>,
+}
+
+/// What the calculations remember from one iteration to the next.
+pub struct PowerState {
+    prev_ac_status: Option<&'static str>,
+    prev_battery_percent: Option<f64>,
+    prev_battery_status: Option<&'static str>,
+    reported_battery_percent: Option<f64>,
+    trend: Option<Trend>,
+    // Whether the driver reports negative values when draining, as
+    // seen without the status saying so.
+    signed_net_power: bool,
+    // Smoothed power draw for time estimates, reset whenever AC is
+    // (dis)connected.
+    power_now_ema: Ema,
+    power_watts_ema: Ema,
+    net_power_ema: Ema,
+    rates: RateHistory,
+}
+
+impl PowerState {
+    pub fn new(power_smoothing_samples: f64) -> PowerState {
+        PowerState {
+            prev_ac_status: None,
+            prev_battery_percent: None,
+            prev_battery_status: None,
+            reported_battery_percent: None,
+            trend: None,
+            signed_net_power: false,
+            power_now_ema: Ema::new(power_smoothing_samples),
+            power_watts_ema: Ema::new(power_smoothing_samples),
+            net_power_ema: Ema::new(power_smoothing_samples),
+            rates: RateHistory::default(),
+        }
+    }
+
+    /// Start smoothing over again, with a possibly different number of
+    /// samples.
+    pub fn set_smoothing(&mut self, power_smoothing_samples: f64) {
+        self.power_now_ema = Ema::new(power_smoothing_samples);
+        self.power_watts_ema = Ema::new(power_smoothing_samples);
+        self.net_power_ema = Ema::new(power_smoothing_samples);
+    }
+
+    // Only change to a guessed battery_status once it has held for
+    // status_hysteresis_secs, or the percentage moved by
+    // status_hysteresis_percent in its direction, so that it doesn't
+    // flap while the charge hovers around a value.
+    fn follow_trend(
+        &mut self,
+        config: &Config,
+        guess: Option<&'static str>,
+        battery_percent: Option<f64>,
+        now: Instant,
+    ) -> Option<&'static str> {
+        if self.prev_battery_status.is_none() || self.prev_battery_status == guess {
+            self.trend = None;
+            return guess;
+        }
+
+        if self.trend.as_ref().map(|trend| trend.status) != Some(guess) {
+            self.trend = Some(Trend {
+                status: guess,
+                since: now,
+                battery_percent: self.prev_battery_percent.or(battery_percent),
+            });
+        }
+        let trend = self.trend.as_ref()?;
+
+        let held = now.saturating_duration_since(trend.since).as_secs_f64()
+            >= config.status_hysteresis_secs;
+        let moved = match (guess, battery_percent, trend.battery_percent) {
+            (Some("Charging"), Some(battery_percent), Some(start)) => {
+                battery_percent - start >= config.status_hysteresis_percent
+            }
+            (Some("Discharging"), Some(battery_percent), Some(start)) => {
+                start - battery_percent >= config.status_hysteresis_percent
+            }
+            _ => false,
+        };
+        if held || moved {
+            self.trend = None;
+            guess
+        } else {
+            self.prev_battery_status
+        }
+    }
+
+    /// Forget about the previous iterations, which might be hours old
+    /// after a resume.
+    pub fn reset(&mut self) {
+        self.prev_battery_percent = None;
+        self.prev_battery_status = None;
+        self.reported_battery_percent = None;
+        self.trend = None;
+        self.power_now_ema.reset();
+        self.power_watts_ema.reset();
+        self.net_power_ema.reset();
+        self.rates.clear();
+    }
+
+    pub fn compute(&mut self, config: &Config, inputs: &Inputs) -> Outputs {
+        let reading = inputs.reading;
+        let (pdcs, pdvl, pdam) = (inputs.pdcs, inputs.pdvl, inputs.pdam);
+        let charge_full = reading.charge_full;
+        let charge_now = reading.charge_now;
+
+        // Derive battery variables.
+        let charge_shutdown = charge_full.map(|charge_full| {
+            let rsbp = config.request_shutdown_battery_percent;
+            charge_full * (rsbp / 100.0)
+        });
+
+        // Calculate ac_status.
+        let ac_status = if let Some(pdcs) = pdcs {
+            if pd::is_connected(pdcs) && pd::is_sink(pdcs) {
+                let was_disconnected = self.prev_ac_status == Some("Disconnected");
+                let pd_power = match (pdvl, pdam) {
+                    (Some(pdvl), Some(pdam)) => pdvl * pdam, // Watts.
+                    _ => 0.0,
+                };
+
+                // Basically all power supplies get reported as low power for ~0.5 seconds
+                // after connecting, so ignore it on the first iteration after connecting.
+                if !was_disconnected && pd_power > 0.0 && pd_power < config.slow_charger_watts {
+                    Some("Connected slow")
+                } else {
+                    Some("Connected")
+                }
+            } else {
+                Some("Disconnected")
+            }
+        } else {
+            match inputs.ac_online {
+                Some("0") => Some("Disconnected"),
+                Some("1") => Some("Connected"),
+                None => match reading.status.as_deref() {
+                    Some("Full" | "Charging") => Some("Connected"),
+                    Some("Discharging") => Some("Disconnected"),
+                    _ => None,
+                },
+                _ => Some("Disconnected"),
+            }
+        };
+
+        // Describe the PD contract.
+        let pd_wattage = pdvl.zip(pdam).map(|(pdvl, pdam)| pdvl * pdam);
+        let pd_status = pdcs.map(|pdcs| pd::status(pdcs, pd_wattage));
+
+        // Calculate charger_watts and charger_type, from the PD
+        // contract when there is one.
+        let charger_watts = match (ac_status, pd_wattage) {
+            (Some("Connected" | "Connected slow"), Some(pd_wattage)) if pd_wattage > 0.0 => {
+                Some(pd_wattage)
+            }
+            _ => None,
+        };
+        let charger_type = match ac_status {
+            Some("Disconnected") => Some("none"),
+            Some("Connected slow") => Some("slow"),
+            Some(_) if charger_watts.is_some() => Some("PD"),
+            Some(_) => Some("unknown"),
+            None => None,
+        };
+        let charger_class = match (ac_status, charger_watts) {
+            (Some("Disconnected"), _) => Some("none"),
+            (Some(_), Some(charger_watts)) => charger_class(&config.charger_class, charger_watts),
+            (Some(_), None) => Some("unknown"),
+            (None, _) => None,
+        };
+
+        // Calculate net_drain: plugged in, but the charger can't keep up
+        // with the system, so the battery drains anyway.
+        let net_power_watts = reading.net_power_watts;
+        let net_drain = match (ac_status, net_power_watts) {
+            (Some("Disconnected"), _) => Some(false),
+            (Some(_), Some(net_power_watts)) => Some(net_power_watts < 0.0),
+            _ => None,
+        };
+
+        // Smooth power draw.
+        if ac_status != self.prev_ac_status {
+            self.power_now_ema.reset();
+            self.power_watts_ema.reset();
+            self.net_power_ema.reset();
+            self.rates.clear();
+        }
+        self.rates.push(inputs.now, reading.power_now);
+        let power_now = self.power_now_ema.update(reading.power_now);
+        let power_watts = self.power_watts_ema.update(reading.power_watts);
+        let net_power = self.net_power_ema.update(net_power_watts);
+        if reading.status.as_deref() != Some("Discharging") && net_power_watts < Some(0.0) {
+            self.signed_net_power = true;
+        }
+
+        // Calculate battery_percent, from the voltage if that's all
+        // there is.
+        let (battery_percent, battery_percent_accuracy) = match reading.battery_percent() {
+            Some(battery_percent) => (Some(battery_percent), Some("measured")),
+            None => match reading
+                .voltage_now
+                .and_then(|voltage_now| estimate_percent(&config.voltage_curve, voltage_now / 1e6))
+            {
+                Some(battery_percent) => (Some(battery_percent), Some("estimated")),
+                None => (None, None),
+            },
+        };
+        let battery_reached_maxchargelevel =
+            battery_percent > Some(inputs.charge_limit_percent - 0.51);
+
+        // Calculate battery_status.
+        let battery_status = match (ac_status, reading.status.as_deref()) {
+            (_, Some("Full")) => Some("Full"),
+            (_, Some("Discharging")) => Some("Discharging"),
+            // Connected to AC/Mains but Battery 'Not charging', whether "Max Charge Level" reached (represented as "Full") or not
+            (Some("Connected"), Some("Not charging")) => {
+                if battery_reached_maxchargelevel {
+                    Some("Full")
+                } else {
+                    Some("Not charging")
+                }
+            }
+            // Connected to AC/Mains and Battery 'Charging', whether "Max Charge Level" reached (="Full"), otherwise "Charging"
+            (Some("Connected"), Some("Charging")) => {
+                if battery_reached_maxchargelevel {
+                    Some("Full")
+                } else {
+                    Some("Charging")
+                }
+            }
+            _ => {
+                // Probably "Unknown" or "Not charging". Use heuristics as a fallback:
+                // the smoothed current when it has a sign, the percentage otherwise.
+                let ordering = match net_power {
+                    Some(net_power)
+                        if self.signed_net_power && net_power.abs() > NET_POWER_DEADBAND_WATTS =>
+                    {
+                        net_power.partial_cmp(&0.0)
+                    }
+                    _ => match (battery_percent, self.prev_battery_percent) {
+                        (Some(lhs), Some(rhs)) => lhs.partial_cmp(&rhs),
+                        _ => None,
+                    },
+                };
+                let guess = match ordering {
+                    Some(Ordering::Less) => Some("Discharging"),
+                    Some(Ordering::Greater) => Some("Charging"),
+                    _ => {
+                        if battery_percent.unwrap_or(0.0) >= 89.5 {
+                            // Some batteries won't charge when plugged in above ~90%.
+                            // We call this "Full".
+                            Some("Full")
+                        } else {
+                            None
+                        }
+                    }
+                };
+                self.follow_trend(config, guess, battery_percent, inputs.now)
+            }
+        };
+
+        // Keep the reported percentage from bouncing against the
+        // direction of (dis)charging, if enabled, starting over on
+        // every status change. The heuristics keep using the raw one.
+        let raw_battery_percent = battery_percent;
+        let battery_percent = match (battery_percent, self.reported_battery_percent) {
+            (Some(battery_percent), Some(reported))
+                if config.monotonic_battery_percent
+                    && battery_status == self.prev_battery_status =>
+            {
+                match battery_status {
+                    Some("Discharging") => Some(battery_percent.min(reported)),
+                    Some("Charging") => Some(battery_percent.max(reported)),
+                    _ => Some(battery_percent),
+                }
+            }
+            _ => battery_percent,
+        };
+
+        // Calculate secs_until_battery_full.
+        let vars = (charge_full, charge_now, power_now);
+        let secs_until_battery_full = match vars {
+            (Some(charge_full), Some(charge_now), Some(power_now)) => {
+                let charge_maxlevel = charge_full * (inputs.charge_limit_percent / 100.0);
+                let charge_delta = if charge_now < charge_maxlevel {
+                    charge_maxlevel - charge_now
+                } else {
+                    0.0
+                };
+                let hours = if charge_delta == 0.0 {
+                    Some(0.0)
+                } else {
+                    reading.hours_at(charge_delta, power_now)
+                };
+                hours.map(|hours| hours * 3600.0)
+            }
+            _ => None,
+        };
+
+        // Calculate secs_until_battery_empty.
+        let secs_until_battery_empty = match (charge_now, power_now) {
+            (Some(charge_now), Some(power_now)) => reading
+                .hours_at(charge_now, power_now)
+                .map(|hours| hours * 3600.0),
+            _ => None,
+        };
+
+        // Calcuate secs_until_shutdown_request. The power draw is only
+        // needed for the estimate: below the threshold, a reading that
+        // went missing or was rejected must not hold off the shutdown,
+        // and without the charge the voltage estimate has the last word.
+        let below_shutdown = match (charge_now, charge_shutdown) {
+            (Some(charge_now), Some(charge_shutdown)) => charge_now <= charge_shutdown,
+            _ => raw_battery_percent
+                .is_some_and(|percent| percent <= config.request_shutdown_battery_percent),
+        };
+        let secs_until_shutdown =
+            |power_now: Option<f64>| match (charge_now, charge_shutdown, power_now) {
+                _ if below_shutdown => match ac_status {
+                    // Avoid shutdown request while connected.
+                    Some("Connected") => Some(1.0),
+                    _ => Some(0.0),
+                },
+                (Some(charge_now), Some(charge_shutdown), Some(power_now)) => {
+                    let charge_delta = charge_now - charge_shutdown;
+                    reading
+                        .hours_at(charge_delta, power_now)
+                        .map(|hours| hours * 3600.0)
+                }
+                _ => None,
+            };
+        let secs_until_shutdown_request = secs_until_shutdown(power_now);
+
+        // Calculate the estimates from the averaged power draw.
+        let averages = [
+            self.rates.average(inputs.now, SHORT_WINDOW),
+            self.rates.average(inputs.now, LONG_WINDOW),
+        ];
+        let highest = averages.iter().flatten().copied().reduce(f64::max);
+        let lowest = averages.iter().flatten().copied().reduce(f64::min);
+        let secs_until_shutdown_request_min = secs_until_shutdown(highest);
+        let secs_until_shutdown_request_max = secs_until_shutdown(lowest);
+
+        // Calculate low_battery_warning, only while running on battery.
+        let low_battery_warning = match (config.warning_battery_percent, battery_percent) {
+            (Some(warning_battery_percent), Some(battery_percent)) => Some(
+                ac_status == Some("Disconnected") && battery_percent <= warning_battery_percent,
+            ),
+            _ => None,
+        };
+
+        // Calculate battery_overheat.
+        let battery_overheat = match (config.max_battery_temp_celsius, reading.temp_celsius) {
+            (Some(max_battery_temp_celsius), Some(battery_temp_celsius)) => {
+                Some(battery_temp_celsius > max_battery_temp_celsius)
+            }
+            _ => None,
+        };
+
+        // Keep for heuristics.
+        self.prev_ac_status = ac_status;
+        self.prev_battery_percent = raw_battery_percent;
+        self.reported_battery_percent = battery_percent;
+        self.prev_battery_status = battery_status;
+
+        Outputs {
+            ac_status,
+            pd_wattage,
+            pd_status,
+            charger_watts,
+            charger_type,
+            charger_class: charger_class.map(str::to_owned),
+            net_power_watts,
+            net_drain,
+            battery_percent,
+            battery_percent_accuracy,
+            battery_status,
+            power_draw_watts_smoothed: power_watts,
+            secs_until_battery_full,
+            secs_until_battery_empty,
+            secs_until_shutdown_request,
+            secs_until_shutdown_request_min,
+            secs_until_shutdown_request_max,
+            low_battery_warning,
+            battery_overheat,
+        }
+    }
+}
+
+// Interpolate the percentage from a discharge curve of (Volts,
+// percent) points sorted by voltage, clamping outside of it.
+fn estimate_percent(curve: &[(f64, f64)], volts: f64) -> Option<f64> {
+    let (first, last) = (curve.first()?, curve.last()?);
+    if volts <= first.0 {
+        return Some(first.1);
+    }
+    if volts >= last.0 {
+        return Some(last.1);
+    }
+    curve.windows(2).find_map(|points| {
+        let ((v0, p0), (v1, p1)) = (points[0], points[1]);
+        (volts >= v0 && volts <= v1 && v1 > v0).then(|| p0 + (volts - v0) / (v1 - v0) * (p1 - p0))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::Battery;
+    use crate::fixtures::{fixture_dir, FixtureCopy};
+    use crate::hwmon;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    // A recorded sysfs tree: power_supply devices, and the EC's hwmon
+    // device on Steam Decks.
+    struct Fixture {
+        reading: BatteryReading,
+        pdcs: Option<u8>,
+        pdvl: Option<f64>,
+        pdam: Option<f64>,
+        ac_online: Option<String>,
+        charge_limit_percent: f64,
+        now: Instant,
+    }
+
+    fn read_trimmed(path: &Path) -> Option<String> {
+        Some(fs::read_to_string(path).ok()?.trim().to_string())
+    }
+
+    impl Fixture {
+        fn load(name: &str) -> Fixture {
+            let dir = fixture_dir(name);
+            let mut power_supplies: Vec<PathBuf> = fs::read_dir(dir.join("power_supply"))
+                .unwrap()
+                .flatten()
+                .map(|entry| entry.path())
+                .collect();
+            power_supplies.sort();
+            let find = |ty: &str| {
+                power_supplies
+                    .iter()
+                    .find(|path| read_trimmed(&path.join("type")).as_deref() == Some(ty))
+                    .cloned()
+            };
+
+            let path_bat = find("Battery").expect("fixture without battery");
+            let reading = Battery::new(path_bat.clone()).read();
+            let ac_online = find("Mains").and_then(|path_ac| read_trimmed(&path_ac.join("online")));
+            let charge_limit_percent = read_trimmed(&path_bat.join("charge_control_end_threshold"))
+                .map_or(100.0, |val| val.parse().unwrap());
+
+            let hwmon_dir = dir.join("hwmon");
+            let (pdcs, pdvl, pdam) = if hwmon_dir.exists() {
+                let chip = hwmon::Chip::open("steamdeck_hwmon".to_string(), hwmon_dir.clone());
+                (
+                    read_trimmed(&hwmon_dir.join("pdcs")).map(|val| val.parse().unwrap()),
+                    chip.voltage().ok(),
+                    chip.current().ok(),
+                )
+            } else {
+                (None, None, None)
+            };
+
+            Fixture {
+                reading,
+                pdcs,
+                pdvl,
+                pdam,
+                ac_online,
+                charge_limit_percent,
+                now: Instant::now(),
+            }
+        }
+
+        fn inputs(&self) -> Inputs<'_> {
+            Inputs {
+                reading: &self.reading,
+                pdcs: self.pdcs,
+                pdvl: self.pdvl,
+                pdam: self.pdam,
+                ac_online: self.ac_online.as_deref(),
+                charge_limit_percent: self.charge_limit_percent,
+                now: self.now,
+            }
+        }
+
+        fn compute(&self) -> Outputs {
+            PowerState::new(1.0).compute(&Config::default(), &self.inputs())
+        }
+    }
+
+    fn assert_close(val: Option<f64>, expected: f64) {
+        let val = val.expect("no value");
+        assert!((val - expected).abs() < 0.1, "{val} != {expected}");
+    }
+
+    #[test]
+    fn steamdeck_discharging() {
+        let outputs = Fixture::load("steamdeck-discharging").compute();
+        assert_eq!(outputs.ac_status, Some("Disconnected"));
+        assert_eq!(outputs.battery_status, Some("Discharging"));
+        assert_eq!(outputs.battery_percent_accuracy, Some("measured"));
+        assert_eq!(outputs.charger_type, Some("none"));
+        assert_eq!(outputs.pd_status.as_deref(), Some("disconnected"));
+        assert_eq!(outputs.net_drain, Some(false));
+        assert_close(outputs.battery_percent, 50.0);
+        assert_close(outputs.net_power_watts, -11.7);
+        assert_close(outputs.secs_until_battery_empty, 5923.1);
+    }
+
+    #[test]
+    fn steamdeck_without_power_draw() {
+        // current_now went away: no estimates, but everything else.
+        let mut fixture = Fixture::load("steamdeck-discharging");
+        fixture.reading.power_now = None;
+        fixture.reading.power_watts = None;
+        fixture.reading.net_power_watts = None;
+        let outputs = fixture.compute();
+        assert_eq!(outputs.battery_status, Some("Discharging"));
+        assert_close(outputs.battery_percent, 50.0);
+        assert_eq!(outputs.secs_until_battery_empty, None);
+        assert_eq!(outputs.secs_until_shutdown_request, None);
+
+        // Below the threshold, the shutdown is still requested.
+        fixture.reading.charge_now = fixture.reading.charge_full.map(|full| full * 0.002);
+        let outputs = fixture.compute();
+        assert_eq!(outputs.secs_until_shutdown_request, Some(0.0));
+    }
+
+    #[test]
+    fn rejected_readings_keep_the_shutdown() {
+        // A driver reporting nonsense near empty: voltage_now and
+        // current_now are rejected, which leaves no power draw.
+        let copy = FixtureCopy::new("steamdeck-discharging", "power_supply/BAT1");
+        fs::write(copy.path.join("voltage_now"), "0\n").unwrap();
+        fs::write(copy.path.join("current_now"), "-99000000\n").unwrap();
+        fs::write(copy.path.join("charge_now"), "10000\n").unwrap();
+        let mut fixture = Fixture::load("steamdeck-discharging");
+        fixture.reading = Battery::new(copy.path.clone()).read();
+        assert_eq!(fixture.reading.voltage_now, None);
+        assert_eq!(fixture.reading.power_now, None);
+        let outputs = fixture.compute();
+        assert_eq!(outputs.secs_until_shutdown_request, Some(0.0));
+    }
+
+    #[test]
+    fn steamdeck_charging() {
+        let outputs = Fixture::load("steamdeck-charging").compute();
+        assert_eq!(outputs.ac_status, Some("Connected"));
+        assert_eq!(outputs.battery_status, Some("Charging"));
+        assert_eq!(outputs.charger_type, Some("PD"));
+        assert_eq!(
+            outputs.pd_status.as_deref(),
+            Some("connected,sink,contract")
+        );
+        assert_close(outputs.charger_watts, 45.0);
+        assert_close(outputs.battery_percent, 80.0);
+        assert_close(outputs.secs_until_battery_full, 1776.9);
+        assert_eq!(
+            outputs.secs_until_shutdown_request.map(|x| x > 0.0),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn steamdeck_slow_charger() {
+        // Chargers look slow right after connecting, so it only counts
+        // from the second iteration on.
+        let fixture = Fixture::load("steamdeck-charging");
+        let config = Config {
+            slow_charger_watts: 60.0,
+            ..Config::default()
+        };
+        let mut power_state = PowerState::new(1.0);
+        let mut inputs = fixture.inputs();
+        inputs.pdcs = Some(0);
+        let outputs = power_state.compute(&config, &inputs);
+        assert_eq!(outputs.ac_status, Some("Disconnected"));
+
+        let outputs = power_state.compute(&config, &fixture.inputs());
+        assert_eq!(outputs.ac_status, Some("Connected"));
+        let outputs = power_state.compute(&config, &fixture.inputs());
+        assert_eq!(outputs.ac_status, Some("Connected slow"));
+        assert_eq!(outputs.charger_type, Some("slow"));
+        assert_eq!(outputs.charger_class.as_deref(), Some("fast"));
+    }
+
+    #[test]
+    fn thinkpad_at_charge_limit() {
+        let outputs = Fixture::load("thinkpad").compute();
+        assert_eq!(outputs.ac_status, Some("Connected"));
+        assert_eq!(outputs.battery_status, Some("Full"));
+        assert_eq!(outputs.charger_type, Some("unknown"));
+        assert_eq!(outputs.charger_class.as_deref(), Some("unknown"));
+        assert_eq!(outputs.pd_status, None);
+        assert_close(outputs.battery_percent, 80.0);
+        assert_close(outputs.secs_until_battery_full, 0.0);
+    }
+
+    #[test]
+    fn thinkpad_discharging_energy_units() {
+        // 40 Wh left at 10 W, without the voltage getting in the way.
+        let mut fixture = Fixture::load("thinkpad");
+        fixture.ac_online = Some("0".to_string());
+        fixture.reading.status = Some("Discharging".to_string());
+        fixture.reading.power_now = Some(10.0 * 1e12);
+        let outputs = fixture.compute();
+        assert_eq!(outputs.battery_status, Some("Discharging"));
+        assert_close(outputs.secs_until_battery_empty, 4.0 * 3600.0);
+    }
+
+    #[test]
+    fn ally_charging() {
+        let outputs = Fixture::load("ally").compute();
+        assert_eq!(outputs.ac_status, Some("Connected"));
+        assert_eq!(outputs.battery_status, Some("Charging"));
+        assert_eq!(outputs.net_drain, Some(false));
+        assert_close(outputs.battery_percent, 50.0);
+        assert_close(outputs.net_power_watts, 32.0);
+        assert_close(outputs.secs_until_battery_full, 2246.5);
+    }
+
+    #[test]
+    fn ally_unknown_status_uses_trend() {
+        let mut fixture = Fixture::load("ally");
+        fixture.ac_online = None;
+        fixture.reading.status = Some("Unknown".to_string());
+        let config = Config::default();
+        let mut power_state = PowerState::new(1.0);
+
+        let outputs = power_state.compute(&config, &fixture.inputs());
+        assert_eq!(outputs.ac_status, None);
+        assert_eq!(outputs.battery_status, None);
+
+        fixture.reading.charge_now = fixture.reading.charge_now.map(|x| x - 10000.0);
+        let outputs = power_state.compute(&config, &fixture.inputs());
+        assert_eq!(outputs.battery_status, Some("Discharging"));
+    }
+
+    #[test]
+    fn status_hysteresis_percent_trend() {
+        let mut fixture = Fixture::load("ally");
+        fixture.ac_online = None;
+        fixture.reading.status = Some("Unknown".to_string());
+        let config = Config::default();
+        let mut power_state = PowerState::new(1.0);
+
+        power_state.compute(&config, &fixture.inputs());
+        fixture.reading.charge_now = Some(1280000.0);
+        let outputs = power_state.compute(&config, &fixture.inputs());
+        assert_eq!(outputs.battery_status, Some("Discharging"));
+
+        // Going back up a little doesn't count yet.
+        fixture.reading.charge_now = Some(1282580.0);
+        fixture.now += Duration::from_secs(1);
+        let outputs = power_state.compute(&config, &fixture.inputs());
+        assert_eq!(outputs.battery_status, Some("Discharging"));
+
+        // But it does once it keeps going up for long enough.
+        fixture.reading.charge_now = Some(1285160.0);
+        fixture.now += Duration::from_secs(30);
+        let outputs = power_state.compute(&config, &fixture.inputs());
+        assert_eq!(outputs.battery_status, Some("Charging"));
+    }
+
+    #[test]
+    fn status_hysteresis_signed_current() {
+        let mut fixture = Fixture::load("steamdeck-discharging");
+        fixture.reading.status = Some("Unknown".to_string());
+        let config = Config::default();
+        let mut power_state = PowerState::new(1.0);
+
+        let outputs = power_state.compute(&config, &fixture.inputs());
+        assert_eq!(outputs.battery_status, Some("Discharging"));
+
+        // The current turning around isn't enough on its own...
+        fixture.reading.net_power_watts = Some(5.0);
+        fixture.now += Duration::from_secs(1);
+        let outputs = power_state.compute(&config, &fixture.inputs());
+        assert_eq!(outputs.battery_status, Some("Discharging"));
+
+        // ...until the charge follows.
+        fixture.reading.charge_now = Some(2525000.0);
+        fixture.now += Duration::from_secs(1);
+        let outputs = power_state.compute(&config, &fixture.inputs());
+        assert_eq!(outputs.battery_status, Some("Charging"));
+    }
+
+    #[test]
+    fn monotonic_battery_percent() {
+        let mut fixture = Fixture::load("steamdeck-discharging");
+        let config = Config {
+            monotonic_battery_percent: true,
+            ..Config::default()
+        };
+        let mut power_state = PowerState::new(1.0);
+        power_state.compute(&config, &fixture.inputs());
+
+        // Jumping up while discharging is held back...
+        fixture.reading.charge_now = Some(2550000.0);
+        let outputs = power_state.compute(&config, &fixture.inputs());
+        assert_close(outputs.battery_percent, 50.0);
+        fixture.reading.charge_now = Some(2450000.0);
+        let outputs = power_state.compute(&config, &fixture.inputs());
+        assert_close(outputs.battery_percent, 49.0);
+
+        // ...but not across a status change.
+        fixture.pdcs = Some(1);
+        fixture.reading.status = Some("Charging".to_string());
+        fixture.reading.charge_now = Some(2400000.0);
+        let outputs = power_state.compute(&config, &fixture.inputs());
+        assert_eq!(outputs.battery_status, Some("Charging"));
+        assert_close(outputs.battery_percent, 48.0);
+    }
+
+    #[test]
+    fn voltage_curve_estimate() {
+        let mut fixture = Fixture::load("steamdeck-discharging");
+        fixture.reading.charge_now = None;
+        let outputs = fixture.compute();
+        assert_eq!(outputs.battery_percent, None);
+        assert_eq!(outputs.battery_percent_accuracy, None);
+
+        let config = Config {
+            voltage_curve: vec![(6.0, 0.0), (7.4, 20.0), (8.4, 100.0)],
+            ..Config::default()
+        };
+        let outputs = PowerState::new(1.0).compute(&config, &fixture.inputs());
+        assert_close(outputs.battery_percent, 52.0);
+        assert_eq!(outputs.battery_percent_accuracy, Some("estimated"));
+
+        fixture.reading.voltage_now = Some(5000000.0);
+        let outputs = PowerState::new(1.0).compute(&config, &fixture.inputs());
+        assert_close(outputs.battery_percent, 0.0);
+        assert_eq!(outputs.secs_until_shutdown_request, Some(0.0));
+    }
+
+    #[test]
+    fn shutdown_request_min_max() {
+        let mut fixture = Fixture::load("steamdeck-discharging");
+        let config = Config::default();
+        let mut power_state = PowerState::new(1.0);
+
+        // A minute at the fixture's 11.7 W, then a burst at twice that.
+        let power_now = fixture.reading.power_now.unwrap();
+        for _ in 0..60 {
+            power_state.compute(&config, &fixture.inputs());
+            fixture.now += Duration::from_secs(1);
+        }
+        fixture.reading.power_now = Some(power_now * 2.0);
+        for _ in 0..30 {
+            power_state.compute(&config, &fixture.inputs());
+            fixture.now += Duration::from_secs(1);
+        }
+        let outputs = power_state.compute(&config, &fixture.inputs());
+
+        let min = outputs.secs_until_shutdown_request_min.unwrap();
+        let max = outputs.secs_until_shutdown_request_max.unwrap();
+        let now = outputs.secs_until_shutdown_request.unwrap();
+        assert!(min < max, "{min} >= {max}");
+        assert_close(Some(min), now);
+        assert!(max > now * 1.2, "{max} <= {now} * 1.2");
+    }
+
+    #[test]
+    fn low_battery_warning_only_on_battery() {
+        let config = Config {
+            warning_battery_percent: Some(60.0),
+            ..Config::default()
+        };
+        let fixture = Fixture::load("steamdeck-discharging");
+        let outputs = PowerState::new(1.0).compute(&config, &fixture.inputs());
+        assert_eq!(outputs.low_battery_warning, Some(true));
+
+        let fixture = Fixture::load("ally");
+        let outputs = PowerState::new(1.0).compute(&config, &fixture.inputs());
+        assert_eq!(outputs.low_battery_warning, Some(false));
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{notify, report_once};
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::fmt::Write as _;
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+fn default_timeout_secs() -> f64 {
+    30.0
+}
+
+// A command to run before the critical action, like flushing game
+// saves, stopping services or syncing.
+#[derive(Clone, Deserialize)]
+pub struct PreShutdownCommand {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: f64,
+}
+
+// Run the command, killing it once it's out of time.
+fn run_one(pre_shutdown: &PreShutdownCommand) -> String {
+    let command = &pre_shutdown.command;
+    let start = Instant::now();
+    let timeout = Duration::from_secs_f64(pre_shutdown.timeout_secs.max(0.0));
+    let mut child = match Command::new(command).args(&pre_shutdown.args).spawn() {
+        Err(err) => return format!("failed to start: {err}"),
+        Ok(child) => child,
+    };
+
+    loop {
+        match child.try_wait() {
+            Err(err) => return format!("failed: {err}"),
+            Ok(Some(status)) if status.success() => {
+                return format!("completed in {:.1}s", start.elapsed().as_secs_f64())
+            }
+            Ok(Some(status)) => {
+                return format!(
+                    "failed ({status}) after {:.1}s",
+                    start.elapsed().as_secs_f64()
+                )
+            }
+            Ok(None) if start.elapsed() >= timeout => {
+                if let Err(err) = child.kill() {
+                    error!("kill {command}: {err}");
+                }
+                let _ = child.wait();
+                return format!("timed out after {:.1}s", timeout.as_secs_f64());
+            }
+            Ok(None) => {
+                report_once("sd_notify", notify::notify("WATCHDOG=1"));
+                thread::sleep(CHECK_INTERVAL);
+            }
+        }
+    }
+}
+
+// Run the commands one after the other, whatever happens to each, and
+// write down how they went to report_path.
+pub fn run(commands: &[PreShutdownCommand], report_path: &str) {
+    let mut report = String::new();
+    for pre_shutdown in commands {
+        info!("Running pre-shutdown command {}.", pre_shutdown.command);
+        let result = run_one(pre_shutdown);
+        if result.starts_with("completed") {
+            info!("{}: {result}", pre_shutdown.command);
+        } else {
+            warn!("{}: {result}", pre_shutdown.command);
+        }
+        let _ = writeln!(report, "{}: {result}", pre_shutdown.command);
+
+        // Written after every command, in case the battery doesn't
+        // last until the end.
+        if let Err(err) = fs::write(report_path, &report) {
+            error!("write {report_path}: {err}");
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Windows to average the power draw over: one following bursts, one
+// smoothing them out.
+pub const SHORT_WINDOW: Duration = Duration::from_secs(30);
+pub const LONG_WINDOW: Duration = Duration::from_secs(300);
+
+// Recent power draw samples, for estimates that hold up under bursty
+// loads better than the latest sample does.
+#[derive(Default)]
+pub struct RateHistory {
+    samples: VecDeque<(Instant, f64)>,
+}
+
+impl RateHistory {
+    pub fn push(&mut self, now: Instant, power: Option<f64>) {
+        if let Some(power) = power {
+            self.samples.push_back((now, power));
+        }
+        while self
+            .samples
+            .front()
+            .is_some_and(|(time, _)| now.saturating_duration_since(*time) > LONG_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    // The average over the window, each sample weighted by the time
+    // since the one before, as sampling speeds up with uevents.
+    pub fn average(&self, now: Instant, window: Duration) -> Option<f64> {
+        let start = self
+            .samples
+            .iter()
+            .position(|(time, _)| now.saturating_duration_since(*time) <= window)?;
+        let samples: Vec<&(Instant, f64)> = self.samples.range(start..).collect();
+        if samples.len() == 1 {
+            return Some(samples[0].1);
+        }
+
+        let (mut total, mut weights) = (0.0, 0.0);
+        for pair in samples.windows(2) {
+            let weight = pair[1].0.saturating_duration_since(pair[0].0).as_secs_f64();
+            total += pair[1].1 * weight;
+            weights += weight;
+        }
+        if weights > 0.0 {
+            Some(total / weights)
+        } else {
+            samples.last().map(|(_, power)| *power)
+        }
+    }
+}
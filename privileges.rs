@@ -0,0 +1,105 @@
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+// Whether vpower still runs as root, and so can run the shutdown
+// commands and write any sysfs knob itself.
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+// name in dir, without following symlinks, or None for what can't be
+// opened that way, like symlinks and sockets.
+fn open_at(dir: libc::c_int, name: &CStr) -> io::Result<Option<OwnedFd>> {
+    let flags = libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_NOCTTY;
+    match check(unsafe { libc::openat(dir, name.as_ptr(), flags | libc::O_CLOEXEC) }) {
+        Ok(fd) => Ok(Some(unsafe { OwnedFd::from_raw_fd(fd) })),
+        Err(err) if matches!(err.raw_os_error(), Some(libc::ELOOP | libc::ENXIO)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn entries(dir: &OwnedFd) -> io::Result<Vec<CString>> {
+    let fd = check(unsafe { libc::dup(dir.as_raw_fd()) })?;
+    let stream = unsafe { libc::fdopendir(fd) };
+    if stream.is_null() {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(err);
+    }
+    let mut names = Vec::new();
+    loop {
+        let entry = unsafe { libc::readdir(stream) };
+        if entry.is_null() {
+            break;
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        if name != c"." && name != c".." {
+            names.push(name.to_owned());
+        }
+    }
+    unsafe { libc::closedir(stream) };
+    Ok(names)
+}
+
+// Hand what's in the dir to uid and gid, through file descriptors so
+// that the unprivileged user, who owns it from the last start on,
+// can't have root follow a symlink or hard link it swapped in to a
+// file anywhere else.
+fn chown_all(fd: &OwnedFd, uid: u32, gid: u32) -> io::Result<()> {
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    check(unsafe { libc::fstat(fd.as_raw_fd(), stat.as_mut_ptr()) })?;
+    let stat = unsafe { stat.assume_init() };
+    match stat.st_mode & libc::S_IFMT {
+        libc::S_IFDIR => {
+            check(unsafe { libc::fchown(fd.as_raw_fd(), uid, gid) })?;
+            for name in entries(fd)? {
+                if let Some(entry) = open_at(fd.as_raw_fd(), &name)? {
+                    chown_all(&entry, uid, gid)?;
+                }
+            }
+        }
+        // A file linked from elsewhere too might be any file.
+        _ if stat.st_nlink > 1 => {}
+        _ => {
+            check(unsafe { libc::fchown(fd.as_raw_fd(), uid, gid) })?;
+        }
+    }
+    Ok(())
+}
+
+// Switch to user for good, after handing it dirs, created if missing,
+// so that it can keep writing there.
+pub fn drop_to(user: &str, dirs: &[&str]) -> io::Result<()> {
+    let name = CString::new(user).map_err(io::Error::other)?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no such user"));
+    }
+    let (uid, gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+
+    for dir in dirs {
+        fs::create_dir_all(dir)?;
+        let path = CString::new(*dir).map_err(io::Error::other)?;
+        if let Some(fd) = open_at(libc::AT_FDCWD, &path)? {
+            chown_all(&fd, uid, gid)?;
+        }
+    }
+
+    // The group first, as that takes root.
+    unsafe {
+        if libc::setgroups(1, &gid) != 0 || libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
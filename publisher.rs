@@ -0,0 +1,216 @@
+use crate::config::Config;
+use crate::history::History;
+use crate::monitor::panic_message;
+use crate::mqtt::{MqttConfig, MqttPublisher};
+use crate::snapshot::Snapshot;
+use crate::telemetry::{Telemetry, TelemetryConfig};
+use log::{error, warn};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// How many samples may wait for the publishers before new ones get
+// dropped.
+const QUEUE_LEN: usize = 4;
+
+// The configuration of the sinks, to tell which changed on reload.
+#[derive(Clone, PartialEq)]
+pub struct Sinks {
+    // interval_secs and retention_days.
+    history: Option<(f64, f64)>,
+    mqtt: Option<MqttConfig>,
+    telemetry: Option<TelemetryConfig>,
+}
+
+impl Sinks {
+    pub fn new(config: &Config) -> Sinks {
+        Sinks {
+            history: config
+                .history
+                .then_some((config.history_interval_secs, config.history_retention_days)),
+            mqtt: config.mqtt.clone(),
+            telemetry: config.telemetry.clone(),
+        }
+    }
+}
+
+enum Message {
+    Sample {
+        snapshot: Box<Snapshot>,
+        json: String,
+    },
+    // The configuration in the slot changed.
+    Configure,
+    Flush,
+}
+
+// The latest configuration, for the thread to pick up before its next
+// message, so that a reload never waits for room in the queue.
+type Slot = Arc<Mutex<Option<Sinks>>>;
+
+fn take(slot: &Slot) -> Option<Sinks> {
+    slot.lock().unwrap_or_else(|err| err.into_inner()).take()
+}
+
+// What the thread owns.
+#[derive(Default)]
+struct Outputs {
+    sinks: Option<Sinks>,
+    history: Option<History>,
+    mqtt: Option<MqttPublisher>,
+    telemetry: Option<Telemetry>,
+}
+
+impl Outputs {
+    // Start over the sinks whose configuration changed.
+    fn configure(&mut self, sinks: Sinks) {
+        let prev = self.sinks.take();
+        if prev.as_ref().map(|prev| &prev.history) != Some(&sinks.history) {
+            self.history = sinks
+                .history
+                .map(|(interval_secs, retention_days)| History::new(interval_secs, retention_days));
+        }
+        if prev.as_ref().map(|prev| &prev.mqtt) != Some(&sinks.mqtt) {
+            // Disconnect before connecting again.
+            self.mqtt = None;
+            self.mqtt = sinks
+                .mqtt
+                .as_ref()
+                .and_then(|mqtt| match MqttPublisher::new(mqtt) {
+                    Err(err) => {
+                        error!("mqtt: {err}");
+                        None
+                    }
+                    Ok(publisher) => Some(publisher),
+                });
+        }
+        if prev.as_ref().map(|prev| &prev.telemetry) != Some(&sinks.telemetry) {
+            self.telemetry = sinks.telemetry.as_ref().map(Telemetry::new);
+        }
+        self.sinks = Some(sinks);
+    }
+
+    fn publish(&mut self, snapshot: &Snapshot, json: &str) {
+        if let Some(mqtt) = &mut self.mqtt {
+            mqtt.publish(snapshot);
+        }
+        if let Some(telemetry) = &mut self.telemetry {
+            telemetry.record(json);
+        }
+        if let Some(history) = &mut self.history {
+            history.record(snapshot);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.flush();
+        }
+    }
+}
+
+fn run(receiver: Receiver<Message>, slot: Slot) {
+    let mut outputs = Outputs::default();
+    for message in receiver {
+        // A sink failing unexpectedly shouldn't take the others along.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            if let Some(sinks) = take(&slot) {
+                outputs.configure(sinks);
+            }
+            match message {
+                Message::Sample { snapshot, json } => outputs.publish(&snapshot, &json),
+                Message::Configure => {}
+                Message::Flush => outputs.flush(),
+            }
+        }));
+        if let Err(payload) = result {
+            error!("Publishing failed: {}", panic_message(&*payload));
+        }
+    }
+}
+
+// Hands the samples to the sinks that can be slow, like MQTT over a bad
+// connection or telemetry on a slow disk, in a thread of their own, so
+// that they never hold up the main loop. When they fall behind, samples
+// are dropped rather than queued up.
+pub struct Publisher {
+    sender: Option<SyncSender<Message>>,
+    slot: Slot,
+    thread: Option<JoinHandle<()>>,
+    behind: bool,
+}
+
+impl Publisher {
+    pub fn new(sinks: Sinks) -> Publisher {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+        let slot = Slot::default();
+        let thread = {
+            let slot = slot.clone();
+            thread::spawn(move || run(receiver, slot))
+        };
+        let publisher = Publisher {
+            sender: Some(sender),
+            slot,
+            thread: Some(thread),
+            behind: false,
+        };
+        publisher.configure(sinks);
+        publisher
+    }
+
+    // Waiting for room in the queue.
+    fn send(&self, message: Message) {
+        if let Some(sender) = &self.sender {
+            if sender.send(message).is_err() {
+                error!("The publisher thread is gone.");
+            }
+        }
+    }
+
+    // Replaces whatever configuration the thread hasn't picked up yet.
+    // With the queue full, the thread picks it up before the samples
+    // already queued, without being woken up.
+    pub fn configure(&self, sinks: Sinks) {
+        *self.slot.lock().unwrap_or_else(|err| err.into_inner()) = Some(sinks);
+        if let Some(sender) = &self.sender {
+            if let Err(TrySendError::Disconnected(_)) = sender.try_send(Message::Configure) {
+                error!("The publisher thread is gone.");
+            }
+        }
+    }
+
+    pub fn publish(&mut self, snapshot: &Snapshot, json: &str) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let message = Message::Sample {
+            snapshot: Box::new(snapshot.clone()),
+            json: json.to_owned(),
+        };
+        match sender.try_send(message) {
+            Ok(()) => self.behind = false,
+            Err(TrySendError::Full(_)) => {
+                if !self.behind {
+                    warn!("Publishing is falling behind, dropping samples.");
+                }
+                self.behind = true;
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!("The publisher thread is gone.");
+                self.sender = None;
+            }
+        }
+    }
+
+    // Publish what's queued, flush the history and stop the sinks.
+    pub fn stop(&mut self) {
+        self.send(Message::Flush);
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("The publisher thread panicked.");
+            }
+        }
+    }
+}
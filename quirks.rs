@@ -0,0 +1,442 @@
+use crate::battery::BatteryReading;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::platform_info::read_ec_attribute;
+use crate::sensors::matches;
+use crate::sysfs;
+use crate::units::Scales;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+
+pub const QUIRKS_DIR: &str = "/etc/vpower/quirks.d";
+
+const PRODUCT_NAME_PATH: &str = "/sys/class/dmi/id/product_name";
+const BOARD_NAME_PATH: &str = "/sys/class/dmi/id/board_name";
+const BIOS_VERSION_PATH: &str = "/sys/class/dmi/id/bios_version";
+const HWMON_DIR: &str = "/sys/class/hwmon";
+
+// Per-device adjustments, in the same format as the files in
+// QUIRKS_DIR, which come after these and so take precedence.
+const BUILTIN: &str = r#"
+# Steam Deck LCD and OLED. The OLED's 50 Wh battery (40 Wh on the
+# LCD) takes longer to fill from a weak charger, so it counts chargers
+# as slow below a higher wattage.
+[[quirk]]
+product_names = ["Jupiter"]
+board_names = ["Jupiter"]
+model = "Steam Deck LCD"
+sensors_chip = "steamdeck_hwmon"
+slow_charger_watts = 30
+
+[[quirk]]
+product_names = ["Galileo"]
+board_names = ["Galileo"]
+model = "Steam Deck OLED"
+sensors_chip = "steamdeck_hwmon"
+slow_charger_watts = 40
+
+# ASUS ROG Ally and Ally X, and Lenovo Legion Go (83E1): their ECs
+# don't expose the PD contract, but UCSI describes the charger.
+[[quirk]]
+product_names = ["ROG Ally*", "83E1"]
+charger_supply = "ucsi-source-psy-*"
+"#;
+
+// Values that can be ignored, as if the device didn't have them.
+const ATTRIBUTES: [&str; 8] = [
+    "pdcs",
+    "pdvl",
+    "pdam",
+    "charge_full_design",
+    "cycle_count",
+    "temp",
+    "voltage_min_design",
+    "status",
+];
+
+#[derive(Deserialize)]
+struct QuirksFile {
+    #[serde(default)]
+    quirk: Vec<Quirk>,
+}
+
+// Adjustments for the devices whose DMI product name matches one of
+// product_names, or board name one of board_names, with wildcards.
+#[derive(Clone, Default, Deserialize)]
+struct Quirk {
+    #[serde(default)]
+    product_names: Vec<String>,
+    #[serde(default)]
+    board_names: Vec<String>,
+    // What to call the device, published as device_model.
+    model: Option<String>,
+    // Only for these EC firmware versions, inclusive, as in
+    // <output_dir>/platform/firmware_version, like 45096.
+    firmware_min: Option<u64>,
+    firmware_max: Option<u64>,
+    // Only for these BIOS versions, inclusive: the last number in the
+    // DMI BIOS version, like 131 for F7A0131.
+    bios_min: Option<u64>,
+    bios_max: Option<u64>,
+    // Defaults for the options of the same name.
+    sensors_chip: Option<String>,
+    charger_supply: Option<String>,
+    slow_charger_watts: Option<f64>,
+    // Multiply readings by these.
+    pdvl_scale: Option<f64>,
+    pdam_scale: Option<f64>,
+    power_scale: Option<f64>,
+    // Multiply the battery's voltage_*, current_now, and charge_* or
+    // energy_* attributes by these, before checking them.
+    voltage_scale: Option<f64>,
+    current_scale: Option<f64>,
+    charge_scale: Option<f64>,
+    // Whether current_now and power_now are positive while
+    // discharging, instead of learning it from the status.
+    positive_discharge_current: Option<bool>,
+    // Attributes to ignore, from ATTRIBUTES.
+    #[serde(default)]
+    ignore: Vec<String>,
+}
+
+// All quirks matching this device, merged in order.
+#[derive(Clone)]
+pub struct Quirks {
+    pub product_name: Option<String>,
+    pub board_name: Option<String>,
+    pub bios_version: Option<String>,
+    // The EC's, on the devices with an EC driver that has one.
+    pub firmware_version: Option<String>,
+    pub model: Option<String>,
+    sensors_chip: Option<String>,
+    charger_supply: Option<String>,
+    slow_charger_watts: Option<f64>,
+    pdvl_scale: f64,
+    pdam_scale: f64,
+    power_scale: f64,
+    scales: Scales,
+    positive_discharge_current: Option<bool>,
+    ignore: Vec<String>,
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            product_name: None,
+            board_name: None,
+            bios_version: None,
+            firmware_version: None,
+            model: None,
+            sensors_chip: None,
+            charger_supply: None,
+            slow_charger_watts: None,
+            pdvl_scale: 1.0,
+            pdam_scale: 1.0,
+            power_scale: 1.0,
+            scales: Scales::default(),
+            positive_discharge_current: None,
+            ignore: Vec::new(),
+        }
+    }
+}
+
+fn parse(what: &str, string: &str) -> Vec<Quirk> {
+    match toml::from_str::<QuirksFile>(string) {
+        Err(err) => {
+            error!("read {what}: {err}");
+            Vec::new()
+        }
+        Ok(file) => file.quirk,
+    }
+}
+
+// The files in QUIRKS_DIR, in name order.
+fn quirk_files() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(QUIRKS_DIR) {
+        Err(_) => Vec::new(),
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+            .collect(),
+    };
+    paths.sort();
+    paths
+}
+
+// The built-in quirks, then those in QUIRKS_DIR in file name order.
+fn all_quirks() -> Vec<Quirk> {
+    let mut quirks = parse("built-in quirks", BUILTIN);
+    for path in quirk_files() {
+        match fs::read_to_string(&path) {
+            Err(err) => error!("read {}: {err}", path.display()),
+            Ok(string) => quirks.extend(parse(&path.display().to_string(), &string)),
+        }
+    }
+    quirks
+}
+
+// Whether all the files in QUIRKS_DIR can be read, before applying
+// them.
+pub fn validate() -> Result<()> {
+    for path in quirk_files() {
+        fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|string| toml::from_str::<QuirksFile>(&string).map_err(|err| err.to_string()))
+            .map_err(|message| Error::Config {
+                path: path.display().to_string(),
+                message,
+            })?;
+    }
+    Ok(())
+}
+
+// The last number in a version, which goes up with each release on
+// the devices that need firmware specific quirks.
+fn last_number(version: &str) -> Option<u64> {
+    let digits = version.trim_end_matches(|c: char| !c.is_ascii_digit());
+    let start = digits
+        .rfind(|c: char| !c.is_ascii_digit())
+        .map_or(0, |i| i + 1);
+    digits[start..].parse().ok()
+}
+
+// Whether version is within min and max, inclusive, if either is set.
+fn in_range(version: Option<u64>, min: Option<u64>, max: Option<u64>) -> bool {
+    if min.is_none() && max.is_none() {
+        return true;
+    }
+    version.is_some_and(|version| {
+        min.is_none_or(|min| version >= min) && max.is_none_or(|max| version <= max)
+    })
+}
+
+// The firmware_version of the EC driver, found on its hwmon device
+// before the sensors are set up, as the quirks pick the chip.
+fn ec_firmware_version() -> Option<String> {
+    let mut chips: Vec<PathBuf> = fs::read_dir(sysfs(HWMON_DIR))
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    chips.sort();
+    chips
+        .iter()
+        .find_map(|chip| read_ec_attribute(chip, "firmware_version"))
+}
+
+impl Quirks {
+    // Find the quirks for this device.
+    pub fn load() -> Quirks {
+        let read_name = |path| {
+            fs::read_to_string(sysfs(path))
+                .ok()
+                .map(|name| name.trim().to_string())
+        };
+        let device = Quirks {
+            product_name: read_name(PRODUCT_NAME_PATH),
+            board_name: read_name(BOARD_NAME_PATH),
+            bios_version: read_name(BIOS_VERSION_PATH),
+            firmware_version: ec_firmware_version(),
+            ..Quirks::default()
+        };
+        device.merge(all_quirks())
+    }
+
+    // Apply the quirks matching the device described by self.
+    fn merge(self, quirks: Vec<Quirk>) -> Quirks {
+        let mut merged = self;
+        let firmware_version = merged.firmware_version.as_deref().and_then(last_number);
+        let bios_version = merged.bios_version.as_deref().and_then(last_number);
+
+        for quirk in quirks {
+            let any_matches = |patterns: &[String], name: &Option<String>| {
+                name.as_ref().is_some_and(|name| {
+                    patterns
+                        .iter()
+                        .any(|pattern| matches(pattern.as_bytes(), name.as_bytes()))
+                })
+            };
+            if !any_matches(&quirk.product_names, &merged.product_name)
+                && !any_matches(&quirk.board_names, &merged.board_name)
+            {
+                continue;
+            }
+            if !in_range(firmware_version, quirk.firmware_min, quirk.firmware_max)
+                || !in_range(bios_version, quirk.bios_min, quirk.bios_max)
+            {
+                continue;
+            }
+            merged.model = quirk.model.or(merged.model);
+            merged.sensors_chip = quirk.sensors_chip.or(merged.sensors_chip);
+            merged.charger_supply = quirk.charger_supply.or(merged.charger_supply);
+            merged.slow_charger_watts = quirk.slow_charger_watts.or(merged.slow_charger_watts);
+            merged.pdvl_scale = quirk.pdvl_scale.unwrap_or(merged.pdvl_scale);
+            merged.pdam_scale = quirk.pdam_scale.unwrap_or(merged.pdam_scale);
+            merged.power_scale = quirk.power_scale.unwrap_or(merged.power_scale);
+            merged.scales.voltage = quirk.voltage_scale.unwrap_or(merged.scales.voltage);
+            merged.scales.current = quirk.current_scale.unwrap_or(merged.scales.current);
+            merged.scales.charge = quirk.charge_scale.unwrap_or(merged.scales.charge);
+            merged.positive_discharge_current = quirk
+                .positive_discharge_current
+                .or(merged.positive_discharge_current);
+            for attribute in quirk.ignore {
+                if !ATTRIBUTES.contains(&attribute.as_str()) {
+                    warn!("quirks: cannot ignore unknown attribute {attribute}");
+                } else if !merged.ignore.contains(&attribute) {
+                    merged.ignore.push(attribute);
+                }
+            }
+        }
+        merged
+    }
+
+    // Quirks only change defaults: vpower.toml still has the last word.
+    pub fn apply_defaults(&self, config: &mut Config) {
+        if let Some(sensors_chip) = &self.sensors_chip {
+            config.sensors_chip = Some(sensors_chip.clone());
+        }
+        if let Some(charger_supply) = &self.charger_supply {
+            config.charger_supply = Some(charger_supply.clone());
+        }
+        if let Some(slow_charger_watts) = self.slow_charger_watts {
+            config.slow_charger_watts = slow_charger_watts;
+        }
+    }
+
+    pub fn scales(&self) -> Scales {
+        self.scales
+    }
+
+    pub fn positive_discharge_current(&self) -> Option<bool> {
+        self.positive_discharge_current
+    }
+
+    fn ignores(&self, attribute: &str) -> bool {
+        self.ignore.iter().any(|ignored| ignored == attribute)
+    }
+
+    pub fn apply_pd(&self, pdcs: &mut Option<u8>, pdvl: &mut Option<f64>, pdam: &mut Option<f64>) {
+        if self.ignores("pdcs") {
+            *pdcs = None;
+        }
+        *pdvl = pdvl
+            .filter(|_| !self.ignores("pdvl"))
+            .map(|pdvl| pdvl * self.pdvl_scale);
+        *pdam = pdam
+            .filter(|_| !self.ignores("pdam"))
+            .map(|pdam| pdam * self.pdam_scale);
+    }
+
+    pub fn apply_battery(&self, reading: &mut BatteryReading) {
+        if self.ignores("charge_full_design") {
+            reading.charge_full_design = None;
+        }
+        if self.ignores("cycle_count") {
+            reading.cycle_count = None;
+        }
+        if self.ignores("temp") {
+            reading.temp_celsius = None;
+        }
+        if self.ignores("voltage_min_design") {
+            reading.voltage_min_design = None;
+        }
+        if self.ignores("status") {
+            reading.status = None;
+        }
+        for power in [
+            &mut reading.power_now,
+            &mut reading.power_watts,
+            &mut reading.net_power_watts,
+        ] {
+            *power = power.map(|power| power * self.power_scale);
+        }
+    }
+
+    pub fn print(&self) {
+        let product_name = self.product_name.as_deref().unwrap_or("unknown");
+        let board_name = self.board_name.as_deref().unwrap_or("unknown");
+        let bios_version = self.bios_version.as_deref().unwrap_or("unknown");
+        let firmware_version = self.firmware_version.as_deref().unwrap_or("unknown");
+        info!(
+            "quirks: for {product_name} (board {board_name}, BIOS {bios_version}, \
+             EC firmware {firmware_version})"
+        );
+        if let Some(model) = &self.model {
+            info!("quirks.model: {model}");
+        }
+        if let Some(sensors_chip) = &self.sensors_chip {
+            info!("quirks.sensors_chip: {sensors_chip}");
+        }
+        if let Some(charger_supply) = &self.charger_supply {
+            info!("quirks.charger_supply: {charger_supply}");
+        }
+        if let Some(slow_charger_watts) = self.slow_charger_watts {
+            info!("quirks.slow_charger_watts: {slow_charger_watts}");
+        }
+        info!("quirks.pdvl_scale: {}", self.pdvl_scale);
+        info!("quirks.pdam_scale: {}", self.pdam_scale);
+        info!("quirks.power_scale: {}", self.power_scale);
+        info!("quirks.voltage_scale: {}", self.scales.voltage);
+        info!("quirks.current_scale: {}", self.scales.current);
+        info!("quirks.charge_scale: {}", self.scales.charge);
+        if let Some(positive_discharge_current) = self.positive_discharge_current {
+            info!("quirks.positive_discharge_current: {positive_discharge_current}");
+        }
+        info!("quirks.ignore: {:?}", self.ignore);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(bios_version: &str, firmware_version: Option<&str>) -> Quirks {
+        Quirks {
+            product_name: Some("Jupiter".to_string()),
+            bios_version: Some(bios_version.to_string()),
+            firmware_version: firmware_version.map(str::to_string),
+            ..Quirks::default()
+        }
+    }
+
+    #[test]
+    fn firmware_range() {
+        let quirks = parse(
+            "test",
+            r#"
+            [[quirk]]
+            product_names = ["Jupiter"]
+            firmware_max = 45096
+            ignore = ["pdvl"]
+            "#,
+        );
+        let ignores = |firmware_version| {
+            device("F7A0131", firmware_version)
+                .merge(quirks.clone())
+                .ignores("pdvl")
+        };
+        assert!(ignores(Some("45096")));
+        assert!(!ignores(Some("45097")));
+        assert!(!ignores(None));
+    }
+
+    #[test]
+    fn bios_range() {
+        let quirks = parse(
+            "test",
+            r#"
+            [[quirk]]
+            product_names = ["Jupiter"]
+            bios_max = 116
+            ignore = ["pdvl"]
+            "#,
+        );
+        assert_eq!(last_number("F7A0131"), Some(131));
+        let device = |bios_version| device(bios_version, Some("45096"));
+        assert!(device("F7A0116").merge(quirks.clone()).ignores("pdvl"));
+        assert!(!device("F7A0131").merge(quirks).ignores("pdvl"));
+    }
+}
@@ -0,0 +1,94 @@
+use crate::ac::{self, Adapter};
+use crate::battery::{aggregate, Battery, BatteryReading};
+use crate::config::Config;
+use crate::overrides::Overrides;
+use crate::power_state::{Inputs, Outputs, PowerState};
+use crate::report_once;
+use crate::sensors::Sensors;
+use std::path::PathBuf;
+use std::time::Instant;
+
+// What an iteration of the main loop, or --oneshot, reads: the
+// batteries with the quirks and the calibration applied, the PD
+// contract and the AC adapters.
+pub struct Sample {
+    pub readings: Vec<BatteryReading>,
+    pub reading: BatteryReading,
+    pub pdcs: Option<u8>,
+    pub pdvl: Option<f64>,
+    pub pdam: Option<f64>,
+    pub ac_adapters: Vec<Adapter>,
+    pub charge_limit_percent: f64,
+    pub now: Instant,
+}
+
+impl Sample {
+    pub fn read(
+        config: &Config,
+        batteries: &[Battery],
+        capacity_factor: f64,
+        sensors: &Sensors,
+        ac_paths: &[PathBuf],
+        charge_limit_percent: f64,
+        now: Instant,
+    ) -> Sample {
+        let readings: Vec<BatteryReading> = batteries
+            .iter()
+            .map(|battery| {
+                let mut reading = battery.read();
+                config.quirks.apply_battery(&mut reading);
+                reading.charge_full = reading
+                    .charge_full
+                    .map(|charge_full| charge_full * capacity_factor);
+                reading
+            })
+            .collect();
+        let reading = aggregate(&readings);
+        let mut pdam = report_once("pdam", sensors.pdam());
+        let mut pdcs = report_once("pdcs", sensors.pdcs());
+        let mut pdvl = report_once("pdvl", sensors.pdvl());
+        config.quirks.apply_pd(&mut pdcs, &mut pdvl, &mut pdam);
+        Sample {
+            readings,
+            reading,
+            pdcs,
+            pdvl,
+            pdam,
+            ac_adapters: ac::read(ac_paths),
+            charge_limit_percent,
+            now,
+        }
+    }
+
+    pub fn inputs(&self) -> Inputs<'_> {
+        // Without a PD contract status, fall back to the AC
+        // adapters, connected if any of them is.
+        let ac_online = match self.pdcs {
+            Some(_) => None,
+            None => ac::online(&self.ac_adapters),
+        };
+        Inputs {
+            reading: &self.reading,
+            pdcs: self.pdcs,
+            pdvl: self.pdvl,
+            pdam: self.pdam,
+            ac_online,
+            charge_limit_percent: self.charge_limit_percent,
+            now: self.now,
+        }
+    }
+
+    // Derive everything else, with the values pinned in the override
+    // file.
+    pub fn derive(
+        &self,
+        config: &Config,
+        power_state: &mut PowerState,
+        overrides: &mut Overrides,
+    ) -> Outputs {
+        let mut outputs = power_state.compute(config, &self.inputs());
+        overrides.update();
+        overrides.apply(&mut outputs);
+        outputs
+    }
+}
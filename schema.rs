@@ -0,0 +1,68 @@
+use crate::write_str;
+
+// The version of what vpower writes to output_dir, published in
+// <output_dir>/schema_version. Within a version, a file keeps its
+// name, unit and meaning: files can be added, but changing one means
+// a new name, with the old one still written for as long as the
+// version stays the same. Anything else bumps the version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+// Files written under a new name, as (old name, new name): the old
+// one is kept up to date with the same contents. None so far.
+const RENAMED: &[(&str, &str)] = &[];
+
+fn legacy_names_in(renamed: &[(&'static str, &'static str)], name: &str) -> Vec<&'static str> {
+    renamed
+        .iter()
+        .filter(|(_, new)| *new == name)
+        .map(|(old, _)| *old)
+        .collect()
+}
+
+// The old names to write along with name.
+pub fn legacy_names(name: &str) -> Vec<&'static str> {
+    legacy_names_in(RENAMED, name)
+}
+
+pub fn write_version(dir_path: &str) {
+    write_str(
+        dir_path,
+        "schema_version",
+        Some(&SCHEMA_VERSION.to_string()),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn legacy_names_follow_renames() {
+        let renamed = [
+            ("power_watts", "power_draw_watts"),
+            ("watts", "power_draw_watts"),
+            ("status", "battery_status"),
+        ];
+        assert_eq!(
+            legacy_names_in(&renamed, "power_draw_watts"),
+            ["power_watts", "watts"]
+        );
+        assert!(legacy_names_in(&renamed, "ac_status").is_empty());
+        assert!(legacy_names_in(&renamed, "power_watts").is_empty());
+    }
+
+    #[test]
+    fn renamed_files_are_consistent() {
+        // An old name can't be written for two files, nor be the new
+        // name of another one.
+        let mut old_names = HashSet::new();
+        for (old, new) in RENAMED {
+            assert_ne!(old, new);
+            assert!(old_names.insert(old), "{old} renamed twice");
+        }
+        for (_, new) in RENAMED {
+            assert!(!old_names.contains(new), "{new} is also an old name");
+        }
+    }
+}
@@ -1,239 +1,304 @@
-use libc::*;
-use std::ffi::CStr;
+use crate::hwmon;
+#[cfg(feature = "steamdeck-sensors")]
+use crate::libsensors::*;
+use crate::pd::PDCS_CONNECTED;
+#[cfg(feature = "steamdeck-sensors")]
+use crate::sysfs_root_set;
+use crate::typec;
+use libc::c_int;
+#[cfg(feature = "steamdeck-sensors")]
+use log::warn;
+use log::{error, info};
 use std::fs;
-use std::mem::MaybeUninit;
-use std::ptr;
+use std::io;
+use std::path::Path;
 use std::str::FromStr;
+use thiserror::Error;
 
-#[repr(C)]
-struct sensors_bus_id {
-    ty: c_short,
-    nr: c_short,
-}
-
-#[repr(C)]
-struct sensors_chip_name {
-    prefix: *mut c_char,
-    bus: sensors_bus_id,
-    addr: c_int,
-    path: *mut c_char,
-}
-
-#[repr(C)]
-struct sensors_feature {
-    name: *mut c_char,
-    number: c_int,
-    ty: c_int,
-    first_subfeature: c_int,
-    padding1: c_int,
-}
-
-#[repr(C)]
-#[derive(Debug)]
-struct sensors_subfeature {
-    name: *mut c_char,
-    number: c_int,
-    ty: c_int,
-    mapping: c_int,
-    flags: c_uint,
-}
+// Chip names to try when none is configured.
+const DEFAULT_CHIPS: [&str; 2] = [
+    // New name for the sensor name in SteamOS 3.5
+    "steamdeck_hwmon",
+    // Fallback to old jupiter name for SteamOS 3.4
+    "jupiter",
+];
 
-#[link(name = "sensors")]
-extern "C" {
-    fn sensors_init(input: *mut FILE) -> c_int;
-    fn sensors_cleanup();
-
-    fn sensors_get_detected_chips(
-        mat: *const sensors_chip_name,
-        nr: *mut c_int,
-    ) -> *const sensors_chip_name;
-
-    fn sensors_get_features(
-        name: *const sensors_chip_name,
-        nr: *mut c_int,
-    ) -> *const sensors_feature;
-
-    fn sensors_get_subfeature(
-        name: *const sensors_chip_name,
-        feature: *const sensors_feature,
-        ty: c_int,
-    ) -> *const sensors_subfeature;
-
-    fn sensors_get_value(
-        name: *const sensors_chip_name,
-        subfeat_nr: c_int,
-        value: *mut c_double,
-    ) -> c_int;
+#[derive(Clone, Debug, Error)]
+pub enum Error {
+    #[error("cannot load libsensors: {0}")]
+    Load(String),
+    #[error("sensors_init failed: {0}")]
+    Init(c_int),
+    #[error("libsensors not initialized")]
+    NotInitialized,
+    #[error("libsensors cannot read from --sysfs-root")]
+    SysfsRoot,
+    #[error("no chip matching '{0}'")]
+    ChipNotFound(String),
+    #[error("no {0}")]
+    FeatureNotFound(String),
+    #[error("sensors_get_value failed: {0}")]
+    Value(c_int),
+    #[error("{0}")]
+    Read(String),
 }
 
-const SENSORS_FEATURE_IN: c_int = 0x00;
-const SENSORS_FEATURE_CURR: c_int = 0x05;
-
-const SENSORS_SUBFEATURE_IN_INPUT: c_int = SENSORS_FEATURE_IN << 8;
-const SENSORS_SUBFEATURE_CURR_INPUT: c_int = SENSORS_FEATURE_CURR << 8;
-
-unsafe fn get_chip(name: *const c_char) -> *const sensors_chip_name {
-    let mut nr = 0;
-    loop {
-        let chip = sensors_get_detected_chips(ptr::null(), &mut nr);
-        if chip.is_null() {
-            return chip;
-        }
-
-        let chip = &*chip;
-        if strcmp(chip.prefix, name) == 0 {
-            return chip;
+// Shell-style wildcard matching, supporting * and ?.
+pub fn matches(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            matches(&pattern[1..], name) || (!name.is_empty() && matches(pattern, &name[1..]))
         }
+        (Some(b'?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => matches(&pattern[1..], &name[1..]),
+        _ => false,
     }
 }
 
-unsafe fn find_jupiter_chip() -> *const sensors_chip_name {
-    // New name for the sensor name in SteamOS 3.5
-    let mut chip = get_chip("steamdeck_hwmon\0".as_ptr() as *const c_char);
-    if !chip.is_null() {
-        println!("Using sensor: steamdeck_hwmon");
-        return chip;
-    }
-
-    // Fallback to old jupiter name for SteamOS 3.4
-    chip = get_chip("jupiter\0".as_ptr() as *const c_char);
-    if !chip.is_null() {
-        println!("Using sensor: jupiter");
-        return chip;
+#[cfg(feature = "steamdeck-sensors")]
+fn find_chip(library: &Library, patterns: &[&str]) -> Result<ChipHandle, Error> {
+    let chips = library.chips();
+    for pattern in patterns {
+        let found = chips
+            .iter()
+            .find(|chip| matches(pattern.as_bytes(), chip.prefix().as_bytes()));
+        if let Some(chip) = found {
+            return Ok(chip.to_handle());
+        }
     }
-
-    println!("Error: failed to find sensor");
-    return chip;
+    Err(Error::ChipNotFound(patterns.join(", ")))
 }
 
-unsafe fn get_feature(chip: *const sensors_chip_name, feature_ty: c_int) -> *const sensors_feature {
-    let mut nr = 0;
-    loop {
-        let feature = sensors_get_features(chip, &mut nr);
-        if feature.is_null() {
-            return feature;
-        }
+enum Backend {
+    #[cfg(feature = "steamdeck-sensors")]
+    Libsensors {
+        // Declared before library, so they are dropped first.
+        chip: Result<ChipHandle, Error>,
+        pdvl_subfeature_num: Result<c_int, Error>, // PD contract voltage.
+        pdam_subfeature_num: Result<c_int, Error>, // PD contract current.
+        library: Library,
+    },
+    // Reading sysfs directly, when libsensors can't be used, or vpower
+    // was built without the steamdeck-sensors feature.
+    Hwmon(Result<hwmon::Chip, Error>),
+}
 
-        let feature = &*feature;
-        if feature.ty == feature_ty {
-            return feature;
+// Initialize libsensors, or fall back to hwmon.
+#[cfg(feature = "steamdeck-sensors")]
+fn backend() -> Backend {
+    // libsensors only knows about the real /sys.
+    let library = if sysfs_root_set() {
+        Err(Error::SysfsRoot)
+    } else {
+        Library::init()
+    };
+    match library {
+        Ok(library) => Backend::Libsensors {
+            chip: Err(Error::NotInitialized),
+            pdvl_subfeature_num: Err(Error::NotInitialized),
+            pdam_subfeature_num: Err(Error::NotInitialized),
+            library,
+        },
+        Err(err) => {
+            warn!("{err}, reading hwmon directly");
+            Backend::Hwmon(Err(Error::NotInitialized))
         }
     }
 }
 
-unsafe fn get_subfeature_num(
-    chip: *const sensors_chip_name,
-    feature_ty: c_int,
-    subfeature_ty: c_int,
-) -> Option<c_int> {
-    let feature = get_feature(chip, feature_ty);
-    if !feature.is_null() {
-        let subfeature = sensors_get_subfeature(chip, feature, subfeature_ty);
-        if !subfeature.is_null() {
-            let subfeature = &*subfeature;
-            return Some(subfeature.number);
-        }
-    }
-    None
+#[cfg(not(feature = "steamdeck-sensors"))]
+fn backend() -> Backend {
+    info!("Built without libsensors, reading hwmon directly");
+    Backend::Hwmon(Err(Error::NotInitialized))
 }
 
 pub struct Sensors {
-    initialized: bool,
-    chip: *const sensors_chip_name,
-    pdvl_subfeature_num: Option<c_int>, // PD contract voltage.
-    pdam_subfeature_num: Option<c_int>, // PD contract current.
+    backend: Backend,
+    // Where the PD values come from instead of the chip, if set.
+    charger_supply: Option<String>,
 }
 
 impl Sensors {
-    pub fn new() -> Sensors {
+    // Use the first chip matching chip_pattern, or one of the known
+    // Steam Deck chips if not given, unless there is a charger_supply
+    // pattern for USB-C power_supply devices to use instead.
+    pub fn new(chip_pattern: Option<&str>, charger_supply: Option<&str>) -> Sensors {
         let mut sensors = Sensors {
-            initialized: false,
-            chip: ptr::null(),
-            pdvl_subfeature_num: None,
-            pdam_subfeature_num: None,
+            backend: backend(),
+            charger_supply: None,
+        };
+        sensors.select_chip(chip_pattern);
+        sensors.select_charger_supply(charger_supply);
+
+        sensors
+    }
+
+    pub fn select_chip(&mut self, chip_pattern: Option<&str>) {
+        let patterns = match chip_pattern {
+            Some(chip_pattern) => vec![chip_pattern],
+            None => DEFAULT_CHIPS.to_vec(),
         };
 
-        unsafe {
-            sensors.initialized = sensors_init(ptr::null_mut()) == 0;
-            if sensors.initialized {
-                sensors.chip = find_jupiter_chip();
-                if !sensors.chip.is_null() {
-                    sensors.pdvl_subfeature_num = get_subfeature_num(
-                        sensors.chip,
-                        SENSORS_FEATURE_IN,
-                        SENSORS_SUBFEATURE_IN_INPUT,
-                    );
-                    sensors.pdam_subfeature_num = get_subfeature_num(
-                        sensors.chip,
-                        SENSORS_FEATURE_CURR,
-                        SENSORS_SUBFEATURE_CURR_INPUT,
-                    );
+        match &mut self.backend {
+            #[cfg(feature = "steamdeck-sensors")]
+            Backend::Libsensors {
+                chip,
+                pdvl_subfeature_num,
+                pdam_subfeature_num,
+                library,
+            } => {
+                *chip = find_chip(library, &patterns);
+                match chip {
+                    Err(err) => {
+                        error!("failed to find sensor: {err}");
+                        *pdvl_subfeature_num = Err(err.clone());
+                        *pdam_subfeature_num = Err(err.clone());
+                    }
+                    Ok(chip) => {
+                        info!("Using sensor: {}", chip.prefix);
+                        *pdvl_subfeature_num = find_subfeature(
+                            &chip.features,
+                            SENSORS_FEATURE_IN,
+                            SENSORS_SUBFEATURE_IN_INPUT,
+                        );
+                        *pdam_subfeature_num = find_subfeature(
+                            &chip.features,
+                            SENSORS_FEATURE_CURR,
+                            SENSORS_SUBFEATURE_CURR_INPUT,
+                        );
+                    }
+                }
+            }
+            Backend::Hwmon(chip) => {
+                *chip = hwmon::find_chip(&patterns);
+                match chip {
+                    Err(err) => error!("failed to find sensor: {err}"),
+                    Ok(chip) => info!("Using sensor: {} ({})", chip.name, chip.path.display()),
                 }
             }
         }
+    }
 
-        sensors
+    pub fn select_charger_supply(&mut self, charger_supply: Option<&str>) {
+        if let Some(pattern) = charger_supply {
+            info!("Using charger power_supply: {pattern}");
+        }
+        self.charger_supply = charger_supply.map(str::to_string);
     }
 
-    fn path(&self) -> Option<String> {
-        if self.chip.is_null() {
-            None
-        } else {
-            unsafe {
-                let chip = &*self.chip;
-                Some(CStr::from_ptr(chip.path).to_owned().into_string().unwrap())
-            }
+    // Where the PD values are read from, for --check.
+    pub fn describe(&self) -> (&'static str, Result<String, Error>) {
+        match &self.backend {
+            #[cfg(feature = "steamdeck-sensors")]
+            Backend::Libsensors { chip, .. } => (
+                "libsensors",
+                chip.as_ref()
+                    .map(|chip| chip.prefix.clone())
+                    .map_err(Error::clone),
+            ),
+            Backend::Hwmon(chip) => (
+                "hwmon",
+                chip.as_ref()
+                    .map(|chip| chip.name.clone())
+                    .map_err(Error::clone),
+            ),
         }
     }
 
+    fn charger_contract(&self) -> Option<Result<Option<typec::Contract>, Error>> {
+        self.charger_supply.as_deref().map(typec::read)
+    }
+
+    pub(crate) fn chip_path(&self) -> Result<&Path, Error> {
+        match &self.backend {
+            #[cfg(feature = "steamdeck-sensors")]
+            Backend::Libsensors { chip, .. } => chip.as_ref().map(|chip| chip.path.as_path()),
+            Backend::Hwmon(chip) => chip.as_ref().map(|chip| chip.path.as_path()),
+        }
+        .map_err(Error::clone)
+    }
+
     // PD contract status.
-    pub fn pdcs(&self) -> Option<u8> {
-        if let Some(path) = self.path() {
-            let path = format!("{path}/pdcs");
-            if let Ok(string) = fs::read_to_string(&path) {
-                if let Ok(val) = u8::from_str(string.trim()) {
-                    return Some(val);
-                }
-            }
+    pub fn pdcs(&self) -> io::Result<u8> {
+        if let Some(contract) = self.charger_contract() {
+            // Always a sink: these only describe chargers.
+            return match contract {
+                Err(err) => Err(io::Error::new(io::ErrorKind::NotFound, err.to_string())),
+                Ok(contract) => Ok(if contract.is_some() {
+                    PDCS_CONNECTED
+                } else {
+                    0
+                }),
+            };
         }
-        None
+        let path = self
+            .chip_path()
+            .map_err(|err| io::Error::new(io::ErrorKind::NotFound, err.to_string()))?;
+        let string = fs::read_to_string(path.join("pdcs"))?;
+        u8::from_str(string.trim()).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
     }
 
     // PD contract voltage (Volts).
-    pub fn pdvl(&self) -> Option<f64> {
-        if !self.chip.is_null() {
-            if let Some(subfeature_num) = self.pdvl_subfeature_num {
-                unsafe {
-                    let mut val = MaybeUninit::uninit();
-                    if sensors_get_value(self.chip, subfeature_num, val.as_mut_ptr()) == 0 {
-                        return Some(val.assume_init());
-                    }
-                }
-            }
+    pub fn pdvl(&self) -> Result<f64, Error> {
+        if let Some(contract) = self.charger_contract() {
+            return Ok(contract?.map_or(0.0, |contract| contract.voltage));
+        }
+        match &self.backend {
+            #[cfg(feature = "steamdeck-sensors")]
+            Backend::Libsensors {
+                chip,
+                pdvl_subfeature_num,
+                library,
+                ..
+            } => value(library, chip, pdvl_subfeature_num),
+            Backend::Hwmon(chip) => chip.as_ref().map_err(Error::clone)?.voltage(),
         }
-        None
     }
 
     // PD contract current (Amps).
-    pub fn pdam(&self) -> Option<f64> {
-        if !self.chip.is_null() {
-            if let Some(subfeature_num) = self.pdam_subfeature_num {
-                unsafe {
-                    let mut val = MaybeUninit::uninit();
-                    if sensors_get_value(self.chip, subfeature_num, val.as_mut_ptr()) == 0 {
-                        return Some(val.assume_init());
-                    }
-                }
-            }
+    pub fn pdam(&self) -> Result<f64, Error> {
+        if let Some(contract) = self.charger_contract() {
+            return Ok(contract?.map_or(0.0, |contract| contract.current));
+        }
+        match &self.backend {
+            #[cfg(feature = "steamdeck-sensors")]
+            Backend::Libsensors {
+                chip,
+                pdam_subfeature_num,
+                library,
+                ..
+            } => value(library, chip, pdam_subfeature_num),
+            Backend::Hwmon(chip) => chip.as_ref().map_err(Error::clone)?.current(),
         }
-        None
     }
 }
 
-impl Drop for Sensors {
-    fn drop(&mut self) {
-        if self.initialized {
-            unsafe { sensors_cleanup() };
-        }
+#[cfg(feature = "steamdeck-sensors")]
+fn value(
+    library: &Library,
+    chip: &Result<ChipHandle, Error>,
+    subfeature_num: &Result<c_int, Error>,
+) -> Result<f64, Error> {
+    let chip = chip.as_ref().map_err(Error::clone)?;
+    let subfeature_num = subfeature_num.as_ref().map_err(Error::clone)?;
+    library.value(chip, *subfeature_num)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches(b"steamdeck_hwmon", b"steamdeck_hwmon"));
+        assert!(matches(b"steamdeck_*", b"steamdeck_hwmon"));
+        assert!(matches(b"*", b""));
+        assert!(matches(b"*-psy-*", b"ucsi-source-psy-USBC000:001"));
+        assert!(matches(b"hwmon?", b"hwmon3"));
+        assert!(!matches(b"hwmon?", b"hwmon"));
+        assert!(!matches(b"hwmon?", b"hwmon10"));
+        assert!(!matches(b"jupiter", b"steamdeck_hwmon"));
+        assert!(!matches(b"steamdeck_*", b"jupiter"));
     }
 }
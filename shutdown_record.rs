@@ -0,0 +1,91 @@
+use crate::action::CriticalAction;
+use crate::history::HISTORY_DIR;
+use crate::snapshot::Snapshot;
+use crate::write_str;
+use log::{error, info};
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const LAST_SHUTDOWN_FILE: &str = "last_shutdown.json";
+
+// What things looked like when vpower shut the system down, so that
+// the next boot can tell whether vpower or something else did.
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: u64,
+    action: String,
+    battery_percent: Option<f64>,
+    voltage_now_volts: Option<f64>,
+    ac_status: Option<&'a str>,
+    charger_type: Option<&'a str>,
+    charger_watts: Option<f64>,
+}
+
+fn path() -> PathBuf {
+    PathBuf::from(HISTORY_DIR).join(LAST_SHUTDOWN_FILE)
+}
+
+// Written to a temporary file first and synced, as the power goes off
+// right after.
+fn write_synced(json: &str) -> io::Result<()> {
+    fs::create_dir_all(HISTORY_DIR)?;
+    let path = path();
+    let dot_path = PathBuf::from(HISTORY_DIR).join(format!(".{LAST_SHUTDOWN_FILE}"));
+    let mut file = File::create(&dot_path)?;
+    writeln!(file, "{json}")?;
+    file.sync_all()?;
+    fs::rename(&dot_path, &path)?;
+    File::open(HISTORY_DIR)?.sync_all()
+}
+
+// Record that vpower is about to run action.
+pub fn write(action: CriticalAction, snapshot: &Snapshot) {
+    let record = Record {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs()),
+        action: action.to_string(),
+        battery_percent: snapshot.battery_percent,
+        voltage_now_volts: snapshot.voltage_now_volts,
+        ac_status: snapshot.ac_status.as_deref(),
+        charger_type: snapshot.charger_type.as_deref(),
+        charger_watts: snapshot.charger_watts,
+    };
+    let result = serde_json::to_string(&record)
+        .map_err(io::Error::other)
+        .and_then(|json| write_synced(&json));
+    if let Err(err) = result {
+        error!("write {}: {err}", path().display());
+    }
+}
+
+// Forget the record after resuming, since the system didn't go down.
+pub fn clear() {
+    if let Err(err) = fs::remove_file(path()) {
+        if err.kind() != io::ErrorKind::NotFound {
+            error!("remove {}: {err}", path().display());
+        }
+    }
+}
+
+// At startup, move the record of the shutdown that ended the previous
+// boot, if vpower caused it, to <dir_path>/previous_shutdown.
+pub fn publish_previous(dir_path: &str) {
+    let json = match fs::read_to_string(path()) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+        Err(err) => {
+            error!("read {}: {err}", path().display());
+            return;
+        }
+        Ok(json) => json,
+    };
+    info!(
+        "The previous boot ended with vpower shutting down: {}",
+        json.trim()
+    );
+    write_str(dir_path, "previous_shutdown", Some(json.trim()));
+    clear();
+}
@@ -0,0 +1,82 @@
+use crate::events::Wakeup;
+use libc::*;
+use log::error;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+static RELOAD: AtomicBool = AtomicBool::new(false);
+static STOP: AtomicBool = AtomicBool::new(false);
+
+// The write end of a pipe the handlers write a byte to, so that a
+// thread can wake up the main loop, which they can't do themselves.
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+fn wake() {
+    let fd = WAKE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe { write(fd, [0u8].as_ptr() as *const c_void, 1) };
+    }
+}
+
+extern "C" fn handle_sighup(_signum: c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+    wake();
+}
+
+extern "C" fn handle_sigterm(_signum: c_int) {
+    STOP.store(true, Ordering::SeqCst);
+    wake();
+}
+
+// Install the handlers, which then wake up the main loop through
+// wakeups.
+pub fn install(wakeups: Sender<Wakeup>) {
+    let mut fds = [-1; 2];
+    if unsafe { pipe2(fds.as_mut_ptr(), O_CLOEXEC | O_NONBLOCK) } != 0 {
+        error!("pipe2: {}", io::Error::last_os_error());
+    } else {
+        let read_fd = fds[0];
+        WAKE_FD.store(fds[1], Ordering::SeqCst);
+        thread::spawn(move || {
+            let mut pollfd = pollfd {
+                fd: read_fd,
+                events: POLLIN,
+                revents: 0,
+            };
+            let mut buf = [0u8; 64];
+            loop {
+                if unsafe { poll(&mut pollfd, 1, -1) } < 0 {
+                    continue;
+                }
+                while unsafe { read(read_fd, buf.as_mut_ptr() as *mut c_void, buf.len()) } > 0 {}
+                if wakeups.send(Wakeup::Signal).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    unsafe {
+        signal(SIGHUP, handle_sighup as *const () as sighandler_t);
+        signal(SIGTERM, handle_sigterm as *const () as sighandler_t);
+        signal(SIGINT, handle_sigterm as *const () as sighandler_t);
+    }
+}
+
+// Same as SIGHUP, for reloads requested through the socket.
+pub fn request_reload() {
+    RELOAD.store(true, Ordering::SeqCst);
+    wake();
+}
+
+// Whether SIGHUP was received since the last call.
+pub fn reload_requested() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+// Whether SIGTERM or SIGINT was received.
+pub fn stop_requested() -> bool {
+    STOP.load(Ordering::SeqCst)
+}
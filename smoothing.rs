@@ -0,0 +1,31 @@
+// Exponential moving average, to keep estimates derived from noisy
+// readings from jumping around.
+pub struct Ema {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Ema {
+    // Weight roughly equivalent to a simple moving average over the
+    // given number of samples.
+    pub fn new(samples: f64) -> Ema {
+        Ema {
+            alpha: 2.0 / (samples.max(1.0) + 1.0),
+            value: None,
+        }
+    }
+
+    pub fn update(&mut self, sample: Option<f64>) -> Option<f64> {
+        if let Some(sample) = sample {
+            self.value = Some(match self.value {
+                Some(value) => value + self.alpha * (sample - value),
+                None => sample,
+            });
+        }
+        self.value
+    }
+
+    pub fn reset(&mut self) {
+        self.value = None;
+    }
+}
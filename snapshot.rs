@@ -0,0 +1,146 @@
+use crate::battery::Units;
+use crate::charger::Charger;
+use crate::codes;
+use crate::power_state::{Inputs, Outputs};
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Values calculated in one iteration of the main loop.
+#[derive(Clone, Default, PartialEq, Serialize)]
+pub struct Snapshot {
+    pub ac_status: Option<String>,
+    pub ac_status_code: Option<u8>,
+    pub ac_present: Option<bool>,
+    pub battery_percent: Option<f64>,
+    pub battery_percent_accuracy: Option<String>,
+    pub battery_status: Option<String>,
+    pub battery_status_code: Option<u8>,
+    pub charge_limit_percent: Option<f64>,
+    pub charger_watts: Option<f64>,
+    pub charger_type: Option<String>,
+    pub charger_type_code: Option<u8>,
+    pub charger_class: Option<String>,
+    pub charger_model: Option<String>,
+    pub dock_connected: Option<bool>,
+    pub platform_profile: Option<String>,
+    pub pd_voltage: Option<f64>,
+    pub pd_current: Option<f64>,
+    pub pd_wattage: Option<f64>,
+    pub pd_status: Option<String>,
+    pub battery_health_percent: Option<f64>,
+    pub cycle_count: Option<f64>,
+    pub charge_full_uah: Option<f64>,
+    pub charge_full_design_uah: Option<f64>,
+    pub energy_full_uwh: Option<f64>,
+    pub energy_full_design_uwh: Option<f64>,
+    pub battery_full_wh: Option<f64>,
+    pub battery_full_design_wh: Option<f64>,
+    pub battery_temp_celsius: Option<f64>,
+    pub battery_overheat: Option<bool>,
+    pub power_draw_watts: Option<f64>,
+    pub power_draw_watts_smoothed: Option<f64>,
+    pub net_power_watts: Option<f64>,
+    pub battery_direction: Option<String>,
+    pub voltage_now_volts: Option<f64>,
+    pub current_now_amps: Option<f64>,
+    pub net_drain: Option<bool>,
+    pub secs_until_battery_full: Option<f64>,
+    pub secs_at_full: Option<f64>,
+    pub secs_until_battery_empty: Option<f64>,
+    pub secs_until_shutdown_request: Option<f64>,
+    pub secs_until_shutdown_request_min: Option<f64>,
+    pub secs_until_shutdown_request_max: Option<f64>,
+    pub low_battery_warning: Option<bool>,
+    pub battery_present: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct Status<'a> {
+    timestamp: f64,
+    sequence: u64,
+    #[serde(flatten)]
+    snapshot: &'a Snapshot,
+}
+
+impl Snapshot {
+    /// Gather the values of one iteration: what was read, what was
+    /// derived from it, and what's providing power.
+    pub fn new(
+        inputs: &Inputs,
+        outputs: &Outputs,
+        charger: &Charger,
+        platform_profile: Option<String>,
+        secs_at_full: Option<f64>,
+        battery_present: bool,
+    ) -> Snapshot {
+        let reading = inputs.reading;
+        // The capacities as the driver reports them, charge_full
+        // corrected by --calibrate, so nobody needs to guess the
+        // units again.
+        let full = (reading.charge_full, reading.charge_full_design);
+        let (charge, energy) = match reading.units {
+            Units::Charge => (full, (None, None)),
+            Units::Energy => ((None, None), full),
+        };
+        Snapshot {
+            ac_status: outputs.ac_status.map(str::to_owned),
+            ac_status_code: outputs.ac_status.and_then(codes::ac_status_code),
+            ac_present: outputs.ac_status.map(|x| x != "Disconnected"),
+            battery_percent: outputs.battery_percent,
+            battery_percent_accuracy: outputs.battery_percent_accuracy.map(str::to_owned),
+            battery_status: outputs.battery_status.map(str::to_owned),
+            battery_status_code: outputs.battery_status.and_then(codes::battery_status_code),
+            charge_limit_percent: Some(inputs.charge_limit_percent),
+            charger_watts: outputs.charger_watts,
+            charger_type: outputs.charger_type.map(str::to_owned),
+            charger_type_code: outputs.charger_type.and_then(codes::charger_type_code),
+            charger_class: outputs.charger_class.clone(),
+            charger_model: charger.model.clone(),
+            dock_connected: Some(charger.dock_connected),
+            platform_profile,
+            pd_voltage: inputs.pdvl,
+            pd_current: inputs.pdam,
+            pd_wattage: outputs.pd_wattage,
+            pd_status: outputs.pd_status.clone(),
+            battery_health_percent: reading.battery_health_percent(),
+            cycle_count: reading.cycle_count,
+            charge_full_uah: charge.0,
+            charge_full_design_uah: charge.1,
+            energy_full_uwh: energy.0,
+            energy_full_design_uwh: energy.1,
+            battery_full_wh: reading.charge_full.and_then(|x| reading.to_wh(x)),
+            battery_full_design_wh: reading.charge_full_design.and_then(|x| reading.to_wh(x)),
+            battery_temp_celsius: reading.temp_celsius,
+            battery_overheat: outputs.battery_overheat,
+            power_draw_watts: reading.power_watts,
+            power_draw_watts_smoothed: outputs.power_draw_watts_smoothed,
+            net_power_watts: outputs.net_power_watts,
+            voltage_now_volts: reading.voltage_now.map(|x| x / 1e6),
+            current_now_amps: reading.net_current_amps,
+            battery_direction: reading.direction.map(|x| x.name().to_owned()),
+            net_drain: outputs.net_drain,
+            secs_until_battery_full: outputs.secs_until_battery_full,
+            secs_at_full,
+            secs_until_battery_empty: outputs.secs_until_battery_empty,
+            secs_until_shutdown_request: outputs.secs_until_shutdown_request,
+            secs_until_shutdown_request_min: outputs.secs_until_shutdown_request_min,
+            secs_until_shutdown_request_max: outputs.secs_until_shutdown_request_max,
+            low_battery_warning: outputs.low_battery_warning,
+            battery_present: Some(battery_present),
+        }
+    }
+
+    /// All values in one document, so consumers don't have to read
+    /// several files that might come from different iterations.
+    pub fn to_json(&self, sequence: u64) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |duration| duration.as_secs_f64());
+        let status = Status {
+            timestamp,
+            sequence,
+            snapshot: self,
+        };
+        serde_json::to_string(&status).unwrap()
+    }
+}
@@ -0,0 +1,432 @@
+use crate::events::Wakeup;
+use crate::snapshot::Snapshot;
+use crate::{logging, signals};
+use log::{error, info};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Clients that don't read are dropped rather than keeping a thread.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Lines queued for a subscriber, which is dropped once it falls
+// further behind.
+const SUBSCRIBER_QUEUE: usize = 4;
+
+// Any local user may connect, so commands are short, clients that go
+// quiet are dropped, and there are only so many at once, rather than
+// letting them take up memory and threads.
+const MAX_LINE: u64 = 256;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_CLIENTS: usize = 16;
+const MAX_SUBSCRIBERS: usize = 32;
+
+// Requests for the main loop to act on.
+pub enum Request {
+    SimulateBatteryLow,
+    // Who asked, for the log.
+    ForcedShutdown {
+        enabled: bool,
+        uid: libc::uid_t,
+        pid: libc::pid_t,
+    },
+    PostponeShutdown {
+        uid: libc::uid_t,
+        pid: libc::pid_t,
+    },
+}
+
+struct Shared {
+    snapshot: Option<Snapshot>,
+    json: Option<String>,
+    stats_json: Option<String>,
+    // Each one written to by its own thread.
+    subscribers: Vec<SyncSender<Arc<str>>>,
+    requests: Vec<Request>,
+    // To have the main loop act on requests right away.
+    wakeups: Sender<Wakeup>,
+}
+
+impl Shared {
+    fn request(&mut self, request: Request) {
+        self.requests.push(request);
+        // Only fails when the main loop is gone anyway.
+        let _ = self.wakeups.send(Wakeup::Request);
+    }
+}
+
+// Line-based protocol: "GET" replies with the current status as one
+// line of JSON, "SUBSCRIBE" does the same and then pushes a new line
+// every time the values change, taking no more commands, until it
+// falls behind. "STATS" replies with the battery life statistics.
+// "POSTPONE_SHUTDOWN" restarts the confirmation window of a pending
+// shutdown, a limited number of times.
+//
+// Only root may use the control commands, which reply "OK": "RELOAD"
+// re-reads the configuration, "SIMULATE battery_low" acts as if the
+// warning threshold was reached, "VERBOSE on" or "VERBOSE off"
+// toggle debug messages, and "FORCED_SHUTDOWN on" or
+// "FORCED_SHUTDOWN off" whether critical_action runs, until restarted.
+pub struct SocketServer {
+    shared: Arc<Mutex<Shared>>,
+}
+
+// Counts a client for as long as it's connected.
+struct Client(Arc<AtomicUsize>);
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl SocketServer {
+    pub fn new(path: &str, wakeups: Sender<Wakeup>) -> io::Result<SocketServer> {
+        // Remove the socket of a previous instance.
+        if let Err(err) = fs::remove_file(path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err);
+            }
+        }
+
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+
+        let shared = Arc::new(Mutex::new(Shared {
+            snapshot: None,
+            json: None,
+            stats_json: None,
+            subscribers: Vec::new(),
+            requests: Vec::new(),
+            wakeups,
+        }));
+        let server = SocketServer {
+            shared: shared.clone(),
+        };
+
+        thread::spawn(move || {
+            let clients = Arc::new(AtomicUsize::new(0));
+            for stream in listener.incoming() {
+                match stream {
+                    Err(err) => error!("accept: {err}"),
+                    Ok(mut stream) if clients.load(Ordering::SeqCst) >= MAX_CLIENTS => {
+                        let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+                        let _ = writeln!(stream, "ERROR too many clients");
+                    }
+                    Ok(stream) => {
+                        clients.fetch_add(1, Ordering::SeqCst);
+                        let client = Client(clients.clone());
+                        let shared = shared.clone();
+                        thread::spawn(move || handle_client(stream, shared, client));
+                    }
+                }
+            }
+        });
+
+        Ok(server)
+    }
+
+    pub fn publish(&self, snapshot: &Snapshot, json: &str) {
+        let mut shared = self.shared.lock().unwrap();
+        let changed = shared.snapshot.as_ref() != Some(snapshot);
+        shared.snapshot = Some(snapshot.clone());
+        shared.json = Some(json.to_owned());
+
+        // Never blocking the main loop on a subscriber, dropping the
+        // ones that fell behind or went away.
+        if changed {
+            let line: Arc<str> = Arc::from(json);
+            shared
+                .subscribers
+                .retain(|sender| sender.try_send(line.clone()).is_ok());
+        }
+    }
+
+    pub fn publish_stats(&self, json: &str) {
+        self.shared.lock().unwrap().stats_json = Some(json.to_owned());
+    }
+
+    pub fn take_requests(&self) -> Vec<Request> {
+        mem::take(&mut self.shared.lock().unwrap().requests)
+    }
+}
+
+fn peer_cred(stream: &UnixStream) -> io::Result<libc::ucred> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let err = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if err != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred)
+}
+
+// Write the lines publish() queues, until the subscriber is dropped
+// or doesn't read. A line cut short by the timeout is the last one.
+fn write_lines(mut stream: UnixStream, lines: mpsc::Receiver<Arc<str>>) {
+    for line in lines {
+        if writeln!(stream, "{line}").is_err() {
+            break;
+        }
+    }
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+}
+
+fn handle_client(stream: UnixStream, shared: Arc<Mutex<Shared>>, client: Client) {
+    let mut reader = match stream.try_clone() {
+        Err(err) => {
+            error!("socket: {err}");
+            return;
+        }
+        Ok(reader) => BufReader::new(reader),
+    };
+    let mut stream = stream;
+    let timeouts = stream
+        .set_write_timeout(Some(WRITE_TIMEOUT))
+        .and_then(|()| stream.set_read_timeout(Some(READ_TIMEOUT)));
+    if let Err(err) = timeouts {
+        error!("socket: {err}");
+        return;
+    }
+    let cred = peer_cred(&stream).ok();
+    let is_root = cred.is_some_and(|cred| cred.uid == 0);
+
+    loop {
+        let mut line = String::new();
+        match reader.by_ref().take(MAX_LINE).read_line(&mut line) {
+            Err(_) | Ok(0) => return,
+            Ok(len) if len as u64 == MAX_LINE && !line.ends_with('\n') => {
+                let _ = writeln!(stream, "ERROR line too long");
+                return;
+            }
+            Ok(_) => {}
+        }
+
+        let mut shared = shared.lock().unwrap();
+        let json = shared.json.clone().unwrap_or_else(|| "null".to_string());
+        let result = match line.trim() {
+            "GET" => writeln!(stream, "{json}"),
+            "SUBSCRIBE" if shared.subscribers.len() >= MAX_SUBSCRIBERS => {
+                writeln!(stream, "ERROR too many subscribers")
+            }
+            "SUBSCRIBE" => {
+                // This thread writes what publish() queues from now on,
+                // counted as a subscriber rather than a client.
+                let (sender, lines) = mpsc::sync_channel(SUBSCRIBER_QUEUE);
+                let _ = sender.try_send(Arc::from(json));
+                shared.subscribers.push(sender);
+                drop(shared);
+                drop(client);
+                write_lines(stream, lines);
+                return;
+            }
+            "STATS" => {
+                let stats_json = shared.stats_json.as_deref().unwrap_or("null");
+                writeln!(stream, "{stats_json}")
+            }
+            "" => Ok(()),
+            "POSTPONE_SHUTDOWN" => match cred {
+                None => writeln!(stream, "ERROR unknown peer"),
+                Some(cred) => {
+                    shared.request(Request::PostponeShutdown {
+                        uid: cred.uid,
+                        pid: cred.pid,
+                    });
+                    writeln!(stream, "OK")
+                }
+            },
+            "RELOAD"
+            | "SIMULATE battery_low"
+            | "VERBOSE on"
+            | "VERBOSE off"
+            | "FORCED_SHUTDOWN on"
+            | "FORCED_SHUTDOWN off"
+                if !is_root =>
+            {
+                writeln!(stream, "ERROR permission denied")
+            }
+            "RELOAD" => {
+                signals::request_reload();
+                writeln!(stream, "OK")
+            }
+            "SIMULATE battery_low" => {
+                shared.request(Request::SimulateBatteryLow);
+                writeln!(stream, "OK")
+            }
+            "VERBOSE on" | "VERBOSE off" => {
+                let verbose = line.trim() == "VERBOSE on";
+                logging::set_verbose(verbose);
+                info!(
+                    "Verbose logging turned {}.",
+                    if verbose { "on" } else { "off" }
+                );
+                writeln!(stream, "OK")
+            }
+            "FORCED_SHUTDOWN on" | "FORCED_SHUTDOWN off" => {
+                let cred = cred.unwrap();
+                shared.request(Request::ForcedShutdown {
+                    enabled: line.trim() == "FORCED_SHUTDOWN on",
+                    uid: cred.uid,
+                    pid: cred.pid,
+                });
+                writeln!(stream, "OK")
+            }
+            command => writeln!(stream, "ERROR unknown command: {command}"),
+        };
+        if result.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    static SOCKETS: AtomicUsize = AtomicUsize::new(0);
+
+    struct TestServer {
+        path: String,
+        server: SocketServer,
+        _wakeups: mpsc::Receiver<Wakeup>,
+    }
+
+    impl TestServer {
+        fn new() -> TestServer {
+            let socket = SOCKETS.fetch_add(1, Ordering::SeqCst);
+            let name = format!("vpower-test-{}-{socket}.sock", std::process::id());
+            let path = std::env::temp_dir().join(name).display().to_string();
+            let (sender, wakeups) = mpsc::channel();
+            let server = SocketServer::new(&path, sender).unwrap();
+            TestServer {
+                path,
+                server,
+                _wakeups: wakeups,
+            }
+        }
+
+        fn connect(&self) -> (UnixStream, BufReader<UnixStream>) {
+            let stream = UnixStream::connect(&self.path).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            (stream, reader)
+        }
+
+        fn publish(&self, battery_percent: f64) -> String {
+            let snapshot = Snapshot {
+                battery_percent: Some(battery_percent),
+                ..Snapshot::default()
+            };
+            let json = format!("{{\"battery_percent\":{battery_percent}}}");
+            self.server.publish(&snapshot, &json);
+            json
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn ask(stream: &mut UnixStream, reader: &mut BufReader<UnixStream>, command: &str) -> String {
+        writeln!(stream, "{command}").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    }
+
+    #[test]
+    fn gets_and_subscribes() {
+        let server = TestServer::new();
+        let (mut stream, mut reader) = server.connect();
+        assert_eq!(ask(&mut stream, &mut reader, "GET"), "null");
+        let json = server.publish(50.0);
+        assert_eq!(ask(&mut stream, &mut reader, "GET"), json);
+        assert_eq!(ask(&mut stream, &mut reader, "STATS"), "null");
+        let reply = ask(&mut stream, &mut reader, "FOO");
+        assert_eq!(reply, "ERROR unknown command: FOO");
+
+        let (mut stream, mut reader) = server.connect();
+        assert_eq!(ask(&mut stream, &mut reader, "SUBSCRIBE"), json);
+        // Only changes are pushed.
+        server.publish(50.0);
+        let json = server.publish(49.0);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line.trim_end(), json);
+    }
+
+    #[test]
+    fn limits_lines() {
+        let server = TestServer::new();
+        let (mut stream, mut reader) = server.connect();
+        let line = "G".repeat(MAX_LINE as usize * 2);
+        assert_eq!(ask(&mut stream, &mut reader, &line), "ERROR line too long");
+    }
+
+    #[test]
+    fn limits_clients() {
+        let server = TestServer::new();
+        let clients: Vec<_> = (0..MAX_CLIENTS).map(|_| server.connect()).collect();
+        let (_, mut reader) = server.connect();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line.trim_end(), "ERROR too many clients");
+        drop(clients);
+    }
+
+    #[test]
+    fn limits_subscribers() {
+        let server = TestServer::new();
+        let subscribers: Vec<_> = (0..MAX_SUBSCRIBERS)
+            .map(|_| {
+                let (mut stream, mut reader) = server.connect();
+                assert_eq!(ask(&mut stream, &mut reader, "SUBSCRIBE"), "null");
+                (stream, reader)
+            })
+            .collect();
+        // Subscribers don't count as clients.
+        let (mut stream, mut reader) = server.connect();
+        let reply = ask(&mut stream, &mut reader, "SUBSCRIBE");
+        assert_eq!(reply, "ERROR too many subscribers");
+        drop(subscribers);
+    }
+
+    #[test]
+    fn drops_subscribers_that_fall_behind() {
+        let server = TestServer::new();
+        let (mut stream, mut reader) = server.connect();
+        assert_eq!(ask(&mut stream, &mut reader, "SUBSCRIBE"), "null");
+        // Never reading, with publish() not blocking on it once the
+        // socket buffer is full.
+        let json = "x".repeat(1 << 16);
+        for percent in 0..100 {
+            let snapshot = Snapshot {
+                battery_percent: Some(f64::from(percent)),
+                ..Snapshot::default()
+            };
+            server.server.publish(&snapshot, &json);
+        }
+        assert!(server.server.shared.lock().unwrap().subscribers.is_empty());
+    }
+}
@@ -0,0 +1,279 @@
+use crate::history::HISTORY_DIR;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const STATS_FILE: &str = "stats.json";
+
+// Shorter sessions say more about the noise than the battery.
+const MIN_SESSION_SECS: f64 = 60.0;
+const MIN_SESSION_PERCENT: f64 = 1.0;
+
+// One stretch on battery, from unplugging to plugging in or shutting
+// down. Time suspended doesn't count.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Session {
+    pub start_timestamp: u64,
+    pub start_percent: f64,
+    pub end_percent: f64,
+    pub duration_secs: f64,
+    pub average_watts: f64,
+}
+
+// All sessions so far, added up.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Lifetime {
+    pub sessions: u64,
+    pub duration_secs: f64,
+    pub percent_used: f64,
+    pub energy_wh: f64,
+}
+
+impl Lifetime {
+    pub fn average_watts(&self) -> Option<f64> {
+        (self.duration_secs > 0.0).then(|| self.energy_wh / (self.duration_secs / 3600.0))
+    }
+
+    // How long a full battery lasts, on average.
+    pub fn battery_life_secs(&self) -> Option<f64> {
+        (self.percent_used > 0.0).then(|| self.duration_secs / self.percent_used * 100.0)
+    }
+}
+
+// What ends up in /var/lib/vpower/stats.json.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Stats {
+    pub last_session: Option<Session>,
+    pub lifetime: Lifetime,
+}
+
+impl Stats {
+    // With the averages, for the socket.
+    pub fn to_json(&self) -> String {
+        let mut json = serde_json::to_value(self).unwrap_or_default();
+        json["lifetime"]["average_watts"] = self.lifetime.average_watts().into();
+        json["lifetime"]["battery_life_secs"] = self.lifetime.battery_life_secs().into();
+        json.to_string()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+fn load(path: &Path) -> Stats {
+    let string = match fs::read_to_string(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Stats::default(),
+        Err(err) => {
+            error!("read {}: {err}", path.display());
+            return Stats::default();
+        }
+        Ok(string) => string,
+    };
+    serde_json::from_str(&string).unwrap_or_else(|err| {
+        error!("read {}: {err}", path.display());
+        Stats::default()
+    })
+}
+
+fn save(path: &Path, stats: &Stats) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_string_pretty(stats)?)?;
+    fs::rename(&tmp_path, path)
+}
+
+struct Current {
+    start_timestamp: u64,
+    start: Instant,
+    start_percent: f64,
+    end_percent: f64,
+    last_sample: Instant,
+    energy_wh: f64,
+}
+
+// Follows discharge sessions, keeping the statistics up to date.
+pub struct SessionTracker {
+    path: PathBuf,
+    stats: Stats,
+    current: Option<Current>,
+}
+
+impl SessionTracker {
+    pub fn new() -> SessionTracker {
+        SessionTracker::at(Path::new(HISTORY_DIR).join(STATS_FILE))
+    }
+
+    // Keeping the statistics in path.
+    fn at(path: PathBuf) -> SessionTracker {
+        SessionTracker {
+            stats: load(&path),
+            path,
+            current: None,
+        }
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    // Whether the statistics changed, by a session ending.
+    pub fn update(
+        &mut self,
+        now: Instant,
+        on_battery: bool,
+        battery_percent: Option<f64>,
+        power_watts: Option<f64>,
+    ) -> bool {
+        let battery_percent = match battery_percent {
+            Some(battery_percent) if on_battery => battery_percent,
+            _ => return self.end(),
+        };
+        match &mut self.current {
+            None => {
+                self.current = Some(Current {
+                    start_timestamp: now_secs(),
+                    start: now,
+                    start_percent: battery_percent,
+                    end_percent: battery_percent,
+                    last_sample: now,
+                    energy_wh: 0.0,
+                });
+            }
+            Some(current) => {
+                let hours = now.duration_since(current.last_sample).as_secs_f64() / 3600.0;
+                current.energy_wh += power_watts.unwrap_or(0.0) * hours;
+                current.last_sample = now;
+                current.end_percent = battery_percent;
+            }
+        }
+        false
+    }
+
+    // End the current session, if any, like before shutting down.
+    pub fn end(&mut self) -> bool {
+        let current = match self.current.take() {
+            Some(current) => current,
+            None => return false,
+        };
+        let duration_secs = current
+            .last_sample
+            .duration_since(current.start)
+            .as_secs_f64();
+        let percent_used = current.start_percent - current.end_percent;
+        if duration_secs < MIN_SESSION_SECS || percent_used < MIN_SESSION_PERCENT {
+            return false;
+        }
+
+        let session = Session {
+            start_timestamp: current.start_timestamp,
+            start_percent: current.start_percent,
+            end_percent: current.end_percent,
+            duration_secs,
+            average_watts: current.energy_wh / (duration_secs / 3600.0),
+        };
+        info!(
+            "Session on battery: {:.0}% to {:.0}% in {:.0} minutes, {:.1} W on average.",
+            session.start_percent,
+            session.end_percent,
+            session.duration_secs / 60.0,
+            session.average_watts
+        );
+        let lifetime = &mut self.stats.lifetime;
+        lifetime.sessions += 1;
+        lifetime.duration_secs += duration_secs;
+        lifetime.percent_used += percent_used;
+        lifetime.energy_wh += current.energy_wh;
+        self.stats.last_session = Some(session);
+
+        if let Err(err) = save(&self.path, &self.stats) {
+            error!("write {}: {err}", self.path.display());
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Scratch;
+    use std::time::Duration;
+
+    fn tracker() -> (Scratch, SessionTracker) {
+        let dir = Scratch::new("stats");
+        let tracker = SessionTracker::at(dir.path.join(STATS_FILE));
+        (dir, tracker)
+    }
+
+    #[test]
+    fn session_on_battery() {
+        let (scratch, mut tracker) = tracker();
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        assert!(!tracker.update(at(0), true, Some(80.0), Some(10.0)));
+        assert!(!tracker.update(at(1800), true, Some(70.0), Some(10.0)));
+        assert!(!tracker.update(at(3600), true, Some(60.0), Some(10.0)));
+        // Plugging in ends it.
+        assert!(tracker.update(at(3660), false, Some(60.0), Some(20.0)));
+        assert!(!tracker.update(at(3720), false, Some(61.0), Some(20.0)));
+
+        let session = tracker.stats().last_session.clone().unwrap();
+        assert_eq!(session.start_percent, 80.0);
+        assert_eq!(session.end_percent, 60.0);
+        assert_eq!(session.duration_secs, 3600.0);
+        assert!((session.average_watts - 10.0).abs() < 1e-9);
+        // And it's kept for next time.
+        let saved = SessionTracker::at(scratch.path.join(STATS_FILE));
+        assert_eq!(saved.stats().lifetime.sessions, 1);
+    }
+
+    #[test]
+    fn ignores_short_sessions() {
+        let (_scratch, mut tracker) = tracker();
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        // Too short.
+        tracker.update(at(0), true, Some(80.0), Some(10.0));
+        tracker.update(at(59), true, Some(70.0), Some(10.0));
+        assert!(!tracker.end());
+        // Too little used.
+        tracker.update(at(100), true, Some(80.0), Some(10.0));
+        tracker.update(at(3700), true, Some(79.5), Some(10.0));
+        assert!(!tracker.end());
+        // Without a battery percentage, like when it can't be read.
+        tracker.update(at(4000), true, Some(80.0), Some(10.0));
+        assert!(!tracker.update(at(4030), true, None, Some(10.0)));
+        assert!(tracker.stats().last_session.is_none());
+        assert_eq!(tracker.stats().lifetime.sessions, 0);
+        assert!(!tracker.end());
+    }
+
+    #[test]
+    fn adds_up_lifetime() {
+        let (_scratch, mut tracker) = tracker();
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        tracker.update(at(0), true, Some(100.0), Some(10.0));
+        tracker.update(at(3600), true, Some(80.0), Some(10.0));
+        assert!(tracker.end());
+        tracker.update(at(7200), true, Some(80.0), Some(20.0));
+        tracker.update(at(9000), true, Some(60.0), Some(20.0));
+        assert!(tracker.end());
+
+        let lifetime = &tracker.stats().lifetime;
+        assert_eq!(lifetime.sessions, 2);
+        assert_eq!(lifetime.duration_secs, 5400.0);
+        assert_eq!(lifetime.percent_used, 40.0);
+        assert!((lifetime.energy_wh - 20.0).abs() < 1e-9);
+        let average_watts = lifetime.average_watts().unwrap();
+        assert!((average_watts - 20.0 / 1.5).abs() < 1e-9);
+        assert_eq!(lifetime.battery_life_secs(), Some(13500.0));
+    }
+}
@@ -0,0 +1,40 @@
+use libc::{clock_gettime, clockid_t, timespec, CLOCK_BOOTTIME, CLOCK_MONOTONIC};
+use std::time::Duration;
+
+// Less than this is noise between reading the two clocks.
+const MIN_SUSPEND: Duration = Duration::from_secs(1);
+
+fn clock(id: clockid_t) -> Duration {
+    let mut ts = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { clock_gettime(id, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+// CLOCK_MONOTONIC stops while suspended but CLOCK_BOOTTIME doesn't, so
+// time spent suspended shows up as the difference between them growing.
+fn suspended_total() -> Duration {
+    clock(CLOCK_BOOTTIME).saturating_sub(clock(CLOCK_MONOTONIC))
+}
+
+pub struct SuspendDetector {
+    suspended: Duration,
+}
+
+impl SuspendDetector {
+    pub fn new() -> SuspendDetector {
+        SuspendDetector {
+            suspended: suspended_total(),
+        }
+    }
+
+    // How long the system was suspended since the last call, if it was.
+    pub fn check(&mut self) -> Option<Duration> {
+        let suspended = suspended_total();
+        let delta = suspended.saturating_sub(self.suspended);
+        self.suspended = suspended;
+        (delta >= MIN_SUSPEND).then_some(delta)
+    }
+}
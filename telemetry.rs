@@ -0,0 +1,188 @@
+use crate::history::csv_field;
+use crate::report_once;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsFd;
+use std::os::unix::fs::OpenOptionsExt;
+
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TelemetryFormat {
+    Csv,
+    Jsonl,
+}
+
+// Where and how to stream every sample.
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TelemetryConfig {
+    // A file, a FIFO, or "-" for stdout.
+    pub path: String,
+    #[serde(default = "default_format")]
+    pub format: TelemetryFormat,
+    // Once a file grows past this, move it to <path>.1, and so on up
+    // to <path>.<keep>, 0 meaning never.
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: f64,
+    #[serde(default = "default_keep")]
+    pub keep: u32,
+}
+
+fn default_format() -> TelemetryFormat {
+    TelemetryFormat::Csv
+}
+
+fn default_max_size_mb() -> f64 {
+    100.0
+}
+
+fn default_keep() -> u32 {
+    3
+}
+
+// The values of a status.json line, timestamp and sequence first and
+// the rest sorted, as a CSV header and row.
+fn csv(status_json: &str) -> serde_json::Result<(String, String)> {
+    let mut status: Map<String, Value> = serde_json::from_str(status_json)?;
+    let mut header = Vec::new();
+    let mut row = Vec::new();
+    let first = ["timestamp", "sequence"].map(|key| (key.to_string(), status.remove(key)));
+    for (key, val) in first
+        .into_iter()
+        .chain(status.into_iter().map(|(key, val)| (key, Some(val))))
+    {
+        header.push(key);
+        row.push(match val {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(val)) => csv_field(Some(&val)),
+            Some(val) => val.to_string(),
+        });
+    }
+    Ok((header.join(","), row.join(",")))
+}
+
+// Samples written line by line, for benchmarking runs. A FIFO only gets
+// them while something reads it, without ever holding up vpower.
+pub struct Telemetry {
+    config: TelemetryConfig,
+    file: Option<File>,
+    // How much the file holds, when it's a regular file.
+    size: Option<u64>,
+}
+
+impl Telemetry {
+    pub fn new(config: &TelemetryConfig) -> Telemetry {
+        Telemetry {
+            config: config.clone(),
+            file: None,
+            size: None,
+        }
+    }
+
+    pub fn record(&mut self, status_json: &str) {
+        match self.write(status_json) {
+            Ok(()) => {}
+            // A full FIFO.
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            // A FIFO nobody reads (anymore).
+            Err(err)
+                if err.kind() == io::ErrorKind::BrokenPipe
+                    || err.raw_os_error() == Some(libc::ENXIO) =>
+            {
+                self.file = None
+            }
+            result => {
+                self.file = None;
+                report_once(&format!("telemetry {}", self.config.path), result);
+            }
+        }
+    }
+
+    fn open(&mut self) -> io::Result<File> {
+        let file = match self.file.take() {
+            Some(file) => file,
+            None if self.config.path == "-" => {
+                File::from(io::stdout().as_fd().try_clone_to_owned()?)
+            }
+            None => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&self.config.path)?,
+        };
+        if self.size.is_none() {
+            let metadata = file.metadata()?;
+            self.size = metadata.is_file().then_some(metadata.len());
+        }
+        Ok(file)
+    }
+
+    // Move <path> to <path>.1, <path>.1 to <path>.2, and so on.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        self.size = None;
+        let path = &self.config.path;
+        let numbered = |n: u32| format!("{path}.{n}");
+        if self.config.keep == 0 {
+            return fs::remove_file(path);
+        }
+        for n in (1..self.config.keep).rev() {
+            if let Err(err) = fs::rename(numbered(n), numbered(n + 1)) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    return Err(err);
+                }
+            }
+        }
+        fs::rename(path, numbered(1))
+    }
+
+    fn write(&mut self, status_json: &str) -> io::Result<()> {
+        let max_size = self.config.max_size_mb * 1e6;
+        if self.config.max_size_mb > 0.0 && self.size.is_some_and(|size| size as f64 >= max_size) {
+            self.rotate()?;
+        }
+        let (header, row) = match self.config.format {
+            TelemetryFormat::Jsonl => (None, status_json.to_string()),
+            TelemetryFormat::Csv => {
+                let (header, row) = csv(status_json)?;
+                (Some(header), row)
+            }
+        };
+        // What was opened starts with the header, unless appending to
+        // a file that has it already.
+        let fresh = self.file.is_none();
+        let mut file = self.open()?;
+        let mut lines = String::new();
+        if let Some(header) = header.filter(|_| fresh && self.size.is_none_or(|size| size == 0)) {
+            lines.push_str(&header);
+            lines.push('\n');
+        }
+        lines.push_str(&row);
+        lines.push('\n');
+        let result = file.write_all(lines.as_bytes());
+        self.file = Some(file);
+        result?;
+        if let Some(size) = &mut self.size {
+            *size += lines.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_columns() {
+        let json = r#"{"timestamp":1.5,"sequence":2,"battery_status":"Full, really","ac_status":null,"battery_percent":80.0}"#;
+        let (header, row) = csv(json).unwrap();
+        assert_eq!(
+            header,
+            "timestamp,sequence,ac_status,battery_percent,battery_status"
+        );
+        assert_eq!(row, "1.5,2,,80.0,\"Full, really\"");
+    }
+}
@@ -0,0 +1,57 @@
+use crate::battery::POWER_SUPPLY_DIR;
+use crate::sensors::{matches, Error};
+use crate::sysfs;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// A charger on a USB-C port, as described by the port's power_supply
+// device (like the ones UCSI registers, ucsi-source-psy-*).
+pub struct Contract {
+    pub voltage: f64, // Volts.
+    pub current: f64, // Amps.
+}
+
+fn read_micro(path: &Path, name: &str) -> Result<f64, Error> {
+    let path = path.join(name);
+    let string = fs::read_to_string(&path)
+        .map_err(|err| Error::Read(format!("{}: {err}", path.display())))?;
+    let val = f64::from_str(string.trim())
+        .map_err(|err| Error::Read(format!("{}: {err}", path.display())))?;
+    Ok(val / 1e6)
+}
+
+// The contract of the online power_supply matching pattern, the one
+// with the highest wattage if there are several ports, or None without
+// a charger.
+pub fn read(pattern: &str) -> Result<Option<Contract>, Error> {
+    let dir = sysfs(POWER_SUPPLY_DIR);
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .map_err(|err| Error::Read(format!("{}: {err}", dir.display())))?
+        .flatten()
+        .filter(|entry| matches(pattern.as_bytes(), entry.file_name().as_encoded_bytes()))
+        .map(|entry| entry.path())
+        .collect();
+    if paths.is_empty() {
+        return Err(Error::Read(format!("no power_supply matching '{pattern}'")));
+    }
+    paths.sort();
+
+    let mut best: Option<Contract> = None;
+    for path in paths {
+        if fs::read_to_string(path.join("online")).is_ok_and(|online| online.trim() == "1") {
+            let contract = Contract {
+                voltage: read_micro(&path, "voltage_now")?,
+                current: read_micro(&path, "current_max")?,
+            };
+            let watts = contract.voltage * contract.current;
+            if best
+                .as_ref()
+                .is_none_or(|best| watts > best.voltage * best.current)
+            {
+                best = Some(contract);
+            }
+        }
+    }
+    Ok(best)
+}
@@ -0,0 +1,157 @@
+use crate::events::Wakeup;
+use libc::*;
+use log::error;
+use std::io;
+use std::mem;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Time to keep draining after the first uevent, so that the burst
+// the kernel sends on plug/unplug results in a single wakeup.
+const SETTLE_TIME: Duration = Duration::from_millis(50);
+
+pub struct UeventMonitor {
+    fd: c_int,
+}
+
+impl UeventMonitor {
+    pub fn new() -> UeventMonitor {
+        let mut monitor = UeventMonitor { fd: -1 };
+
+        unsafe {
+            let fd = socket(
+                AF_NETLINK,
+                SOCK_DGRAM | SOCK_CLOEXEC | SOCK_NONBLOCK,
+                NETLINK_KOBJECT_UEVENT,
+            );
+            if fd < 0 {
+                error!("netlink socket: {}", std::io::Error::last_os_error());
+                return monitor;
+            }
+
+            let mut addr: sockaddr_nl = mem::zeroed();
+            addr.nl_family = AF_NETLINK as sa_family_t;
+            addr.nl_groups = 1; // Kernel uevents.
+            let addr_ptr = &addr as *const sockaddr_nl as *const sockaddr;
+            let addr_len = mem::size_of::<sockaddr_nl>() as socklen_t;
+            if bind(fd, addr_ptr, addr_len) != 0 {
+                error!("netlink bind: {}", std::io::Error::last_os_error());
+                close(fd);
+                return monitor;
+            }
+
+            monitor.fd = fd;
+        }
+
+        monitor
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.fd >= 0
+    }
+
+    // Wake up the main loop once for every power_supply uevent, or
+    // burst of them, from a thread of its own. Without netlink, the
+    // main loop only polls.
+    pub fn start(self, wakeups: Sender<Wakeup>) {
+        if !self.is_available() {
+            return;
+        }
+        thread::spawn(move || {
+            // Rather than spinning on an error that doesn't go away.
+            if let Err(err) = self.run(&wakeups) {
+                error!("netlink: {err}, polling only from now on");
+            }
+        });
+    }
+
+    fn run(&self, wakeups: &Sender<Wakeup>) -> io::Result<()> {
+        loop {
+            if !self.poll(None)? || !self.drain()? {
+                continue;
+            }
+            self.settle()?;
+            if wakeups.send(Wakeup::PowerSupply).is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    // Coalesce the rest of the burst.
+    fn settle(&self) -> io::Result<()> {
+        let settle_deadline = Instant::now() + SETTLE_TIME;
+        loop {
+            let now = Instant::now();
+            if now >= settle_deadline || !self.poll(Some(settle_deadline - now))? {
+                return Ok(());
+            }
+            self.drain()?;
+        }
+    }
+
+    // Wait for messages, forever without a timeout, returning whether
+    // there are any.
+    fn poll(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut pfd = pollfd {
+            fd: self.fd,
+            events: POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.map_or(-1, |timeout| {
+            timeout.as_millis().clamp(1, c_int::MAX as u128) as c_int
+        });
+        if unsafe { poll(&mut pfd, 1, timeout_ms) } < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::Interrupted => Ok(false),
+                _ => Err(err),
+            };
+        }
+        // POLLERR is left for recv to report, and clear.
+        if pfd.revents & (POLLHUP | POLLNVAL) != 0 {
+            return Err(io::Error::other("socket hung up"));
+        }
+        Ok(pfd.revents != 0)
+    }
+
+    // Read all pending messages, returning whether any of them came
+    // from the power_supply subsystem.
+    fn drain(&self) -> io::Result<bool> {
+        let mut found = false;
+        let mut buf = [0u8; 8192];
+        loop {
+            let len = unsafe { recv(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len(), 0) };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(EINTR) => {}
+                    Some(EAGAIN) => return Ok(found),
+                    // The socket overflowed: one of the uevents lost
+                    // may have been a power_supply one.
+                    Some(ENOBUFS) => found = true,
+                    _ => return Err(err),
+                }
+                continue;
+            }
+            if len == 0 {
+                return Ok(found);
+            }
+            let msg = &buf[..len as usize];
+            if msg
+                .split(|&b| b == 0)
+                .any(|field| field == b"SUBSYSTEM=power_supply")
+            {
+                found = true;
+            }
+        }
+    }
+}
+
+impl Drop for UeventMonitor {
+    fn drop(&mut self) {
+        if self.is_available() {
+            unsafe { close(self.fd) };
+        }
+    }
+}
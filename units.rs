@@ -0,0 +1,174 @@
+use log::{info, warn};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
+
+// sysfs power_supply attributes are in millionths of SI units.
+const MICRO: f64 = 1e6;
+
+/// What a battery attribute measures.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quantity {
+    /// voltage_*, in µV.
+    Voltage,
+    /// current_now, in µA.
+    Current,
+    /// power_now, in µW.
+    Power,
+    /// charge_*, in µAh.
+    Charge,
+    /// energy_*, in µWh.
+    Energy,
+    /// In °C, once divided as the file requires.
+    Temperature,
+}
+
+impl Quantity {
+    fn unit(self) -> &'static str {
+        match self {
+            Quantity::Voltage => "V",
+            Quantity::Current => "A",
+            Quantity::Power => "W",
+            Quantity::Charge => "Ah",
+            Quantity::Energy => "Wh",
+            Quantity::Temperature => "°C",
+        }
+    }
+
+    // What a value read is multiplied by to be in unit().
+    fn factor(self) -> f64 {
+        match self {
+            Quantity::Temperature => 1.0,
+            _ => 1.0 / MICRO,
+        }
+    }
+
+    // What the battery of anything from a handheld to a big laptop can
+    // report, in unit(). Outside of it, the driver is broken or got the
+    // scale wrong, like mV instead of µV. Signed quantities are checked
+    // without their sign, which only tells the direction.
+    fn range(self) -> RangeInclusive<f64> {
+        match self {
+            Quantity::Voltage => 2.0..=30.0,
+            Quantity::Current => 0.0..=20.0,
+            Quantity::Power => 0.0..=300.0,
+            Quantity::Charge => 0.0..=100.0,
+            Quantity::Energy => 0.0..=1000.0,
+            Quantity::Temperature => -40.0..=120.0,
+        }
+    }
+}
+
+/// µV, µA, µAh or µWh to V, A, Ah or Wh.
+pub fn si(micro: f64) -> f64 {
+    micro / MICRO
+}
+
+/// The power in W of a voltage in µV and a current in µA.
+pub fn watts(microvolts: f64, microamps: f64) -> f64 {
+    si(microvolts) * si(microamps)
+}
+
+/// What to multiply raw battery attributes by, from the quirks, for
+/// drivers that get their scale wrong.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Scales {
+    pub voltage: f64,
+    pub current: f64,
+    /// charge_* and energy_*.
+    pub charge: f64,
+}
+
+impl Default for Scales {
+    fn default() -> Scales {
+        Scales {
+            voltage: 1.0,
+            current: 1.0,
+            charge: 1.0,
+        }
+    }
+}
+
+impl Scales {
+    // power_now, read or computed, is scaled by power_scale once the
+    // battery is read, in Quirks::apply_battery.
+    fn of(&self, quantity: Quantity) -> f64 {
+        match quantity {
+            Quantity::Voltage => self.voltage,
+            Quantity::Current => self.current,
+            Quantity::Charge | Quantity::Energy => self.charge,
+            Quantity::Power | Quantity::Temperature => 1.0,
+        }
+    }
+}
+
+/// Scales raw readings and rejects the absurd ones, so that they never
+/// make it into percentages and time estimates.
+#[derive(Default)]
+pub struct Validator {
+    scales: Scales,
+    // The attributes rejected last time, to only log when that changes.
+    rejected: RefCell<HashSet<String>>,
+}
+
+impl Validator {
+    pub fn new(scales: Scales) -> Validator {
+        Validator {
+            scales,
+            rejected: RefCell::default(),
+        }
+    }
+
+    /// val, read from what, scaled, or None if it makes no sense.
+    pub fn check(&self, what: &str, quantity: Quantity, val: Option<f64>) -> Option<f64> {
+        let val = val? * self.scales.of(quantity);
+        let converted = val * quantity.factor();
+        let range = quantity.range();
+        let mut rejected = self.rejected.borrow_mut();
+        let magnitude = match quantity {
+            Quantity::Temperature => converted,
+            _ => converted.abs(),
+        };
+        if range.contains(&magnitude) {
+            if rejected.remove(what) {
+                info!("{what} makes sense again.");
+            }
+            return Some(val);
+        }
+        if rejected.insert(what.to_string()) {
+            let unit = quantity.unit();
+            warn!(
+                "{what}: {converted} {unit} is outside of {}-{} {unit}, ignoring it.",
+                range.start(),
+                range.end()
+            );
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks() {
+        let validator = Validator::default();
+        let check = |quantity, val| validator.check("test", quantity, Some(val));
+        assert_eq!(check(Quantity::Voltage, 8_200_000.0), Some(8_200_000.0));
+        // mV instead of µV.
+        assert_eq!(check(Quantity::Voltage, 8_200.0), None);
+        assert_eq!(check(Quantity::Current, -2_500_000.0), Some(-2_500_000.0));
+        assert_eq!(check(Quantity::Charge, 5_000_000_000.0), None);
+        assert_eq!(check(Quantity::Temperature, -50.0), None);
+        assert_eq!(validator.check("test", Quantity::Energy, None), None);
+
+        let scales = Scales {
+            voltage: 1000.0,
+            ..Scales::default()
+        };
+        let validator = Validator::new(scales);
+        let val = validator.check("test", Quantity::Voltage, Some(8_200.0));
+        assert_eq!(val, Some(8_200_000.0));
+    }
+}
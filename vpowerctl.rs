@@ -0,0 +1,135 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process::ExitCode;
+use vpower::config::OUTPUT_DIR;
+
+// Talks to the daemon over <output_dir>/vpower.sock.
+#[derive(Parser)]
+#[command(version, about = "Controls vpower and prints the values it publishes.")]
+struct Args {
+    /// Talk to the vpower instance writing to DIR
+    #[arg(long, value_name = "DIR", default_value = OUTPUT_DIR)]
+    output_dir: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the current values
+    Status,
+    /// Print the values every time they change
+    Watch,
+    /// Print the battery life statistics
+    Stats,
+    /// Re-read the configuration
+    Reload,
+    /// Act as if the warning threshold was reached
+    SimulateLowBattery,
+    /// Toggle debug messages in the daemon's log
+    Verbose { state: Switch },
+    /// Whether to run the critical action on low battery, or only log,
+    /// until the daemon restarts
+    ForcedShutdown { state: Switch },
+    /// Put off a pending shutdown for another confirmation window
+    PostponeShutdown,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Switch {
+    On,
+    Off,
+}
+
+impl Switch {
+    fn as_str(self) -> &'static str {
+        match self {
+            Switch::On => "on",
+            Switch::Off => "off",
+        }
+    }
+}
+
+fn connect(output_dir: &str) -> io::Result<(UnixStream, BufReader<UnixStream>)> {
+    let socket_path = format!("{output_dir}/vpower.sock");
+    let stream = UnixStream::connect(&socket_path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{socket_path}: {err}")))?;
+    let reader = BufReader::new(stream.try_clone()?);
+    Ok((stream, reader))
+}
+
+// Send one command and return the reply line.
+fn request(output_dir: &str, command: &str) -> io::Result<String> {
+    let (mut stream, mut reader) = connect(output_dir)?;
+    writeln!(stream, "{command}")?;
+    let mut reply = String::new();
+    reader.read_line(&mut reply)?;
+    Ok(reply.trim_end().to_string())
+}
+
+fn print_status(json: &str) {
+    let status: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(json) {
+        Ok(status) => status,
+        Err(_) => {
+            println!("{json}");
+            return;
+        }
+    };
+    for (key, val) in status {
+        match val {
+            serde_json::Value::Null => {}
+            serde_json::Value::String(val) => println!("{key}: {val}"),
+            val => println!("{key}: {val}"),
+        }
+    }
+}
+
+fn control(output_dir: &str, command: &str) -> io::Result<()> {
+    let reply = request(output_dir, command)?;
+    match reply.strip_prefix("ERROR ") {
+        Some(err) => Err(io::Error::other(err.to_string())),
+        None => Ok(()),
+    }
+}
+
+fn run(args: &Args) -> io::Result<()> {
+    let output_dir = &args.output_dir;
+    match args.command {
+        Command::Status => {
+            print_status(&request(output_dir, "GET")?);
+            Ok(())
+        }
+        Command::Watch => {
+            let (mut stream, reader) = connect(output_dir)?;
+            writeln!(stream, "SUBSCRIBE")?;
+            for line in reader.lines() {
+                print_status(&line?);
+                println!();
+            }
+            Ok(())
+        }
+        Command::Stats => {
+            print_status(&request(output_dir, "STATS")?);
+            Ok(())
+        }
+        Command::Reload => control(output_dir, "RELOAD"),
+        Command::SimulateLowBattery => control(output_dir, "SIMULATE battery_low"),
+        Command::Verbose { state } => control(output_dir, &format!("VERBOSE {}", state.as_str())),
+        Command::ForcedShutdown { state } => {
+            control(output_dir, &format!("FORCED_SHUTDOWN {}", state.as_str()))
+        }
+        Command::PostponeShutdown => control(output_dir, "POSTPONE_SHUTDOWN"),
+    }
+}
+
+fn main() -> ExitCode {
+    match run(&Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("vpowerctl: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use crate::{report_once, sysfs};
+use log::{error, info};
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const RTC_WAKEALARM: &str = "/sys/class/rtc/rtc0/wakealarm";
+
+// Don't wake up sooner than this, however low the battery.
+const MIN_DELAY_SECS: f64 = 60.0;
+
+// Only move the alarm when the target moved by more than this, as
+// the estimates change a little on every iteration.
+const REPROGRAM_SECS: u64 = 60;
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+// Keeps the RTC set to wake the system shortly before the battery
+// would reach request_shutdown_battery_percent while suspended, so
+// that the critical action can run instead of the battery dying in
+// sleep. The alarm doesn't do anything while awake.
+#[derive(Default)]
+pub struct WakeAlarm {
+    // When the alarm is set for, if it was set by us.
+    programmed: Option<u64>,
+    // Wh left above the shutdown level on the last update.
+    prev_wh: Option<f64>,
+    // How long the system was just suspended for, until the next
+    // update.
+    suspended: Option<Duration>,
+    // The drain while suspended, measured across the last suspend.
+    suspend_watts: Option<f64>,
+}
+
+impl WakeAlarm {
+    pub fn resumed(&mut self, suspended: Duration) {
+        self.suspended = Some(suspended);
+    }
+
+    // wh is the energy left above the shutdown level, and
+    // secs_until_shutdown the estimate at the current, awake, power
+    // draw, which is the best guess until a suspend was measured.
+    // Clears the alarm when not discharging.
+    pub fn update(
+        &mut self,
+        discharging: bool,
+        wh: Option<f64>,
+        secs_until_shutdown: Option<f64>,
+        margin_secs: f64,
+    ) {
+        if let (Some(suspended), Some(prev_wh), Some(wh)) =
+            (self.suspended.take(), self.prev_wh, wh)
+        {
+            let watts = (prev_wh - wh) / (suspended.as_secs_f64() / 3600.0);
+            if discharging && watts > 0.0 {
+                info!("Drained {watts:.2} W while suspended.");
+                self.suspend_watts = Some(watts);
+            }
+        }
+        self.prev_wh = wh;
+
+        if !discharging {
+            self.clear();
+            return;
+        }
+        let secs = match (self.suspend_watts, wh) {
+            (Some(suspend_watts), Some(wh)) => Some(wh / suspend_watts * 3600.0),
+            _ => secs_until_shutdown,
+        };
+        match secs {
+            Some(secs) => {
+                let delay = (secs - margin_secs).max(MIN_DELAY_SECS);
+                self.set(now_secs() + delay as u64);
+            }
+            None => self.clear(),
+        }
+    }
+
+    fn set(&mut self, at: u64) {
+        if self
+            .programmed
+            .is_some_and(|programmed| programmed.abs_diff(at) <= REPROGRAM_SECS)
+        {
+            return;
+        }
+        // An alarm has to be cleared before setting another one.
+        let result = fs::write(sysfs(RTC_WAKEALARM), "0")
+            .and_then(|_| fs::write(sysfs(RTC_WAKEALARM), at.to_string()));
+        match report_once(&format!("write {RTC_WAKEALARM}"), result) {
+            None => self.programmed = None,
+            Some(()) => {
+                if self.programmed.is_none() {
+                    info!(
+                        "Set wake alarm in {} seconds.",
+                        at.saturating_sub(now_secs())
+                    );
+                }
+                self.programmed = Some(at);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        if self.programmed.take().is_some() {
+            if let Err(err) = fs::write(sysfs(RTC_WAKEALARM), "0") {
+                error!("write {RTC_WAKEALARM}: {err}");
+            }
+        }
+    }
+}